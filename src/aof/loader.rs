@@ -0,0 +1,244 @@
+use super::manifest::{AofFileType, AofInfo, AofManifest};
+use super::writer::AOFWriter;
+use crate::engine::commands::{check_command, dispatch};
+use crate::engine::parser::{parse_repl_frame, ReplFrame};
+use crate::engine::reply::RespReply;
+use crate::engine::{CommandHandlerResponse, RespMessage};
+use crate::rdb::config::RDBConfigOps;
+use crate::rdb::loader::{RDBLoader, RDB_MAGIC};
+use crate::store::engine::StoreEngine;
+use anyhow::Result;
+use std::fs::{self, OpenOptions};
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+
+pub trait AOFLoader {
+    // replay the files of the manifest, None if there is no aof
+    // a single aof of an older version is moved into appenddirname as the base first
+    fn load_aof(self: &Arc<Self>) -> Result<Option<AofManifest>>;
+}
+
+impl AOFLoader for StoreEngine {
+    fn load_aof(self: &Arc<Self>) -> Result<Option<AofManifest>> {
+        let manifest = match self.read_manifest()? {
+            Some(manifest) => manifest,
+            None => match upgrade_aof(self)? {
+                Some(manifest) => manifest,
+                None => return Ok(None),
+            },
+        };
+
+        let dir = self.aof_dir();
+        let count = manifest.files().count();
+        for (i, info) in manifest.files().enumerate() {
+            load_aof_file(self, &dir.join(&info.name), i + 1 == count)?;
+        }
+        // what was loaded is already on disk
+        self.stats.dirty.store(0, Ordering::Relaxed);
+        Ok(Some(manifest))
+    }
+}
+
+// appendfilename in dir as redis before 7 wrote it
+fn upgrade_aof(db: &StoreEngine) -> Result<Option<AofManifest>> {
+    let filename = db.config.read().unwrap().appendfilename.clone();
+    let old_path = Path::new(&db.get_dir()).join(&filename);
+    if !old_path.exists() {
+        return Ok(None);
+    }
+
+    let dir = db.aof_dir();
+    fs::create_dir_all(&dir)?;
+    fs::rename(&old_path, dir.join(&filename))?;
+    let manifest = AofManifest {
+        base: Some(AofInfo {
+            name: filename,
+            seq: 1,
+            file_type: AofFileType::Base,
+        }),
+        incrs: Vec::new(),
+    };
+    db.write_manifest(&manifest)?;
+    println!(
+        "append only file {} moved into {}",
+        old_path.display(),
+        dir.display()
+    );
+    Ok(Some(manifest))
+}
+
+// commands, an rdb, or an rdb followed by commands
+// only the last file may end in the middle of a write, the others were complete
+// when the next one was started
+fn load_aof_file(db: &Arc<StoreEngine>, path: &Path, last: bool) -> Result<()> {
+    let buf = fs::read(path).map_err(|e| {
+        anyhow::anyhow!("can't read the append only file {}: {}", path.display(), e)
+    })?;
+
+    let mut start = 0;
+    if buf.starts_with(RDB_MAGIC.as_bytes()) {
+        let mut cursor = Cursor::new(&buf[..]);
+        db.parse(&mut cursor)
+            .map_err(|e| anyhow::anyhow!("bad rdb in {}: {}", path.display(), e))?;
+        start = cursor.position() as usize;
+    }
+
+    let (writes, valid_len) = split_aof(&buf[start..])?;
+    let valid_len = start + valid_len;
+    if valid_len < buf.len() {
+        if !last || !db.config.read().unwrap().aof_load_truncated {
+            return Err(anyhow::anyhow!(
+                "Unexpected end of file reading the append only file {}, set aof-load-truncated to yes to load it anyway",
+                path.display()
+            ));
+        }
+        // the server died in the middle of a write, what precedes it is kept
+        println!(
+            "warn: the append only file {} is truncated, the last {} bytes are dropped",
+            path.display(),
+            buf.len() - valid_len
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(valid_len as u64)?;
+    }
+
+    // applied like the commands of the master, nothing is propagated
+    // a command which fails doesn't stop the load, as redis it is only reported
+    for cmds in writes {
+        for args in cmds {
+            let name = args[0].clone();
+            let cmd = Arc::new(RwLock::new(RespMessage::from_args("aof".to_string(), args)));
+            let failed = match dispatch(db, cmd) {
+                Ok(CommandHandlerResponse::Basic(RespReply::Error(e))) => Some(e),
+                Ok(_) => None,
+                Err(e) => Some(e.to_string()),
+            };
+            if let Some(e) = failed {
+                println!(
+                    "warn: {} replayed from {} failed: {}",
+                    name,
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// the writes of the file, a MULTI block is a single write without MULTI and EXEC
+// returned with the length of the file up to the last complete write
+fn split_aof(buf: &[u8]) -> Result<(Vec<Vec<Vec<String>>>, usize)> {
+    let mut writes = Vec::new();
+    let mut multi: Option<Vec<Vec<String>>> = None;
+    let mut pos = 0;
+    let mut valid_len = 0;
+
+    while let Some((frame, frame_len)) =
+        parse_repl_frame(&buf[pos..]).map_err(|e| bad_format(pos, e))?
+    {
+        let ReplFrame::Command(args) = frame else {
+            return Err(bad_format(pos, "expected a command"));
+        };
+        check_command(&args).map_err(|e| bad_format(pos, e))?;
+        pos += frame_len;
+
+        let name = args[0].to_lowercase();
+        match (name.as_str(), multi.as_mut()) {
+            ("multi", None) => multi = Some(Vec::new()),
+            ("exec", Some(_)) => writes.push(multi.take().unwrap_or_default()),
+            (_, Some(queued)) => queued.push(args),
+            _ => writes.push(vec![args]),
+        }
+        if multi.is_none() {
+            valid_len = pos;
+        }
+    }
+    Ok((writes, valid_len))
+}
+
+fn bad_format(pos: usize, e: impl std::fmt::Display) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Bad file format reading the append only file at offset {}: {}",
+        pos,
+        e
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::engine::{now_ms, StreamID};
+    use crate::store::stream_engine::StreamEngine;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_load_aof_round_trip() {
+        let dir = std::env::temp_dir().join(format!("aof-round-trip-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let db = Arc::new(StoreEngine::new());
+        db.set_dir(dir.display().to_string());
+        db.set("foo".to_string(), "bar".to_string());
+        db.set_with_expire("tmp".to_string(), "1".to_string(), 60_000);
+        let fields = HashMap::from([("a".to_string(), "1".to_string())]);
+        db.set_stream_key("s", StreamID::new(1, 1), fields).unwrap();
+        db.expire_at("s", now_ms() + 60_000);
+
+        // the commands of dump_aof as the base, followed by an incr file
+        let filename = db.config.read().unwrap().appendfilename.clone();
+        let mut manifest = AofManifest::default();
+        let base = manifest.next_base(&filename, false);
+        let incr = manifest.next_incr(&filename);
+        fs::create_dir_all(db.aof_dir()).unwrap();
+        fs::write(db.aof_dir().join(&base.name), db.dump_aof()).unwrap();
+        fs::write(
+            db.aof_dir().join(&incr.name),
+            "*2\r\n$3\r\nDEL\r\n$3\r\nfoo\r\n*3\r\n$3\r\nSET\r\n$3\r\nnew\r\n$1\r\n1\r\n",
+        )
+        .unwrap();
+        manifest.base = Some(base);
+        manifest.incrs.push(incr);
+        db.write_manifest(&manifest).unwrap();
+
+        let loaded = Arc::new(StoreEngine::new());
+        loaded.set_dir(dir.display().to_string());
+        loaded.stats.loading.store(true, Ordering::Relaxed);
+        assert_eq!(loaded.load_aof().unwrap(), Some(manifest));
+        assert_eq!(loaded.get("foo"), None);
+        assert_eq!(loaded.get("new"), Some("1".to_string()));
+        assert_eq!(loaded.get("tmp"), Some("1".to_string()));
+        assert_eq!(loaded.get_expire("tmp"), db.get_expire("tmp"));
+        assert_eq!(loaded.get_stream_key("s"), db.get_stream_key("s"));
+        assert_eq!(loaded.get_expire("s"), db.get_expire("s"));
+        // the replay isn't counted as commands of clients
+        assert!(loaded.stats.command_stats().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_split_aof() {
+        let set = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n";
+        let multi = b"*1\r\n$5\r\nMULTI\r\n*2\r\n$3\r\nDEL\r\n$1\r\na\r\n*1\r\n$4\r\nEXEC\r\n";
+
+        let mut buf = set.to_vec();
+        buf.extend_from_slice(multi);
+        let (writes, valid_len) = split_aof(&buf).unwrap();
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[1], vec![vec!["DEL".to_string(), "a".to_string()]]);
+        assert_eq!(valid_len, buf.len());
+
+        // half a command and an unfinished MULTI are both cut off
+        let (writes, valid_len) = split_aof(&buf[..buf.len() - 3]).unwrap();
+        assert_eq!(writes.len(), 1);
+        assert_eq!(valid_len, set.len());
+        let (_, valid_len) = split_aof(&buf[..set.len() + 5]).unwrap();
+        assert_eq!(valid_len, set.len());
+
+        assert!(split_aof(b"+OK\r\n").is_err());
+        assert!(split_aof(b"*1\r\n$3\r\nFOO\r\n").is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::engine::parser::split_inline_args;
+use anyhow::Result;
+
+// what a file listed in the manifest holds
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AofFileType {
+    // the dataset when the last rewrite started, an rdb or commands
+    Base,
+    // the writes since then
+    Incr,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct AofInfo {
+    pub name: String,
+    pub seq: u64,
+    pub file_type: AofFileType,
+}
+
+// the files which together make the aof, loaded base first then the incrs in order
+// one line per file as redis 7 writes it: file <name> seq <n> type <b|i|h>
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct AofManifest {
+    pub base: Option<AofInfo>,
+    pub incrs: Vec<AofInfo>,
+}
+
+impl AofManifest {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut manifest = AofManifest::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let args = split_inline_args(line)?;
+            if args.len() % 2 != 0 {
+                return Err(anyhow::anyhow!("invalid aof manifest line: {}", line));
+            }
+            let (mut name, mut seq, mut file_type) = (None, None, None);
+            for pair in args.chunks(2) {
+                match pair[0].as_str() {
+                    "file" => name = Some(pair[1].clone()),
+                    "seq" => seq = pair[1].parse::<u64>().ok(),
+                    "type" => file_type = Some(pair[1].clone()),
+                    // keys added by later versions are skipped
+                    _ => {}
+                }
+            }
+            let (Some(name), Some(seq), Some(file_type)) = (name, seq, file_type) else {
+                return Err(anyhow::anyhow!("invalid aof manifest line: {}", line));
+            };
+            let file_type = match file_type.as_str() {
+                "b" => AofFileType::Base,
+                "i" => AofFileType::Incr,
+                // left over by a rewrite, waiting to be deleted
+                "h" => continue,
+                _ => return Err(anyhow::anyhow!("unknown aof file type: {}", file_type)),
+            };
+            let info = AofInfo {
+                name,
+                seq,
+                file_type,
+            };
+            match file_type {
+                AofFileType::Base if manifest.base.is_some() => {
+                    return Err(anyhow::anyhow!("more than one base aof in the manifest"));
+                }
+                AofFileType::Base => manifest.base = Some(info),
+                AofFileType::Incr => manifest.incrs.push(info),
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn encode(&self) -> String {
+        self.files()
+            .map(|info| {
+                let name = match info.name.contains(' ') {
+                    true => format!("\"{}\"", info.name),
+                    false => info.name.clone(),
+                };
+                let file_type = match info.file_type {
+                    AofFileType::Base => "b",
+                    AofFileType::Incr => "i",
+                };
+                format!("file {} seq {} type {}\n", name, info.seq, file_type)
+            })
+            .collect()
+    }
+
+    // in the order they are loaded
+    pub fn files(&self) -> impl Iterator<Item = &AofInfo> {
+        self.base.iter().chain(self.incrs.iter())
+    }
+
+    // e.g. appendonly.aof.2.base.rdb
+    pub fn next_base(&self, filename: &str, rdb_preamble: bool) -> AofInfo {
+        let seq = self.base.as_ref().map_or(1, |base| base.seq + 1);
+        let ext = if rdb_preamble { "rdb" } else { "aof" };
+        AofInfo {
+            name: format!("{}.{}.base.{}", filename, seq, ext),
+            seq,
+            file_type: AofFileType::Base,
+        }
+    }
+
+    // e.g. appendonly.aof.3.incr.aof
+    pub fn next_incr(&self, filename: &str) -> AofInfo {
+        let seq = self.incrs.last().map_or(1, |incr| incr.seq + 1);
+        AofInfo {
+            name: format!("{}.{}.incr.aof", filename, seq),
+            seq,
+            file_type: AofFileType::Incr,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_aof_manifest() {
+        let text = "file appendonly.aof.1.base.rdb seq 1 type b\n\
+                    file appendonly.aof.1.incr.aof seq 1 type h\n\
+                    file appendonly.aof.2.incr.aof seq 2 type i\n";
+        let manifest = AofManifest::parse(text).unwrap();
+        assert_eq!(manifest.base.as_ref().unwrap().seq, 1);
+        assert_eq!(manifest.incrs.len(), 1);
+        assert_eq!(
+            manifest.encode(),
+            "file appendonly.aof.1.base.rdb seq 1 type b\n\
+             file appendonly.aof.2.incr.aof seq 2 type i\n"
+        );
+        assert_eq!(
+            manifest.next_base("appendonly.aof", false).name,
+            "appendonly.aof.2.base.aof"
+        );
+        assert_eq!(
+            manifest.next_incr("appendonly.aof").name,
+            "appendonly.aof.3.incr.aof"
+        );
+
+        let spaced = AofManifest::parse(
+            &AofManifest {
+                base: Some(AofInfo {
+                    name: "a b.aof".to_string(),
+                    seq: 1,
+                    file_type: AofFileType::Base,
+                }),
+                incrs: Vec::new(),
+            }
+            .encode(),
+        )
+        .unwrap();
+        assert_eq!(spaced.base.unwrap().name, "a b.aof");
+
+        assert!(AofManifest::parse("file a.aof seq 1 type x\n").is_err());
+        assert!(AofManifest::parse("file a.aof seq 1\n").is_err());
+    }
+}
@@ -0,0 +1,22 @@
+pub mod loader;
+pub mod manifest;
+pub mod writer;
+
+use manifest::AofManifest;
+use std::fs::File;
+use std::path::PathBuf;
+
+// the aof while appendonly is on, the writes are appended to the last incr file
+pub struct AofFile {
+    pub file: File,
+    pub path: PathBuf,
+    pub manifest: AofManifest,
+    // of the whole aof, base and incrs
+    pub size: u64,
+    // right after the last rewrite, the automatic rewrite compares the growth with it
+    pub base_size: u64,
+    // of the file written to, a failed write is cut off at it
+    pub incr_size: u64,
+    // written since the last fsync
+    pub unsynced: bool,
+}
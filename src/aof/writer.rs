@@ -0,0 +1,526 @@
+use super::manifest::{AofInfo, AofManifest};
+use super::AofFile;
+use crate::engine::array_to_resp_array;
+use crate::rdb::config::RDBConfigOps;
+use crate::rdb::writer::{replace_file, RDBWriter};
+use crate::store::config::AppendFsync;
+use crate::store::engine::{now_ms, StoreEngine};
+use crate::store::stats::ServerStats;
+use crate::store::stream_engine::StreamEngine;
+use anyhow::Result;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+const AOF_FSYNC_PERIOD: Duration = Duration::from_secs(1);
+
+pub trait AOFWriter {
+    // appenddirname in dir, holds the manifest and the files it lists
+    fn aof_dir(&self) -> PathBuf;
+    fn manifest_path(&self) -> PathBuf;
+    // None if there is no manifest yet
+    fn read_manifest(&self) -> Result<Option<AofManifest>>;
+    // replaced at once, a crash leaves the previous one
+    fn write_manifest(&self, manifest: &AofManifest) -> Result<()>;
+    // the dataset as the commands which rebuild it
+    fn dump_aof(&self) -> Vec<u8>;
+    // log the writes to a new incr file, listed with the base a blocking task dumps
+    // a start during a rewrite is scheduled for when the rewrite ends
+    fn start_aof(self: &Arc<Self>) -> Result<()>;
+    // log the writes to the aof of the manifest as it is, after it was loaded
+    fn open_aof(&self, manifest: AofManifest) -> Result<()>;
+    // flush and close the file, the writes aren't logged anymore
+    fn stop_aof(&self);
+    // append the commands of a write as they are sent to the replicas
+    fn feed_aof(&self, cmds: &[Vec<String>]);
+    // flush what was written so far to disk
+    fn fsync_aof(&self);
+    // compact the aof into a new base in a blocking task, fails if a rewrite already runs
+    fn bgrewrite_aof(self: &Arc<Self>) -> Result<()>;
+    // flushes once a second under appendfsync everysec and starts the automatic rewrites
+    fn aof_cron(self: &Arc<Self>) -> impl std::future::Future<Output = ()> + Send;
+}
+
+impl AOFWriter for StoreEngine {
+    fn aof_dir(&self) -> PathBuf {
+        Path::new(&self.get_dir()).join(&self.config.read().unwrap().appenddirname)
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        let filename = self.config.read().unwrap().appendfilename.clone();
+        self.aof_dir().join(format!("{}.manifest", filename))
+    }
+
+    fn read_manifest(&self) -> Result<Option<AofManifest>> {
+        match fs::read_to_string(self.manifest_path()) {
+            Ok(text) => Ok(Some(AofManifest::parse(&text)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_manifest(&self, manifest: &AofManifest) -> Result<()> {
+        let tmp_path = self
+            .aof_dir()
+            .join(format!("temp-manifest-{}", std::process::id()));
+        replace_file(
+            &tmp_path,
+            &self.manifest_path(),
+            manifest.encode().as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    fn dump_aof(&self) -> Vec<u8> {
+        let now = now_ms();
+        let mut cmds = Vec::new();
+        for (key, value, at) in self.get_string_entries() {
+            match at {
+                Some(at) if at <= now => {}
+                Some(at) => cmds.push(vec![
+                    "SET".to_string(),
+                    key,
+                    value,
+                    "PXAT".to_string(),
+                    at.to_string(),
+                ]),
+                None => cmds.push(vec!["SET".to_string(), key, value]),
+            }
+        }
+        // a stream without entries can't be rebuilt with XADD and is left out
+        for (key, entries, _) in self.get_streams() {
+            let at = self.get_expire(&key);
+            if at.is_some_and(|at| at <= now) {
+                continue;
+            }
+            for (id, fields) in entries {
+                let mut cmd = vec!["XADD".to_string(), key.clone(), String::from(&id)];
+                for (field, value) in fields {
+                    cmd.extend([field, value]);
+                }
+                cmds.push(cmd);
+            }
+            if let Some(at) = at {
+                cmds.push(vec!["PEXPIREAT".to_string(), key, at.to_string()]);
+            }
+        }
+        cmds.into_iter()
+            .map(array_to_resp_array)
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    fn start_aof(self: &Arc<Self>) -> Result<()> {
+        // it writes a base too, a rewrite running meanwhile would pick the same seq
+        if !self.stats.begin_aof_rewrite() {
+            // that rewrite dumps a dataset the new aof may not start from
+            self.stop_aof();
+            self.stats
+                .aof_rewrite_scheduled
+                .store(true, Ordering::Relaxed);
+            println!("append only file start scheduled after the running rewrite");
+            return Ok(());
+        }
+        let (rewritten, rdb_preamble) = begin_start(self).inspect_err(|_| {
+            self.stats.end_aof_rewrite(false);
+        })?;
+        spawn_rewrite(self, rewritten, rdb_preamble);
+        Ok(())
+    }
+
+    fn open_aof(&self, mut manifest: AofManifest) -> Result<()> {
+        let dir = self.aof_dir();
+        // a base alone gets an incr file for the writes to come
+        if manifest.incrs.is_empty() {
+            let filename = self.config.read().unwrap().appendfilename.clone();
+            let incr = manifest.next_incr(&filename);
+            open_incr(&dir, &incr)?;
+            manifest.incrs.push(incr);
+            self.write_manifest(&manifest)?;
+        }
+
+        let file_size = |info: &AofInfo| fs::metadata(dir.join(&info.name)).map_or(0, |m| m.len());
+        let base_size = manifest.base.as_ref().map_or(0, file_size);
+        let size = manifest.files().map(file_size).sum();
+        let incr = manifest.incrs.last().unwrap();
+        let file = open_incr(&dir, incr)?;
+        *self.aof.lock().unwrap() = Some(AofFile {
+            path: dir.join(&incr.name),
+            incr_size: file.metadata()?.len(),
+            file,
+            manifest,
+            size,
+            base_size,
+            unsynced: false,
+        });
+        Ok(())
+    }
+
+    fn stop_aof(&self) {
+        self.stats
+            .aof_rewrite_scheduled
+            .store(false, Ordering::Relaxed);
+        if let Some(aof) = self.aof.lock().unwrap().take() {
+            if let Err(e) = aof.file.sync_data() {
+                println!("err: fsync of {}: {}", aof.path.display(), e);
+            }
+            println!("append only file {} stopped", aof.path.display());
+        }
+    }
+
+    fn feed_aof(&self, cmds: &[Vec<String>]) {
+        let mut aof = self.aof.lock().unwrap();
+        let Some(aof) = aof.as_mut() else {
+            return;
+        };
+
+        let payload: String = cmds.iter().cloned().map(array_to_resp_array).collect();
+        let always = self.config.read().unwrap().appendfsync == AppendFsync::Always;
+        let written = aof.file.write_all(payload.as_bytes()).and_then(|_| {
+            if always {
+                aof.file.sync_data()?;
+            }
+            Ok(())
+        });
+        match &written {
+            Ok(_) => {
+                aof.size += payload.len() as u64;
+                aof.incr_size += payload.len() as u64;
+                aof.unsynced = !always;
+            }
+            Err(e) => {
+                println!("err: writing {}: {}", aof.path.display(), e);
+                // half a command would make the file unreadable
+                let _ = aof.file.set_len(aof.incr_size);
+            }
+        }
+        self.stats
+            .aof_last_write_ok
+            .store(written.is_ok(), Ordering::Relaxed);
+    }
+
+    fn fsync_aof(&self) {
+        if let Some(aof) = self.aof.lock().unwrap().as_mut() {
+            if let Err(e) = aof.file.sync_data() {
+                println!("err: fsync of {}: {}", aof.path.display(), e);
+            }
+            aof.unsynced = false;
+        }
+    }
+
+    fn bgrewrite_aof(self: &Arc<Self>) -> Result<()> {
+        if !self.stats.begin_aof_rewrite() {
+            return Err(anyhow::anyhow!(
+                "Background append only file rewriting already in progress"
+            ));
+        }
+        let (rewritten, rdb_preamble) = begin_rewrite(self).inspect_err(|_| {
+            self.stats.end_aof_rewrite(false);
+        })?;
+        spawn_rewrite(self, rewritten, rdb_preamble);
+        Ok(())
+    }
+
+    async fn aof_cron(self: &Arc<Self>) {
+        let mut ticker = tokio::time::interval(AOF_FSYNC_PERIOD);
+        loop {
+            ticker.tick().await;
+            // a start which waited for the rewrite before it
+            if !self.stats.aof_rewrite_in_progress.load(Ordering::Relaxed)
+                && self
+                    .stats
+                    .aof_rewrite_scheduled
+                    .swap(false, Ordering::Relaxed)
+            {
+                if let Err(e) = self.start_aof() {
+                    println!("err: scheduled append only file start: {}", e);
+                }
+            }
+            if should_auto_rewrite(self) {
+                match self.bgrewrite_aof() {
+                    Ok(_) => println!("the append only file has grown, rewriting it"),
+                    Err(e) => println!("err: automatic aof rewrite: {}", e),
+                }
+            }
+
+            if self.config.read().unwrap().appendfsync != AppendFsync::EverySec {
+                continue;
+            }
+            // the fsync runs on a handle of its own so the writes don't wait for it
+            let file = match self.aof.lock().unwrap().as_mut() {
+                Some(aof) if aof.unsynced => {
+                    aof.unsynced = false;
+                    aof.file.try_clone()
+                }
+                _ => continue,
+            };
+            let synced = tokio::task::spawn_blocking(move || file?.sync_data()).await;
+            if let Ok(Err(e)) = synced {
+                println!("err: fsync of the append only file: {}", e);
+            }
+        }
+    }
+}
+
+// appendfilename and aof-use-rdb-preamble
+fn rewrite_names(db: &StoreEngine) -> (String, bool) {
+    let config = db.config.read().unwrap();
+    (config.appendfilename.clone(), config.aof_use_rdb_preamble)
+}
+
+// a rewrite at a time, each writes the base of its own seq
+fn temp_base_name(base: &AofInfo) -> String {
+    format!("temp-rewriteaof-{}-{}.aof", std::process::id(), base.seq)
+}
+
+// an rdb loads faster, commands can be read by a human
+fn dump_base(db: &StoreEngine, rdb_preamble: bool) -> Vec<u8> {
+    match rdb_preamble {
+        true => db.dump(true),
+        false => db.dump_aof(),
+    }
+}
+
+fn open_incr(dir: &Path, incr: &AofInfo) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(&incr.name))
+}
+
+// files of the previous manifest which the new one doesn't need anymore
+fn remove_unlisted(dir: &Path, previous: &AofManifest, manifest: &AofManifest) {
+    for info in previous.files() {
+        if manifest.files().all(|kept| kept.name != info.name) {
+            if let Err(e) = fs::remove_file(dir.join(&info.name)) {
+                println!("err: removing {}: {}", info.name, e);
+            }
+        }
+    }
+}
+
+// the writes switch to a new incr file which is listed right away, so the aof
+// on disk stays complete, the new base is dumped afterwards without the lock
+// returns the manifest the rewrite ends with and whether its base is an rdb
+fn begin_rewrite(db: &StoreEngine) -> Result<(AofManifest, bool)> {
+    let mut aof = db.aof.lock().unwrap();
+    let dir = db.aof_dir();
+    fs::create_dir_all(&dir)?;
+    let (filename, rdb_preamble) = rewrite_names(db);
+
+    let mut incrs = Vec::new();
+    let previous = match aof.as_mut() {
+        Some(open) => {
+            let incr = open.manifest.next_incr(&filename);
+            let file = open_incr(&dir, &incr)?;
+            let mut manifest = open.manifest.clone();
+            manifest.incrs.push(incr.clone());
+            db.write_manifest(&manifest)?;
+
+            open.file = file;
+            open.path = dir.join(&incr.name);
+            open.incr_size = 0;
+            open.manifest = manifest.clone();
+            incrs.push(incr);
+            manifest
+        }
+        // with the aof off there is only the base to write
+        None => db.read_manifest()?.unwrap_or_default(),
+    };
+
+    let rewritten = AofManifest {
+        base: Some(previous.next_base(&filename, rdb_preamble)),
+        incrs,
+    };
+    Ok((rewritten, rdb_preamble))
+}
+
+// the writes go to a new incr file right away, it is listed in the manifest with
+// the base once that is written, until then the files on disk are left as they are
+fn begin_start(db: &StoreEngine) -> Result<(AofManifest, bool)> {
+    let mut aof = db.aof.lock().unwrap();
+    let dir = db.aof_dir();
+    fs::create_dir_all(&dir)?;
+    let previous = match aof.take() {
+        Some(open) => open.manifest,
+        None => db.read_manifest()?.unwrap_or_default(),
+    };
+
+    let (filename, rdb_preamble) = rewrite_names(db);
+    let incr = previous.next_incr(&filename);
+    // not listed anywhere, left by a start which didn't finish
+    let _ = fs::remove_file(dir.join(&incr.name));
+    let file = open_incr(&dir, &incr)?;
+
+    println!("append only file {} started", dir.display());
+    *aof = Some(AofFile {
+        path: dir.join(&incr.name),
+        file,
+        manifest: AofManifest {
+            base: None,
+            incrs: vec![incr.clone()],
+        },
+        size: 0,
+        base_size: 0,
+        incr_size: 0,
+        unsynced: false,
+    });
+    let rewritten = AofManifest {
+        base: Some(previous.next_base(&filename, rdb_preamble)),
+        incrs: vec![incr],
+    };
+    Ok((rewritten, rdb_preamble))
+}
+
+// dump the new base and swap the manifest in a blocking task, as BGSAVE does
+// a write applied after the incr switch may be in both the base and the incr,
+// replayed again it leaves the same value as it carries absolute times and ids
+fn spawn_rewrite(db: &Arc<StoreEngine>, rewritten: AofManifest, rdb_preamble: bool) {
+    let db = db.clone();
+    tokio::task::spawn_blocking(move || {
+        let content = dump_base(&db, rdb_preamble);
+        let finished = finish_rewrite(&db, rewritten, &content);
+        if let Err(e) = &finished {
+            println!("err: background aof rewrite: {}", e);
+        }
+        db.stats.end_aof_rewrite(finished.is_ok());
+    });
+}
+
+// write the base and swap the manifest, the files it replaces are removed
+fn finish_rewrite(db: &StoreEngine, mut rewritten: AofManifest, content: &[u8]) -> Result<()> {
+    let dir = db.aof_dir();
+    let base = rewritten.base.clone().unwrap();
+    replace_file(
+        &dir.join(temp_base_name(&base)),
+        &dir.join(&base.name),
+        content,
+    )?;
+
+    let mut aof = db.aof.lock().unwrap();
+    // the aof was stopped or started over while the base was written
+    let stale = match (aof.as_ref(), rewritten.incrs.first()) {
+        (Some(open), Some(incr)) => !open.manifest.incrs.contains(incr),
+        (None, None) => false,
+        _ => true,
+    };
+    if stale {
+        let _ = fs::remove_file(dir.join(&base.name));
+        return Err(anyhow::anyhow!(
+            "the append only file changed during the rewrite"
+        ));
+    }
+
+    // the incr files opened since the rewrite started hold the writes the base misses
+    if let (Some(open), Some(first)) = (aof.as_ref(), rewritten.incrs.first()) {
+        rewritten.incrs = open
+            .manifest
+            .incrs
+            .iter()
+            .filter(|incr| incr.seq >= first.seq)
+            .cloned()
+            .collect();
+    }
+    // a start hasn't listed its incr yet, what it replaces is only on disk
+    let previous = db.read_manifest()?.unwrap_or_default();
+    db.write_manifest(&rewritten)?;
+    remove_unlisted(&dir, &previous, &rewritten);
+
+    if let Some(open) = aof.as_mut() {
+        open.base_size = content.len() as u64;
+        open.size = open.base_size
+            + rewritten
+                .incrs
+                .iter()
+                .map(|incr| fs::metadata(dir.join(&incr.name)).map_or(0, |m| m.len()))
+                .sum::<u64>();
+        open.manifest = rewritten;
+    }
+    ServerStats::incr(&db.stats.aof_rewrites, 1);
+    Ok(())
+}
+
+// the aof is bigger than auto-aof-rewrite-min-size and has grown by
+// auto-aof-rewrite-percentage since the last rewrite, 0 disables it
+fn should_auto_rewrite(db: &StoreEngine) -> bool {
+    let (percentage, min_size) = {
+        let config = db.config.read().unwrap();
+        (
+            config.auto_aof_rewrite_percentage,
+            config.auto_aof_rewrite_min_size,
+        )
+    };
+    if percentage == 0 || db.stats.aof_rewrite_in_progress.load(Ordering::Relaxed) {
+        return false;
+    }
+    match db.aof.lock().unwrap().as_ref() {
+        Some(aof) if aof.size > min_size => {
+            let base = aof.base_size.max(1);
+            aof.size.saturating_sub(base) * 100 / base >= percentage
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aof::loader::AOFLoader;
+    use crate::engine::commands::command_handler;
+    use crate::engine::RespMessage;
+    use crate::store::config::ConfigOps;
+    use std::sync::RwLock;
+
+    fn set(db: &Arc<StoreEngine>, i: usize) {
+        let args = vec!["SET".to_string(), format!("k{}", i), i.to_string()];
+        let cmd = Arc::new(RwLock::new(RespMessage::from_args(
+            "test".to_string(),
+            args,
+        )));
+        command_handler(db, cmd).unwrap();
+    }
+
+    async fn wait_rewrite(db: &StoreEngine) {
+        while db.stats.aof_rewrite_in_progress.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_bgrewrite_aof_with_writes() {
+        let dir = std::env::temp_dir().join(format!("aof-rewrite-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let db = Arc::new(StoreEngine::new());
+        db.set_dir(dir.display().to_string());
+        db.config_set("appendonly", "yes").unwrap();
+        wait_rewrite(&db).await;
+        for i in 0..1000 {
+            set(&db, i);
+        }
+
+        // the writes keep coming while the base is dumped
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || (1000..5000).for_each(|i| set(&db, i)))
+        };
+        db.bgrewrite_aof().unwrap();
+        writer.join().unwrap();
+        wait_rewrite(&db).await;
+        assert!(db.stats.aof_last_rewrite_ok.load(Ordering::Relaxed));
+        set(&db, 5000);
+        db.fsync_aof();
+
+        let loaded = Arc::new(StoreEngine::new());
+        loaded.set_dir(dir.display().to_string());
+        loaded.stats.loading.store(true, Ordering::Relaxed);
+        let manifest = loaded.load_aof().unwrap().unwrap();
+        // the base of the start and the one of the rewrite
+        assert_eq!(manifest.base.unwrap().seq, 2);
+        assert_eq!(loaded.get_keys().len(), 5001);
+        assert_eq!(loaded.get("k4999"), Some("4999".to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,682 @@
+use std::sync::{Arc, RwLock};
+
+use super::handler::{
+    handle_bgrewriteaof, handle_bgsave, handle_command, handle_config, handle_del, handle_discard,
+    handle_echo, handle_exec, handle_expire, handle_get, handle_hello, handle_info, handle_keys,
+    handle_lastsave, handle_multi, handle_persist, handle_ping, handle_psync, handle_replica,
+    handle_replicaof, handle_role, handle_save, handle_set, handle_ttl, handle_type, handle_wait,
+    handle_xadd, handle_xrange, handle_xread,
+};
+use super::reply::RespReply;
+use super::{CommandHandlerResponse, RespMessage};
+
+use crate::store::engine::StoreEngine;
+use anyhow::Result;
+
+pub type CommandFn =
+    fn(&Arc<StoreEngine>, Arc<RwLock<RespMessage>>) -> Result<CommandHandlerResponse>;
+
+// flags of a command, same meaning as in redis
+pub mod command_flag {
+    pub const WRITE: u32 = 1 << 0;
+    pub const READONLY: u32 = 1 << 1;
+    pub const DENYOOM: u32 = 1 << 2;
+    pub const ADMIN: u32 = 1 << 3;
+    pub const PUBSUB: u32 = 1 << 4;
+    pub const NOSCRIPT: u32 = 1 << 5;
+    pub const BLOCKING: u32 = 1 << 6;
+    // allowed while the dataset is loading
+    pub const LOADING: u32 = 1 << 7;
+    // allowed on a replica with a broken link
+    pub const STALE: u32 = 1 << 8;
+    pub const FAST: u32 = 1 << 9;
+    // refused inside MULTI
+    pub const NO_MULTI: u32 = 1 << 10;
+}
+
+use command_flag::*;
+
+const FLAG_NAMES: [(u32, &str); 11] = [
+    (WRITE, "write"),
+    (READONLY, "readonly"),
+    (DENYOOM, "denyoom"),
+    (ADMIN, "admin"),
+    (PUBSUB, "pubsub"),
+    (NOSCRIPT, "noscript"),
+    (BLOCKING, "blocking"),
+    (LOADING, "loading"),
+    (STALE, "stale"),
+    (FAST, "fast"),
+    (NO_MULTI, "no_multi"),
+];
+
+// where the keys of a command are
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeySpec {
+    None,
+    // first key, last key (negative counts from the end) and step
+    Range { first: i32, last: i32, step: i32 },
+    // keys follow the keyword and take the first half of the remaining arguments
+    // e.g. XREAD ... STREAMS key [key ...] id [id ...]
+    Keyword(&'static str),
+}
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    // positive: exact number of arguments, negative: at least -arity
+    // the command name is counted
+    pub arity: i32,
+    pub flags: u32,
+    pub keys: KeySpec,
+    pub group: &'static str,
+    pub since: &'static str,
+    pub summary: &'static str,
+    pub handler: CommandFn,
+}
+
+pub static COMMAND_TABLE: &[CommandSpec] = &[
+    CommandSpec {
+        name: "get",
+        arity: 2,
+        flags: READONLY | FAST,
+        keys: KeySpec::Range { first: 1, last: 1, step: 1 },
+        group: "string",
+        since: "1.0.0",
+        summary: "Returns the string value of a key.",
+        handler: handle_get,
+    },
+    CommandSpec {
+        name: "set",
+        arity: -3,
+        flags: WRITE | DENYOOM,
+        keys: KeySpec::Range { first: 1, last: 1, step: 1 },
+        group: "string",
+        since: "1.0.0",
+        summary: "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist.",
+        handler: handle_set,
+    },
+    CommandSpec {
+        name: "ping",
+        arity: -1,
+        flags: FAST,
+        keys: KeySpec::None,
+        group: "connection",
+        since: "1.0.0",
+        summary: "Returns the server's liveliness response.",
+        handler: handle_ping,
+    },
+    CommandSpec {
+        name: "echo",
+        arity: 2,
+        flags: FAST,
+        keys: KeySpec::None,
+        group: "connection",
+        since: "1.0.0",
+        summary: "Returns the given string.",
+        handler: handle_echo,
+    },
+    CommandSpec {
+        name: "hello",
+        arity: -1,
+        flags: NOSCRIPT | LOADING | STALE | FAST,
+        keys: KeySpec::None,
+        group: "connection",
+        since: "6.0.0",
+        summary: "Handshakes with the Redis server.",
+        handler: handle_hello,
+    },
+    CommandSpec {
+        name: "multi",
+        arity: 1,
+        flags: NOSCRIPT | LOADING | STALE | FAST | NO_MULTI,
+        keys: KeySpec::None,
+        group: "transactions",
+        since: "1.2.0",
+        summary: "Starts a transaction.",
+        handler: handle_multi,
+    },
+    CommandSpec {
+        name: "exec",
+        arity: 1,
+        flags: NOSCRIPT | LOADING | STALE,
+        keys: KeySpec::None,
+        group: "transactions",
+        since: "1.2.0",
+        summary: "Executes all commands in a transaction.",
+        handler: handle_exec,
+    },
+    CommandSpec {
+        name: "discard",
+        arity: 1,
+        flags: NOSCRIPT | LOADING | STALE | FAST,
+        keys: KeySpec::None,
+        group: "transactions",
+        since: "2.0.0",
+        summary: "Discards a transaction.",
+        handler: handle_discard,
+    },
+    CommandSpec {
+        name: "info",
+        arity: -1,
+        flags: LOADING | STALE,
+        keys: KeySpec::None,
+        group: "server",
+        since: "1.0.0",
+        summary: "Returns information and statistics about the server.",
+        handler: handle_info,
+    },
+    CommandSpec {
+        name: "config",
+        arity: -2,
+        flags: ADMIN | NOSCRIPT | LOADING | STALE,
+        keys: KeySpec::None,
+        group: "server",
+        since: "2.0.0",
+        summary: "A container for server configuration commands.",
+        handler: handle_config,
+    },
+    CommandSpec {
+        name: "command",
+        arity: -1,
+        flags: LOADING | STALE,
+        keys: KeySpec::None,
+        group: "server",
+        since: "2.8.13",
+        summary: "Returns detailed information about all commands.",
+        handler: handle_command,
+    },
+    CommandSpec {
+        name: "replconf",
+        arity: -1,
+        flags: ADMIN | NOSCRIPT | LOADING | STALE,
+        keys: KeySpec::None,
+        group: "server",
+        since: "3.0.0",
+        summary: "An internal command for configuring the replication stream.",
+        handler: handle_replica,
+    },
+    CommandSpec {
+        name: "psync",
+        arity: -3,
+        flags: ADMIN | NOSCRIPT | NO_MULTI,
+        keys: KeySpec::None,
+        group: "server",
+        since: "2.8.0",
+        summary: "An internal command used in replication.",
+        handler: handle_psync,
+    },
+    CommandSpec {
+        name: "replicaof",
+        arity: 3,
+        flags: ADMIN | NOSCRIPT | STALE,
+        keys: KeySpec::None,
+        group: "server",
+        since: "5.0.0",
+        summary: "Configures a server as replica of another, or promotes it to a master.",
+        handler: handle_replicaof,
+    },
+    CommandSpec {
+        name: "slaveof",
+        arity: 3,
+        flags: ADMIN | NOSCRIPT | STALE,
+        keys: KeySpec::None,
+        group: "server",
+        since: "1.0.0",
+        summary: "Sets a Redis server as a replica of another, or promotes it to being a master.",
+        handler: handle_replicaof,
+    },
+    CommandSpec {
+        name: "save",
+        arity: 1,
+        flags: ADMIN | NOSCRIPT | NO_MULTI,
+        keys: KeySpec::None,
+        group: "server",
+        since: "1.0.0",
+        summary: "Synchronously saves the database(s) to disk.",
+        handler: handle_save,
+    },
+    CommandSpec {
+        name: "bgsave",
+        arity: -1,
+        flags: ADMIN | NOSCRIPT,
+        keys: KeySpec::None,
+        group: "server",
+        since: "1.0.0",
+        summary: "Asynchronously saves the database(s) to disk.",
+        handler: handle_bgsave,
+    },
+    CommandSpec {
+        name: "bgrewriteaof",
+        arity: 1,
+        flags: ADMIN | NOSCRIPT,
+        keys: KeySpec::None,
+        group: "server",
+        since: "1.0.0",
+        summary: "Asynchronously rewrites the append-only file to disk.",
+        handler: handle_bgrewriteaof,
+    },
+    CommandSpec {
+        name: "lastsave",
+        arity: 1,
+        flags: LOADING | STALE | FAST,
+        keys: KeySpec::None,
+        group: "server",
+        since: "1.0.0",
+        summary: "Returns the Unix timestamp of the last successful save to disk.",
+        handler: handle_lastsave,
+    },
+    CommandSpec {
+        name: "role",
+        arity: 1,
+        flags: NOSCRIPT | LOADING | STALE | FAST,
+        keys: KeySpec::None,
+        group: "server",
+        since: "2.8.12",
+        summary: "Returns the replication role.",
+        handler: handle_role,
+    },
+    CommandSpec {
+        name: "wait",
+        arity: 3,
+        flags: NOSCRIPT,
+        keys: KeySpec::None,
+        group: "generic",
+        since: "3.0.0",
+        summary: "Blocks until the asynchronous replication of all preceding write commands sent by the connection is completed.",
+        handler: handle_wait,
+    },
+    CommandSpec {
+        name: "keys",
+        arity: 2,
+        flags: READONLY,
+        keys: KeySpec::None,
+        group: "generic",
+        since: "1.0.0",
+        summary: "Returns all key names that match a pattern.",
+        handler: handle_keys,
+    },
+    CommandSpec {
+        name: "type",
+        arity: 2,
+        flags: READONLY | FAST,
+        keys: KeySpec::Range { first: 1, last: 1, step: 1 },
+        group: "generic",
+        since: "1.0.0",
+        summary: "Determines the type of value stored at a key.",
+        handler: handle_type,
+    },
+    CommandSpec {
+        name: "del",
+        arity: -2,
+        flags: WRITE,
+        keys: KeySpec::Range { first: 1, last: -1, step: 1 },
+        group: "generic",
+        since: "1.0.0",
+        summary: "Deletes one or more keys.",
+        handler: handle_del,
+    },
+    CommandSpec {
+        name: "expire",
+        arity: -3,
+        flags: WRITE | FAST,
+        keys: KeySpec::Range { first: 1, last: 1, step: 1 },
+        group: "generic",
+        since: "1.0.0",
+        summary: "Sets the expiration time of a key in seconds.",
+        handler: handle_expire,
+    },
+    CommandSpec {
+        name: "pexpire",
+        arity: -3,
+        flags: WRITE | FAST,
+        keys: KeySpec::Range { first: 1, last: 1, step: 1 },
+        group: "generic",
+        since: "2.6.0",
+        summary: "Sets the expiration time of a key in milliseconds.",
+        handler: handle_expire,
+    },
+    CommandSpec {
+        name: "expireat",
+        arity: -3,
+        flags: WRITE | FAST,
+        keys: KeySpec::Range { first: 1, last: 1, step: 1 },
+        group: "generic",
+        since: "1.2.0",
+        summary: "Sets the expiration time of a key to a Unix timestamp.",
+        handler: handle_expire,
+    },
+    CommandSpec {
+        name: "pexpireat",
+        arity: -3,
+        flags: WRITE | FAST,
+        keys: KeySpec::Range { first: 1, last: 1, step: 1 },
+        group: "generic",
+        since: "2.6.0",
+        summary: "Sets the expiration time of a key to a Unix milliseconds timestamp.",
+        handler: handle_expire,
+    },
+    CommandSpec {
+        name: "persist",
+        arity: 2,
+        flags: WRITE | FAST,
+        keys: KeySpec::Range { first: 1, last: 1, step: 1 },
+        group: "generic",
+        since: "2.2.0",
+        summary: "Removes the expiration time of a key.",
+        handler: handle_persist,
+    },
+    CommandSpec {
+        name: "ttl",
+        arity: 2,
+        flags: READONLY | FAST,
+        keys: KeySpec::Range { first: 1, last: 1, step: 1 },
+        group: "generic",
+        since: "1.0.0",
+        summary: "Returns the expiration time in seconds of a key.",
+        handler: handle_ttl,
+    },
+    CommandSpec {
+        name: "pttl",
+        arity: 2,
+        flags: READONLY | FAST,
+        keys: KeySpec::Range { first: 1, last: 1, step: 1 },
+        group: "generic",
+        since: "2.6.0",
+        summary: "Returns the expiration time in milliseconds of a key.",
+        handler: handle_ttl,
+    },
+    CommandSpec {
+        name: "xadd",
+        arity: -5,
+        flags: WRITE | DENYOOM | FAST,
+        keys: KeySpec::Range { first: 1, last: 1, step: 1 },
+        group: "stream",
+        since: "5.0.0",
+        summary: "Appends a new message to a stream. Creates the key if it doesn't exist.",
+        handler: handle_xadd,
+    },
+    CommandSpec {
+        name: "xrange",
+        arity: -4,
+        flags: READONLY,
+        keys: KeySpec::Range { first: 1, last: 1, step: 1 },
+        group: "stream",
+        since: "5.0.0",
+        summary: "Returns the messages from a stream within a range of IDs.",
+        handler: handle_xrange,
+    },
+    CommandSpec {
+        name: "xread",
+        arity: -4,
+        flags: READONLY | BLOCKING,
+        keys: KeySpec::Keyword("streams"),
+        group: "stream",
+        since: "5.0.0",
+        summary: "Returns messages from multiple streams with IDs greater than the ones requested. Blocks until a message is available otherwise.",
+        handler: handle_xread,
+    },
+];
+
+pub fn lookup_command(name: &str) -> Option<&'static CommandSpec> {
+    COMMAND_TABLE
+        .iter()
+        .find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
+
+impl CommandSpec {
+    pub fn has_flag(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.has_flag(WRITE)
+    }
+
+    // argc includes the command name
+    pub fn check_arity(&self, argc: usize) -> bool {
+        let argc = argc as i32;
+        if self.arity >= 0 {
+            argc == self.arity
+        } else {
+            argc >= -self.arity
+        }
+    }
+
+    // first key, last key and step as in the legacy COMMAND reply
+    // commands with movable keys report 0 0 0
+    pub fn key_range(&self) -> (i32, i32, i32) {
+        match self.keys {
+            KeySpec::Range { first, last, step } => (first, last, step),
+            _ => (0, 0, 0),
+        }
+    }
+
+    pub fn flag_names(&self) -> Vec<&'static str> {
+        let mut names: Vec<&'static str> = FLAG_NAMES
+            .iter()
+            .filter(|(flag, _)| self.has_flag(*flag))
+            .map(|(_, name)| *name)
+            .collect();
+        if matches!(self.keys, KeySpec::Keyword(_)) {
+            names.push("movablekeys");
+        }
+        names
+    }
+
+    // ACL categories are derived from the flags and the group
+    // they are only reported by COMMAND, there are no users or ACL rules to check them against
+    pub fn acl_categories(&self) -> Vec<String> {
+        let mut categories = Vec::new();
+        // server commands only get the categories of their flags
+        let group = match self.group {
+            "generic" => Some("keyspace"),
+            "transactions" => Some("transaction"),
+            "server" => None,
+            group => Some(group),
+        };
+        if let Some(group) = group {
+            categories.push(format!("@{}", group));
+        }
+        if self.has_flag(WRITE) {
+            categories.push("@write".to_string());
+        }
+        if self.has_flag(READONLY) {
+            categories.push("@read".to_string());
+        }
+        if self.has_flag(ADMIN) {
+            categories.push("@admin".to_string());
+            categories.push("@dangerous".to_string());
+        }
+        if self.has_flag(PUBSUB) {
+            categories.push("@pubsub".to_string());
+        }
+        if self.has_flag(BLOCKING) {
+            categories.push("@blocking".to_string());
+        }
+        if self.has_flag(FAST) {
+            categories.push("@fast".to_string());
+        } else {
+            categories.push("@slow".to_string());
+        }
+        categories
+    }
+
+    // positions of the keys in args, args[0] being the command name
+    pub fn key_positions(&self, args: &[String]) -> Vec<usize> {
+        match self.keys {
+            KeySpec::None => Vec::new(),
+            KeySpec::Range { first, last, step } => {
+                let argc = args.len() as i32;
+                let last = if last < 0 { argc + last } else { last };
+                let mut pos = Vec::new();
+                let mut i = first;
+                while i <= last && i < argc {
+                    pos.push(i as usize);
+                    i += step;
+                }
+                pos
+            }
+            KeySpec::Keyword(keyword) => {
+                match args
+                    .iter()
+                    .skip(1)
+                    .position(|a| a.eq_ignore_ascii_case(keyword))
+                {
+                    Some(idx) => {
+                        let start = idx + 2;
+                        let num = (args.len() - start) / 2;
+                        (start..start + num).collect()
+                    }
+                    None => Vec::new(),
+                }
+            }
+        }
+    }
+
+    pub fn get_keys(&self, args: &[String]) -> Vec<String> {
+        self.key_positions(args)
+            .into_iter()
+            .map(|i| args[i].clone())
+            .collect()
+    }
+
+    // reply of COMMAND INFO
+    // [name, arity, flags, first key, last key, step, acl categories, tips, key specs, subcommands]
+    pub fn info_reply(&self) -> RespReply {
+        let (first, last, step) = self.key_range();
+        RespReply::Array(vec![
+            RespReply::bulk(self.name),
+            RespReply::Integer(self.arity as i64),
+            RespReply::Array(
+                self.flag_names()
+                    .into_iter()
+                    .map(RespReply::simple)
+                    .collect(),
+            ),
+            RespReply::Integer(first as i64),
+            RespReply::Integer(last as i64),
+            RespReply::Integer(step as i64),
+            RespReply::Array(
+                self.acl_categories()
+                    .into_iter()
+                    .map(RespReply::simple)
+                    .collect(),
+            ),
+            RespReply::Array(Vec::new()),
+            self.key_specs_reply(),
+            RespReply::Array(Vec::new()),
+        ])
+    }
+
+    fn key_specs_reply(&self) -> RespReply {
+        let flags = if self.has_flag(WRITE) { "RW" } else { "RO" };
+        let (begin_search, find_keys) = match self.keys {
+            KeySpec::None => return RespReply::Array(Vec::new()),
+            KeySpec::Range { first, last, step } => (
+                RespReply::Map(vec![
+                    (RespReply::bulk("type"), RespReply::bulk("index")),
+                    (
+                        RespReply::bulk("spec"),
+                        RespReply::Map(vec![(
+                            RespReply::bulk("index"),
+                            RespReply::Integer(first as i64),
+                        )]),
+                    ),
+                ]),
+                RespReply::Map(vec![
+                    (RespReply::bulk("type"), RespReply::bulk("range")),
+                    (
+                        RespReply::bulk("spec"),
+                        RespReply::Map(vec![
+                            (
+                                RespReply::bulk("lastkey"),
+                                RespReply::Integer(if last < 0 {
+                                    last as i64
+                                } else {
+                                    (last - first) as i64
+                                }),
+                            ),
+                            (RespReply::bulk("keystep"), RespReply::Integer(step as i64)),
+                            (RespReply::bulk("limit"), RespReply::Integer(0)),
+                        ]),
+                    ),
+                ]),
+            ),
+            KeySpec::Keyword(keyword) => (
+                RespReply::Map(vec![
+                    (RespReply::bulk("type"), RespReply::bulk("keyword")),
+                    (
+                        RespReply::bulk("spec"),
+                        RespReply::Map(vec![
+                            (
+                                RespReply::bulk("keyword"),
+                                RespReply::bulk(keyword.to_uppercase()),
+                            ),
+                            (RespReply::bulk("startfrom"), RespReply::Integer(1)),
+                        ]),
+                    ),
+                ]),
+                RespReply::Map(vec![
+                    (RespReply::bulk("type"), RespReply::bulk("range")),
+                    (
+                        RespReply::bulk("spec"),
+                        RespReply::Map(vec![
+                            (RespReply::bulk("lastkey"), RespReply::Integer(-1)),
+                            (RespReply::bulk("keystep"), RespReply::Integer(1)),
+                            (RespReply::bulk("limit"), RespReply::Integer(2)),
+                        ]),
+                    ),
+                ]),
+            ),
+        };
+
+        RespReply::Array(vec![RespReply::Map(vec![
+            (
+                RespReply::bulk("flags"),
+                RespReply::Array(vec![RespReply::simple(flags)]),
+            ),
+            (RespReply::bulk("begin_search"), begin_search),
+            (RespReply::bulk("find_keys"), find_keys),
+        ])])
+    }
+
+    // reply of COMMAND DOCS, the value part of the name => docs map
+    pub fn docs_reply(&self) -> RespReply {
+        RespReply::Map(vec![
+            (RespReply::bulk("summary"), RespReply::bulk(self.summary)),
+            (RespReply::bulk("since"), RespReply::bulk(self.since)),
+            (RespReply::bulk("group"), RespReply::bulk(self.group)),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn to_args(s: &str) -> Vec<String> {
+        s.split(' ').map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_command_table() {
+        let set = lookup_command("SET").unwrap();
+        assert!(set.is_write());
+        assert!(!set.check_arity(2));
+        assert!(set.check_arity(5));
+        assert_eq!(set.get_keys(&to_args("set foo bar")), vec!["foo"]);
+
+        let get = lookup_command("get").unwrap();
+        assert!(get.check_arity(2));
+        assert!(!get.check_arity(3));
+
+        let xread = lookup_command("xread").unwrap();
+        assert_eq!(
+            xread.get_keys(&to_args("xread count 2 streams a b 0 0")),
+            vec!["a", "b"]
+        );
+        assert!(xread.flag_names().contains(&"movablekeys"));
+
+        assert!(lookup_command("nosuchcommand").is_none());
+    }
+}
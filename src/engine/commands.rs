@@ -1,56 +1,109 @@
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
-use super::handler::{
-    handle_config, handle_info, handle_keys, handle_psync, handle_replica, handle_set, handle_type,
-    handle_wait, handle_xadd, handle_xrange, handle_xread,
-};
-use super::{
-    CommandHandlerResponse, RespMessage, RespType, RESP_EMPTY, RESP_ERR, RESP_OK, RESP_PONG,
-};
+use super::command_table::command_flag::{LOADING, STALE};
+use super::command_table::{lookup_command, CommandSpec};
+use super::error::CommandError;
+use super::reply::RespReply;
+use super::{CommandHandlerResponse, RespMessage, RespType};
 
-use super::string_to_bulk_string;
+use crate::aof::writer::AOFWriter;
 use crate::store::engine::StoreEngine;
+use crate::store::master_engine::MasterEngine;
+use crate::store::slave_engine::SlaveEngine;
+use crate::store::stats::ServerStats;
+use crate::store::LinkStatus;
 use anyhow::Result;
 
-// command consts
-const COMMAND_GET: &str = "get";
-const COMMAND_SET: &str = "set";
-const COMMAND_PING: &str = "ping";
-const COMMAND_ECHO: &str = "echo";
-const COMMAND_INFO: &str = "info";
-const COMMAND_REPLCONF: &str = "replconf";
-const COMMAND_PSYNC: &str = "psync";
-const COMMAND_WAIT: &str = "wait";
-const COMMAND_CONFIG: &str = "config";
-const COMMAND_KEYS: &str = "keys";
-const COMMAND_TYPE: &str = "type";
-const COMMAND_XADD: &str = "xadd";
-const COMMAND_XRANGE: &str = "xrange";
-const COMMAND_XREAD: &str = "xread";
-
 // we support multiple responses to handle commands like psync
 pub fn command_handler(
     db: &Arc<StoreEngine>,
     cmd: Arc<RwLock<RespMessage>>,
 ) -> Result<CommandHandlerResponse> {
-    let mut resp_vec = Vec::new();
-    let ret = match cmd.read().unwrap().resp_type {
-        RespType::SimpleString => {
-            resp_vec.push(RESP_OK.to_string().as_bytes().to_vec());
-            Ok(CommandHandlerResponse::Basic(resp_vec))
-        }
-        RespType::Error => {
-            resp_vec.push(RESP_ERR.to_string().as_bytes().to_vec());
-            Ok(CommandHandlerResponse::Basic(resp_vec))
-        }
-        RespType::Integer => {
-            resp_vec.push(
-                format!(":{}\r\n", cmd.read().unwrap().int_data)
-                    .as_bytes()
-                    .to_vec(),
-            );
-            Ok(CommandHandlerResponse::Basic(resp_vec))
+    // the writes reach the aof and the replicas in the order they were applied
+    let is_write = cmd
+        .read()
+        .unwrap()
+        .vec_data
+        .first()
+        .is_some_and(|elem| lookup_command(&elem.str_data).is_some_and(|spec| spec.is_write()));
+    let _write = is_write.then(|| db.write_lock.lock().unwrap());
+    apply_command(db, cmd)
+}
+
+// command_handler for a caller which already holds write_lock
+pub fn apply_command(
+    db: &Arc<StoreEngine>,
+    cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    match dispatch(db, cmd)? {
+        CommandHandlerResponse::Propagate { message, cmds } => Ok(replicate(db, message, cmds)),
+        resps => Ok(resps),
+    }
+}
+
+// command of the table matching args, with the arity checked
+pub fn check_command(args: &[String]) -> Result<&'static CommandSpec> {
+    let Some(name) = args.first() else {
+        return Err(CommandError::unknown_command("", &[]).into());
+    };
+    let Some(spec) = lookup_command(name) else {
+        return Err(CommandError::unknown_command(name, &args[1..]).into());
+    };
+    if !spec.check_arity(args.len()) {
+        return Err(CommandError::WrongArity(spec.name.to_string()).into());
+    }
+    Ok(spec)
+}
+
+// commands of normal clients are refused while the dataset is loading and on a
+// replica cut from its master with replica-serve-stale-data off, unless flagged for it
+// writes are refused on a read only replica and on a master without enough good replicas
+// the master link doesn't go through this, it applies the commands directly
+pub fn check_allowed(db: &Arc<StoreEngine>, args: &[String]) -> Result<()> {
+    let Some(spec) = args.first().and_then(|name| lookup_command(name)) else {
+        return Ok(());
+    };
+    let stale = !db.is_master()
+        && !db.config.read().unwrap().replica_serve_stale_data
+        && db.get_master_link_info().0 != LinkStatus::Up;
+    let refused = if db.stats.loading.load(Ordering::Relaxed) && !spec.has_flag(LOADING) {
+        Some(CommandError::Loading)
+    } else if stale && !spec.has_flag(STALE) {
+        Some(CommandError::MasterDown)
+    } else if !spec.is_write() {
+        None
+    } else if !db.is_master() && db.config.read().unwrap().replica_read_only {
+        Some(CommandError::ReadOnly)
+    } else if !db.has_enough_good_replicas() {
+        Some(CommandError::NoReplicas)
+    } else {
+        None
+    };
+    match refused {
+        Some(e) => {
+            db.stats.record_rejected(spec.name);
+            Err(e.into())
         }
+        None => Ok(()),
+    }
+}
+
+// run a command, the writes come back as Propagate with what goes to the replicas
+// the aof is replayed through it while loading, the commands are applied without
+// being propagated or counted in the stats
+pub fn dispatch(
+    db: &Arc<StoreEngine>,
+    cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    let resp_type = cmd.read().unwrap().resp_type.clone();
+    match resp_type {
+        RespType::SimpleString => Ok(CommandHandlerResponse::Basic(RespReply::ok())),
+        RespType::Error => Ok(CommandHandlerResponse::Basic(RespReply::error("ERR"))),
+        RespType::Integer => Ok(CommandHandlerResponse::Basic(RespReply::Integer(
+            cmd.read().unwrap().int_data,
+        ))),
         RespType::BulkString => {
             match cmd
                 .read()
@@ -60,79 +113,194 @@ pub fn command_handler(
                 .to_lowercase()
                 .as_str()
             {
-                "ping" => {
-                    resp_vec.push(RESP_PONG.to_string().as_bytes().to_vec());
-                    Ok(CommandHandlerResponse::Basic(resp_vec))
-                }
-                _ => return Err(anyhow::anyhow!("Unknown command")),
+                "ping" => Ok(CommandHandlerResponse::Basic(RespReply::simple("PONG"))),
+                name => Err(CommandError::unknown_command(name, &[]).into()),
             }
         }
         RespType::Array => {
-            // TODO
-            match cmd.read().unwrap().vec_data[0].resp_type {
-                RespType::BulkString => {
-                    return match cmd.read().unwrap().vec_data[0]
-                        .str_data
-                        .to_lowercase()
-                        .as_str()
-                    {
-                        "" => {
-                            resp_vec.push(RESP_OK.to_string().as_bytes().to_vec());
-                            Ok(CommandHandlerResponse::Basic(resp_vec))
-                        }
-                        COMMAND_GET => {
-                            let key = cmd.read().unwrap().vec_data[1].str_data.clone();
-                            match db.get(&key) {
-                                Some(val) => {
-                                    resp_vec.push(string_to_bulk_string(val).as_bytes().to_vec());
-                                }
-                                None => {
-                                    resp_vec.push("$-1\r\n".to_string().as_bytes().to_vec());
-                                }
-                            }
-                            Ok(CommandHandlerResponse::Basic(resp_vec))
-                        }
-                        COMMAND_SET => handle_set(&db.clone(), cmd.clone()),
-                        COMMAND_PING => {
-                            resp_vec.push(RESP_PONG.to_string().as_bytes().to_vec());
-                            Ok(CommandHandlerResponse::Basic(resp_vec))
-                        }
-                        COMMAND_ECHO => {
-                            let mut ret = String::new();
-                            for i in 1..cmd.read().unwrap().vec_data.len() {
-                                ret.push_str(&string_to_bulk_string(
-                                    cmd.read().unwrap().vec_data[i].str_data.clone(),
-                                ));
-                            }
-                            resp_vec.push(ret.as_bytes().to_vec());
-                            Ok(CommandHandlerResponse::Basic(resp_vec))
-                        }
-                        COMMAND_WAIT => handle_wait(&db.clone(), cmd.clone()),
-                        COMMAND_INFO => handle_info(&db.clone(), cmd.clone()),
-                        // replconf always return OK
-                        COMMAND_REPLCONF => handle_replica(&db.clone(), cmd.clone()),
-                        // psync return from master node with fullresync and myid
-                        COMMAND_PSYNC => handle_psync(&db.clone(), cmd.clone()),
-                        COMMAND_CONFIG => handle_config(&db.clone(), cmd.clone()),
-                        COMMAND_KEYS => handle_keys(&db.clone(), cmd.clone()),
-                        COMMAND_TYPE => handle_type(&db.clone(), cmd.clone()),
-                        COMMAND_XADD => handle_xadd(&db.clone(), cmd.clone()),
-                        COMMAND_XRANGE => handle_xrange(&db.clone(), cmd.clone()),
-                        COMMAND_XREAD => handle_xread(&db.clone(), cmd.clone()),
-                        _ => {
-                            resp_vec.push(RESP_EMPTY.to_string().as_bytes().to_vec());
-                            Ok(CommandHandlerResponse::Basic(resp_vec))
-                        }
-                    };
+            match cmd.read().unwrap().vec_data.first() {
+                Some(elem) if elem.resp_type == RespType::BulkString => {}
+                _ => return Err(CommandError::Protocol("expected '$'".to_string()).into()),
+            }
+
+            let args = cmd.read().unwrap().args();
+            let loading = db.stats.loading.load(Ordering::Relaxed);
+            let spec = check_command(&args).inspect_err(|_| {
+                if let Some(spec) = args.first().and_then(|name| lookup_command(name)) {
+                    if !loading {
+                        db.stats.record_rejected(spec.name);
+                    }
+                }
+            })?;
+
+            let start = Instant::now();
+            let resps = (spec.handler)(db, cmd);
+            let failed = match &resps {
+                Ok(CommandHandlerResponse::Basic(message)) => message.is_error(),
+                Ok(_) => false,
+                Err(_) => true,
+            };
+            if !loading {
+                db.stats.record_call(spec.name, start.elapsed(), failed);
+            }
+            let resps = resps?;
+            if !spec.is_write() {
+                return Ok(resps);
+            }
+
+            // write commands are sent to the replicas as they were received
+            // unless the handler rewrote them
+            let resps = match resps {
+                CommandHandlerResponse::Basic(message) if !message.is_error() => {
+                    CommandHandlerResponse::Propagate {
+                        message,
+                        cmds: vec![args],
+                    }
                 }
-                _ => {
-                    resp_vec.push(RESP_EMPTY.to_string().as_bytes().to_vec());
-                    Ok(CommandHandlerResponse::Basic(resp_vec))
+                resps => resps,
+            };
+            // a write which changed nothing isn't propagated and isn't worth a save
+            if let CommandHandlerResponse::Propagate { cmds, .. } = &resps {
+                if !cmds.is_empty() {
+                    ServerStats::incr(&db.stats.dirty, 1);
                 }
             }
+            Ok(resps)
         }
-        _ => return Err(anyhow::anyhow!("Unknown command")),
-    };
+        _ => Err(CommandError::Protocol("expected '*'".to_string()).into()),
+    }
+}
+
+// feed the commands to the aof, the backlog and the replicas right away
+// so they keep the order in which the writes were applied
+fn replicate(
+    db: &Arc<StoreEngine>,
+    message: RespReply,
+    cmds: Vec<Vec<String>>,
+) -> CommandHandlerResponse {
+    if cmds.is_empty() {
+        return CommandHandlerResponse::Basic(message);
+    }
+
+    db.feed_aof(&cmds);
+    let offset = db.sync_command(cmds);
+    CommandHandlerResponse::Write { message, offset }
+}
+
+// EXEC runs the queued commands back to back
+// their writes reach the replicas as a single MULTI/EXEC block
+pub fn exec_transaction(
+    db: &Arc<StoreEngine>,
+    queued: Vec<Arc<RwLock<RespMessage>>>,
+) -> CommandHandlerResponse {
+    let _write = db.write_lock.lock().unwrap();
+    apply_transaction(db, queued)
+}
+
+// exec_transaction for a caller which already holds write_lock
+pub fn apply_transaction(
+    db: &Arc<StoreEngine>,
+    queued: Vec<Arc<RwLock<RespMessage>>>,
+) -> CommandHandlerResponse {
+    let mut replies = Vec::with_capacity(queued.len());
+    let mut cmds = vec![vec!["MULTI".to_string()]];
+    for cmd in queued {
+        let reply = match dispatch(db, cmd) {
+            Ok(CommandHandlerResponse::Propagate { message, cmds: c }) => {
+                cmds.extend(c);
+                message
+            }
+            Ok(CommandHandlerResponse::Basic(message))
+            | Ok(CommandHandlerResponse::GetAck(message))
+            | Ok(CommandHandlerResponse::Hello { message, .. }) => message,
+            Ok(CommandHandlerResponse::Wait { _message, .. }) => _message,
+            // nothing blocks inside a transaction
+            Ok(_) => RespReply::Null,
+            Err(e) => RespReply::from(&e),
+        };
+        replies.push(reply);
+    }
+
+    let message = RespReply::Array(replies);
+    if cmds.len() == 1 {
+        return CommandHandlerResponse::Basic(message);
+    }
+    cmds.push(vec!["EXEC".to_string()]);
+    replicate(db, message, cmds)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::config::ConfigOps;
+    use crate::store::HandshakeState;
+
+    fn args(cmd: &str) -> Vec<String> {
+        cmd.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_check_allowed() {
+        let db = Arc::new(StoreEngine::new());
+        db.stats.loading.store(true, Ordering::Relaxed);
+        let err = check_allowed(&db, &args("GET k")).unwrap_err();
+        assert_eq!(
+            err.downcast::<CommandError>().unwrap(),
+            CommandError::Loading
+        );
+        assert!(check_allowed(&db, &args("INFO")).is_ok());
+        db.stats.loading.store(false, Ordering::Relaxed);
+
+        // the link of a new replica is down until the sync is done
+        db.set_replica("127.0.0.1:6379".to_string()).unwrap();
+        assert!(check_allowed(&db, &args("GET k")).is_ok());
+        db.config_set("replica-serve-stale-data", "no").unwrap();
+        let err = check_allowed(&db, &args("GET k")).unwrap_err();
+        assert_eq!(
+            err.downcast::<CommandError>().unwrap(),
+            CommandError::MasterDown
+        );
+        assert!(check_allowed(&db, &args("ROLE")).is_ok());
+    }
+
+    #[test]
+    fn test_read_only_replica() {
+        let db = Arc::new(StoreEngine::new());
+        db.set_replica("127.0.0.1:6379".to_string()).unwrap();
+
+        let err = check_allowed(&db, &args("SET k v")).unwrap_err();
+        assert_eq!(
+            err.downcast::<CommandError>().unwrap(),
+            CommandError::ReadOnly
+        );
+        assert!(check_allowed(&db, &args("GET k")).is_ok());
+        assert!(check_allowed(&db, &args("TTL k")).is_ok());
+
+        db.config_set("replica-read-only", "no").unwrap();
+        assert!(check_allowed(&db, &args("SET k v")).is_ok());
+    }
+
+    #[test]
+    fn test_min_replicas_refusal() {
+        let db = Arc::new(StoreEngine::new());
+        db.config_set("min-replicas-to-write", "1").unwrap();
+        let err = check_allowed(&db, &args("SET k v")).unwrap_err();
+        assert_eq!(
+            err.downcast::<CommandError>().unwrap(),
+            CommandError::NoReplicas
+        );
+        assert!(check_allowed(&db, &args("GET k")).is_ok());
+        let stats = db.stats.command_stats();
+        assert_eq!(stats[0].0, "set");
+        assert_eq!(stats[0].1.rejected_calls, 1);
+
+        // a synced replica which acked within min-replicas-max-lag lets the writes through
+        let host = "127.0.0.1:6380".to_string();
+        db.set_slave_node(host.clone(), "6380".to_string(), HandshakeState::Psync);
+        db.set_slave_offset(host, 0);
+        assert!(check_allowed(&db, &args("SET k v")).is_ok());
 
-    ret
+        db.config_set("min-replicas-to-write", "2").unwrap();
+        assert!(check_allowed(&db, &args("SET k v")).is_err());
+    }
 }
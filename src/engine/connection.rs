@@ -1,24 +1,62 @@
-use super::commands::command_handler;
-use super::{array_to_simple_resp_array, RespMessage, RespParsingState, RespType, RESP_NULL};
+use super::command_table::command_flag;
+use super::commands::{check_allowed, check_command, command_handler, exec_transaction};
+use super::error::CommandError;
+use super::parser::split_inline_args;
+use super::reply::{xread_reply, RespProtocol, RespReply};
+use super::{rdb_to_psync_payload, PsyncPayload, RespMessage, RespParsingState, RespType};
 use crate::engine::CommandHandlerResponse;
 use crate::store::engine::StoreEngine;
 use crate::store::master_engine::MasterEngine;
-use crate::store::replicator::ReplicatorHandle;
-use crate::store::stream_engine::{StreamEngine, StreamRange};
+use crate::store::stats::ServerStats;
+use crate::store::stream_engine::StreamEngine;
 use std::collections::VecDeque;
 use std::sync::{Arc, RwLock};
 
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::net::tcp::OwnedWriteHalf;
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 
+// commands queued between MULTI and EXEC
+#[derive(Default)]
+struct Transaction {
+    queued: Vec<Arc<RwLock<RespMessage>>>,
+    // a command failed to queue, EXEC is refused
+    aborted: bool,
+}
+
+// state of a client kept across its commands
+#[derive(Default)]
+struct ClientState {
+    addr: String,
+    // every connection starts with RESP2 until HELLO 3
+    protocol: RespProtocol,
+    multi: Option<Transaction>,
+    // master offset after the last write of the client, what WAIT waits for
+    write_offset: u64,
+}
+
+// whatever way the connection ends, a panic included, it stops counting and its replica goes
+struct ConnectionGuard<'a> {
+    db: &'a Arc<StoreEngine>,
+    addr: String,
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        ServerStats::decr(&self.db.stats.connected_clients);
+        self.db.remove_replica(&self.addr);
+    }
+}
+
 pub async fn handle_connection(db: &Arc<StoreEngine>, stream: TcpStream, addr: SocketAddr) {
     let mut cmd = String::new();
     let mut buf = [0; 512];
     let mut maybe_split = false;
+    let _ = stream.set_nodelay(true);
 
     let addr = addr.to_string();
 
@@ -32,22 +70,69 @@ pub async fn handle_connection(db: &Arc<StoreEngine>, stream: TcpStream, addr: S
     let (mut rx, tx) = stream.into_split();
     let arc_tx = Arc::new(Mutex::new(tx));
 
-    let actor = ReplicatorHandle::new(db.clone());
+    ServerStats::incr(&db.stats.connected_clients, 1);
+    ServerStats::incr(&db.stats.total_connections_received, 1);
+    let _guard = ConnectionGuard {
+        db,
+        addr: addr.clone(),
+    };
 
-    loop {
-        let _ = rx.readable();
+    let mut client = ClientState {
+        addr: addr.clone(),
+        ..Default::default()
+    };
+
+    'conn: loop {
         let chrs = rx.read(&mut buf).await;
         match chrs {
             Ok(n) => {
                 if n == 0 {
                     break;
                 } else {
+                    ServerStats::incr(&db.stats.total_net_input_bytes, n as u64);
                     for u in buf.iter().take(n) {
                         let c = *u as char;
                         if c == '\r' {
                             maybe_split = true;
                             continue;
                         } else if c == '\n' {
+                            // inline commands may end with a bare \n (e.g. netcat)
+                            if is_inline_command(&cmd_stack, &cmd) {
+                                match split_inline_args(&cmd) {
+                                    Ok(args) if args.is_empty() => {}
+                                    Ok(args) => {
+                                        let resp = Arc::new(RwLock::new(RespMessage::from_args(
+                                            addr.clone(),
+                                            args,
+                                        )));
+                                        if execute_command(db, resp, &arc_tx, &mut client)
+                                            .await
+                                            .is_err()
+                                        {
+                                            break 'conn;
+                                        }
+                                    }
+                                    Err(e) => {
+                                        let err = CommandError::Protocol(e.to_string());
+                                        if write_reply(
+                                            db,
+                                            &arc_tx,
+                                            &RespReply::error(err.to_string()),
+                                            client.protocol,
+                                        )
+                                        .await
+                                        .is_err()
+                                        {
+                                            break 'conn;
+                                        }
+                                    }
+                                }
+
+                                cmd.clear();
+                                maybe_split = false;
+                                continue;
+                            }
+
                             if !maybe_split {
                                 cmd.push(c);
                                 continue;
@@ -57,7 +142,18 @@ pub async fn handle_connection(db: &Arc<StoreEngine>, stream: TcpStream, addr: S
                             if let Some(resp) = cmd_stack.pop_back() {
                                 // main function to parse the command
                                 // the result is in RespMessage
-                                resp.write().unwrap().parse(&cmd);
+                                let parsed = resp.write().unwrap().parse(&cmd);
+                                if let Err(e) = parsed {
+                                    // the rest of the input can't be framed, close as redis does
+                                    let _ = write_reply(
+                                        db,
+                                        &arc_tx,
+                                        &RespReply::error(e.to_string()),
+                                        client.protocol,
+                                    )
+                                    .await;
+                                    break 'conn;
+                                }
 
                                 if resp.read().unwrap().state == RespParsingState::End {
                                     // if the parent is an array, we need to check if it's done
@@ -75,28 +171,26 @@ pub async fn handle_connection(db: &Arc<StoreEngine>, stream: TcpStream, addr: S
                                             // move the array type out of the stack
                                             parent.write().unwrap().state = RespParsingState::End;
                                             if cmd_stack.is_empty() {
-                                                let resp = command_handler(db, parent.clone());
-                                                if let Ok(resps) = resp {
-                                                    command_handler_callback(
-                                                        db.clone(),
-                                                        resps,
-                                                        &arc_tx.clone(),
-                                                        &actor,
-                                                    )
-                                                    .await;
+                                                let written = execute_command(
+                                                    db,
+                                                    parent.clone(),
+                                                    &arc_tx,
+                                                    &mut client,
+                                                )
+                                                .await;
+                                                if written.is_err() {
+                                                    break 'conn;
                                                 }
                                             }
                                         } else {
                                             cmd_stack.push_back(parent);
                                         }
-                                    } else if let Ok(resps) = command_handler(db, resp) {
-                                        command_handler_callback(
-                                            db.clone(),
-                                            resps,
-                                            &arc_tx.clone(),
-                                            &actor,
-                                        )
-                                        .await;
+                                    } else {
+                                        let written =
+                                            execute_command(db, resp, &arc_tx, &mut client).await;
+                                        if written.is_err() {
+                                            break 'conn;
+                                        }
                                     }
 
                                     // next cmd is a new RespMessage
@@ -121,91 +215,234 @@ pub async fn handle_connection(db: &Arc<StoreEngine>, stream: TcpStream, addr: S
                     }
                 }
             }
-            Err(_) => {
-                continue;
-            }
+            Err(_) => break,
         }
     }
 }
 
+// a line which doesn't start with a RESP type prefix outside of any pending command
+fn is_inline_command(cmd_stack: &VecDeque<Arc<RwLock<RespMessage>>>, line: &str) -> bool {
+    if cmd_stack.len() != 1 || !cmd_stack[0].read().unwrap().is_empty() {
+        return false;
+    }
+
+    !line.starts_with(['*', '$', '+', '-', ':'])
+}
+
+// every failure of the handlers is replied as a redis error
+async fn execute_command(
+    db: &Arc<StoreEngine>,
+    cmd: Arc<RwLock<RespMessage>>,
+    stream: &Arc<Mutex<OwnedWriteHalf>>,
+    client: &mut ClientState,
+) -> std::io::Result<()> {
+    if client.multi.is_some() {
+        return queue_command(db, cmd, stream, client).await;
+    }
+
+    let args = cmd.read().unwrap().args();
+    let resps = match check_allowed(db, &args).and_then(|_| command_handler(db, cmd)) {
+        Ok(resps) => resps,
+        Err(e) => CommandHandlerResponse::Basic(RespReply::from(&e)),
+    };
+    command_handler_callback(db.clone(), resps, stream, client).await
+}
+
+// inside MULTI every command but EXEC and DISCARD is only checked and queued
+async fn queue_command(
+    db: &Arc<StoreEngine>,
+    cmd: Arc<RwLock<RespMessage>>,
+    stream: &Arc<Mutex<OwnedWriteHalf>>,
+    client: &mut ClientState,
+) -> std::io::Result<()> {
+    let args = cmd.read().unwrap().args();
+    let name = args.first().map(|a| a.to_lowercase()).unwrap_or_default();
+    let Some(multi) = client.multi.as_mut() else {
+        return Ok(());
+    };
+
+    let reply = match name.as_str() {
+        "exec" => {
+            let multi = client.multi.take().unwrap_or_default();
+            let start = Instant::now();
+            let resps = if multi.aborted {
+                CommandHandlerResponse::Basic(RespReply::error(
+                    "EXECABORT Transaction discarded because of previous errors.",
+                ))
+            } else {
+                exec_transaction(db, multi.queued)
+            };
+            db.stats.record_call("exec", start.elapsed(), multi.aborted);
+            return command_handler_callback(db.clone(), resps, stream, client).await;
+        }
+        "discard" => {
+            db.stats.record_call("discard", Duration::ZERO, false);
+            client.multi = None;
+            RespReply::ok()
+        }
+        "multi" => RespReply::error("ERR MULTI calls can not be nested"),
+        _ => match check_command(&args).and_then(|spec| check_allowed(db, &args).map(|_| spec)) {
+            Ok(spec) if spec.has_flag(command_flag::NO_MULTI) => {
+                multi.aborted = true;
+                RespReply::error("ERR Command not allowed inside a transaction")
+            }
+            Ok(_) => {
+                multi.queued.push(cmd);
+                RespReply::simple("QUEUED")
+            }
+            Err(e) => {
+                multi.aborted = true;
+                RespReply::from(&e)
+            }
+        },
+    };
+    write_reply(db, stream, &reply, client.protocol).await
+}
+
 async fn command_handler_callback(
     db: Arc<StoreEngine>,
     resps: CommandHandlerResponse,
     stream: &Arc<Mutex<OwnedWriteHalf>>,
-    actor: &ReplicatorHandle,
-) {
+    client: &mut ClientState,
+) -> std::io::Result<()> {
+    let protocol = &mut client.protocol;
     match resps {
-        CommandHandlerResponse::Basic(resps) => {
-            for resp in resps {
-                stream.lock().await.write_all(&resp).await.unwrap();
-            }
-        }
-        CommandHandlerResponse::Set { message, offset } => {
-            db.add_master_offset(offset);
-
-            for resp in message {
-                stream.lock().await.write_all(&resp).await.unwrap();
-            }
+        CommandHandlerResponse::Basic(message)
+        | CommandHandlerResponse::Propagate { message, .. } => {
+            write_reply(&db, stream, &message, *protocol).await?;
         }
-        CommandHandlerResponse::Psync { message, host } => {
-            // we need to store stream to replicas
-            db.set_replicas(host, stream.clone()).await;
+        CommandHandlerResponse::NoReply => {}
+        CommandHandlerResponse::Write { message, offset } => {
+            client.write_offset = offset;
 
-            for resp in message {
-                stream.lock().await.write_all(&resp).await.unwrap();
-            }
+            write_reply(&db, stream, &message, *protocol).await?;
         }
-        CommandHandlerResponse::Replica {
+        CommandHandlerResponse::Psync {
             message,
-            cmd,
-            offset,
+            payload,
+            mut receiver,
         } => {
-            db.add_master_offset(offset);
-            // println!(
-            //     "add_master_offset master offset: {}",
-            //     db.get_last_set_offset()
-            // );
-            let _ = actor.set_op(cmd).await;
-            for resp in message {
-                stream.lock().await.write_all(&resp).await.unwrap();
-            }
+            // the rdb or backlog goes first, then the writes buffered meanwhile in order
+            let stream = stream.clone();
+            let fullresync = message.encode(*protocol);
+            let host = client.addr.clone();
+            tokio::spawn(async move {
+                let sent = async {
+                    stream.lock().await.write_all(&fullresync).await?;
+                    let payload = match payload {
+                        PsyncPayload::Backlog(backlog) => backlog,
+                        PsyncPayload::Rdb(snapshot) => tokio::task::spawn_blocking(move || {
+                            rdb_to_psync_payload(&snapshot.encode(false))
+                        })
+                        .await
+                        .map_err(std::io::Error::other)?,
+                    };
+                    stream.lock().await.write_all(&payload).await?;
+                    db.set_replica_online(&host);
+                    while let Some(payload) = receiver.recv().await {
+                        stream.lock().await.write_all(&payload).await?;
+                    }
+                    // the replica was dropped, closing our side makes it reconnect
+                    stream.lock().await.shutdown().await
+                };
+                if let Err(e) = sent.await {
+                    println!("err: replica {}: {}", host, e);
+                    db.remove_replica(&host);
+                }
+            });
         }
-        CommandHandlerResponse::GetAck(resps) => {
-            let _ = actor.getack_op().await;
-            for resp in resps {
-                stream.lock().await.write_all(&resp).await.unwrap();
-            }
+        CommandHandlerResponse::GetAck(message) => {
+            db.send_ack_to_slave();
+            write_reply(&db, stream, &message, *protocol).await?;
         }
         CommandHandlerResponse::Wait {
             _message,
             wait_count,
             wait_time,
         } => {
-            let replicator_follow_count = actor.wait_op(wait_count, wait_time).await;
-            let ret = format!(":{}\r\n", replicator_follow_count);
-            stream.lock().await.write_all(ret.as_bytes()).await.unwrap();
+            ServerStats::incr(&db.stats.blocked_clients, 1);
+            let replicator_follow_count = db
+                .wait_replica(wait_count, wait_time, client.write_offset)
+                .await;
+            ServerStats::decr(&db.stats.blocked_clients);
+            let ret = RespReply::Integer(replicator_follow_count as i64);
+            write_reply(&db, stream, &ret, *protocol).await?;
         }
         CommandHandlerResponse::StreamBlock {
             ms,
+            count,
             key_vec,
             stream_id_vec,
         } => {
-            tokio::time::sleep(tokio::time::Duration::from_millis(ms)).await;
-            let xread_arr = db.get_xread_streams(key_vec, stream_id_vec).unwrap();
+            ServerStats::incr(&db.stats.blocked_clients, 1);
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            ServerStats::decr(&db.stats.blocked_clients);
+            let xread_arr = db
+                .get_xread_streams(key_vec, stream_id_vec, count)
+                .unwrap_or_default();
             if xread_arr.is_empty() {
-                stream
-                    .lock()
-                    .await
-                    .write_all(RESP_NULL.as_bytes())
-                    .await
-                    .unwrap();
-                return;
+                return write_reply(&db, stream, &RespReply::Null, *protocol).await;
             }
-            let resp_vec = vec![array_to_simple_resp_array(xread_arr).as_bytes().to_vec()];
 
-            for resp in resp_vec {
-                stream.lock().await.write_all(&resp).await.unwrap();
-            }
+            write_reply(&db, stream, &xread_reply(&xread_arr), *protocol).await?;
+        }
+        CommandHandlerResponse::Multi(message) => {
+            client.multi = Some(Transaction::default());
+            write_reply(&db, stream, &message, client.protocol).await?;
         }
+        CommandHandlerResponse::Hello {
+            message,
+            protocol: new_protocol,
+        } => {
+            *protocol = new_protocol;
+            write_reply(&db, stream, &message, *protocol).await?;
+        }
+    }
+    Ok(())
+}
+
+// replies are encoded only here with the protocol of the connection
+async fn write_reply(
+    db: &Arc<StoreEngine>,
+    stream: &Arc<Mutex<OwnedWriteHalf>>,
+    message: &RespReply,
+    protocol: RespProtocol,
+) -> std::io::Result<()> {
+    let encoded = message.encode(protocol);
+    ServerStats::incr(&db.stats.total_net_output_bytes, encoded.len() as u64);
+    if message.is_error() {
+        ServerStats::incr(&db.stats.total_error_replies, 1);
+    }
+    stream.lock().await.write_all(&encoded).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_connection_closed_on_protocol_error() {
+        let db = Arc::new(StoreEngine::new());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = TcpStream::connect(server_addr).await.unwrap();
+        let (socket, addr) = listener.accept().await.unwrap();
+        let server = {
+            let db = db.clone();
+            tokio::spawn(async move { handle_connection(&db, socket, addr).await })
+        };
+
+        client.write_all(b"PING\r\n*x\r\nPING\r\n").await.unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(
+            String::from_utf8(reply).unwrap(),
+            "+PONG\r\n-ERR Protocol error: invalid multibulk length\r\n"
+        );
+
+        server.await.unwrap();
+        assert_eq!(ServerStats::get(&db.stats.connected_clients), 0);
     }
 }
@@ -0,0 +1,80 @@
+use thiserror::Error;
+
+// errors which are sent back to the client as they are
+// anything else coming out of a handler is prefixed with ERR
+#[derive(Error, Debug, PartialEq)]
+pub enum CommandError {
+    #[error("ERR unknown command '{0}', with args beginning with: {1}")]
+    UnknownCommand(String, String),
+    #[error("ERR unknown subcommand '{0}'. Try {1} HELP.")]
+    UnknownSubcommand(String, String),
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongArity(String),
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+    #[error("ERR invalid expire time in '{0}' command")]
+    InvalidExpire(String),
+    #[error("ERR Unsupported option {0}")]
+    UnsupportedOption(String),
+    #[error("ERR {0} options at the same time are not compatible")]
+    IncompatibleOptions(String),
+    #[error("ERR timeout is not an integer or out of range")]
+    InvalidTimeout,
+    #[error("ERR timeout is negative")]
+    NegativeTimeout,
+    #[error("ERR Invalid stream ID specified as stream command argument")]
+    InvalidStreamID,
+    #[error("ERR Protocol error: {0}")]
+    Protocol(String),
+    #[error("READONLY You can't write against a read only replica.")]
+    ReadOnly,
+    #[error("NOREPLICAS Not enough good replicas to write.")]
+    NoReplicas,
+    #[error("NOMASTERLINK Can't SYNC while not connected with my master")]
+    NoMasterLink,
+    #[error("LOADING Redis is loading the dataset in memory")]
+    Loading,
+    #[error("MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'.")]
+    MasterDown,
+    #[error("ERR Unknown option or number of arguments for CONFIG SET - '{0}'")]
+    UnknownConfig(String),
+    #[error("ERR CONFIG SET failed (possibly related to argument '{0}') - {1}")]
+    InvalidConfig(String, String),
+}
+
+impl CommandError {
+    // the argument list is quoted and cut the same way redis does
+    pub fn unknown_command(name: &str, args: &[String]) -> Self {
+        let mut args_str = String::new();
+        for arg in args {
+            if args_str.len() >= 128 {
+                break;
+            }
+            let arg: String = arg.chars().take(128 - args_str.len()).collect();
+            args_str.push_str(&format!("'{}' ", arg));
+        }
+        CommandError::UnknownCommand(name.to_string(), args_str)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unknown_command_message() {
+        let err = CommandError::unknown_command("foo", &["a".to_string(), "b".to_string()]);
+        assert_eq!(
+            err.to_string(),
+            "ERR unknown command 'foo', with args beginning with: 'a' 'b' "
+        );
+
+        let long_arg = "x".repeat(200);
+        let err = CommandError::unknown_command("foo", &[long_arg, "b".to_string()]);
+        assert!(!err.to_string().contains("'b'"));
+    }
+}
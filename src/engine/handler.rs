@@ -1,153 +1,361 @@
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
-use super::{
-    array_to_resp_array, array_to_resp_array_for_xrange, array_to_simple_resp_array,
-    count_resp_command_type_offset, string_error_simple_string, string_to_bulk_string,
-    string_to_bulk_string_for_psync, string_to_simple_string, xrange_to_read_wrap,
-    CommandHandlerResponse, RespCommandType, RespMessage, EMPTY_RDB, MYID, RESP_ERR, RESP_OK,
-};
-
-use crate::rdb::config::RDBConfigOps;
+use super::command_table::{lookup_command, COMMAND_TABLE};
+use super::error::CommandError;
+use super::info::{info_section, is_default_section, INFO_SECTIONS};
+use super::reply::{stream_range_reply, xread_reply, RespProtocol, RespReply};
+use super::{CommandHandlerResponse, PsyncPayload, RespMessage, REDIS_VERSION};
+
+use crate::aof::writer::AOFWriter;
 use crate::rdb::value_type_string;
-use crate::store::engine::{StoreEngine, StreamID, StreamIDState};
+use crate::rdb::writer::RDBWriter;
+use crate::store::config::ConfigOps;
+use crate::store::engine::{now_ms, StoreEngine, StreamID, StreamIDState};
 use crate::store::master_engine::MasterEngine;
+use crate::store::slave_engine::SlaveEngine;
+use crate::store::stats::ServerStats;
 use crate::store::stream_engine::StreamEngine;
-use crate::store::{HandshakeState, ReplicaType};
+use crate::store::{HandshakeState, LinkStatus, ReplicaType};
 
 use anyhow::Result;
+use tokio::sync::mpsc::unbounded_channel;
 
-pub fn handle_info(
+pub fn handle_get(
     db: &Arc<StoreEngine>,
     cmd: Arc<RwLock<RespMessage>>,
 ) -> Result<CommandHandlerResponse> {
-    let mut lookup_keys: Vec<String> = Vec::new();
-    for i in 1..cmd.read().unwrap().vec_data.len() {
-        lookup_keys.push(cmd.read().unwrap().vec_data[i].str_data.clone());
+    let key = cmd.read().unwrap().vec_data[1].str_data.clone();
+    db.lookup_read(&key);
+    match db.get(&key) {
+        Some(val) => Ok(CommandHandlerResponse::Basic(RespReply::bulk(val))),
+        None if db.get_stream_key(&key).is_some() => Err(CommandError::WrongType.into()),
+        None => Ok(CommandHandlerResponse::Basic(RespReply::Null)),
     }
+}
 
-    let mut resp_vec = Vec::new();
-    let mut ret = String::new();
+pub fn handle_ping(
+    _db: &Arc<StoreEngine>,
+    cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    let args = cmd.read().unwrap().args();
+    match args.len() {
+        1 => Ok(CommandHandlerResponse::Basic(RespReply::simple("PONG"))),
+        2 => Ok(CommandHandlerResponse::Basic(RespReply::bulk(
+            args[1].clone(),
+        ))),
+        _ => Err(CommandError::WrongArity("ping".to_string()).into()),
+    }
+}
 
-    if lookup_keys.is_empty() {
-        let db_info = "db_size: 0".to_string();
-        ret.push_str(&string_to_bulk_string(db_info));
-    } else {
-        let mut key_iter = lookup_keys.iter();
-        let mut idx = 0;
-        while let Some(k) = key_iter.next() {
-            match k.to_lowercase().as_str() {
-                "replication" => {
-                    if idx == 0 {
-                        // generate role info
-                        match db.get_replica() {
-                            ReplicaType::Master => {
-                                let mut master_info = String::from("role:master\r\n");
-                                let master_repl_id = format!("master_replid:{}\r\n", MYID);
-
-                                // generate master_repl_id, master_repl_offset
-                                master_info = master_info + &master_repl_id;
-                                let master_repl_offset = "master_repl_offset:0".to_string();
-                                master_info = master_info + &master_repl_offset;
-
-                                ret.push_str(&string_to_bulk_string(master_info));
-                            }
-                            ReplicaType::Slave(_) => {
-                                ret.push_str(&string_to_bulk_string("role:slave".to_string()));
-                            }
-                        }
-                    }
-                }
-                _ => {}
-            }
-            idx += 1;
+pub fn handle_echo(
+    _db: &Arc<StoreEngine>,
+    cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    let msg = cmd.read().unwrap().vec_data[1].str_data.clone();
+    Ok(CommandHandlerResponse::Basic(RespReply::bulk(msg)))
+}
+
+// INFO [section [section ...]], the sections always come in the same order
+pub fn handle_info(
+    db: &Arc<StoreEngine>,
+    cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    let args = cmd.read().unwrap().args();
+    let requested: Vec<String> = args[1..].iter().map(|a| a.to_lowercase()).collect();
+
+    // no argument is the same as default
+    let wanted = |name: &str| {
+        if requested.is_empty() {
+            return is_default_section(name);
         }
-    }
+        requested.iter().any(|r| match r.as_str() {
+            "all" | "everything" => true,
+            "default" => is_default_section(name),
+            r => r == name,
+        })
+    };
 
-    resp_vec.push(ret.as_bytes().to_vec());
-    Ok(CommandHandlerResponse::Basic(resp_vec))
+    let sections: Vec<String> = INFO_SECTIONS
+        .iter()
+        .filter(|name| wanted(name))
+        .filter_map(|name| info_section(db, name))
+        .collect();
+
+    // all sections share a single bulk string, separated by an empty line
+    Ok(CommandHandlerResponse::Basic(RespReply::bulk(
+        sections.join("\r\n"),
+    )))
 }
 
 pub fn handle_set(
     db: &Arc<StoreEngine>,
     cmd: Arc<RwLock<RespMessage>>,
 ) -> Result<CommandHandlerResponse> {
-    let mut resp_vec = Vec::new();
+    let args = cmd.read().unwrap().args();
+    let key = args[1].clone();
+    let val = args[2].clone();
+
+    // SET key value [NX | XX] [GET] [EX | PX | EXAT | PXAT | KEEPTTL]
+    let mut nx = false;
+    let mut xx = false;
+    let mut get = false;
+    let mut keep_ttl = false;
+    // absolute unix time in ms
+    let mut expire_at: Option<u128> = None;
+
+    let mut idx = 3;
+    while idx < args.len() {
+        let opt = args[idx].to_lowercase();
+        match opt.as_str() {
+            "nx" if !xx => nx = true,
+            "xx" if !nx => xx = true,
+            "get" => get = true,
+            "keepttl" if expire_at.is_none() => keep_ttl = true,
+            "ex" | "px" | "exat" | "pxat" if expire_at.is_none() && !keep_ttl => {
+                let Some(v) = args.get(idx + 1) else {
+                    return Err(CommandError::Syntax.into());
+                };
+                let v = v.parse::<i64>().map_err(|_| CommandError::NotInteger)?;
+                if v <= 0 {
+                    return Err(CommandError::InvalidExpire("set".to_string()).into());
+                }
 
-    // master node to memorize the offset from set command
-    let offset;
+                // an absolute time past i64 ms can't be propagated as PXAT
+                let now = now_ms() as i64;
+                let at = match opt.as_str() {
+                    "ex" => v.checked_mul(1000).and_then(|ms| ms.checked_add(now)),
+                    "px" => v.checked_add(now),
+                    "exat" => v.checked_mul(1000),
+                    _ => Some(v),
+                }
+                .ok_or_else(|| CommandError::InvalidExpire("set".to_string()))?;
+                expire_at = Some(at as u128);
+                idx += 1;
+            }
+            _ => return Err(CommandError::Syntax.into()),
+        }
+        idx += 1;
+    }
 
-    let key = cmd.read().unwrap().vec_data[1].str_data.clone();
+    let old_val = db.get(&key);
+    let is_stream = db.get_stream_key(&key).is_some();
+    if get && is_stream {
+        return Err(CommandError::WrongType.into());
+    }
+
+    let ret = match (get, old_val.clone()) {
+        (true, Some(v)) => RespReply::bulk(v),
+        (true, None) => RespReply::Null,
+        _ => RespReply::ok(),
+    };
 
-    // no value included
-    if cmd.read().unwrap().vec_data.len() < 3 {
-        resp_vec.push(RESP_ERR.to_string().as_bytes().to_vec());
+    // NX/XX condition not met, nothing is written
+    let exists = old_val.is_some() || is_stream;
+    if (nx && exists) || (xx && !exists) {
+        return Ok(CommandHandlerResponse::Propagate {
+            message: if get { ret } else { RespReply::Null },
+            cmds: Vec::new(),
+        });
     }
 
-    let val = cmd.read().unwrap().vec_data[2].str_data.clone();
-    let cmd_len = cmd.read().unwrap().vec_data.len();
+    // SET overwrites a key of any type
+    if is_stream {
+        db.del(&key);
+    }
 
-    let mut repl_command = format!("SET {} {}", key.clone(), val.clone());
+    // relative expire times are sent as absolute ones so the replicas converge
+    let mut repl_args = vec!["SET".to_string(), key.clone(), val.clone()];
+    match expire_at {
+        Some(at) => {
+            db.set_with_expire_exact(key, val, at);
+            repl_args.push("PXAT".to_string());
+            repl_args.push(at.to_string());
+        }
+        None if keep_ttl => {
+            db.set_keep_ttl(key, val);
+            repl_args.push("KEEPTTL".to_string());
+        }
+        None => db.set(key, val),
+    }
 
-    if cmd_len == 5 && cmd.read().unwrap().vec_data[3].str_data.to_lowercase() == "px" {
-        let ttl = cmd.read().unwrap().vec_data[4]
-            .str_data
-            .parse::<u128>()
-            .unwrap();
-        db.set_with_expire(key.clone(), val.clone(), ttl);
-        repl_command.push_str(format!(" {}", ttl.clone()).as_str());
-
-        offset = count_resp_command_type_offset(RespCommandType::SetPx(
-            key.clone(),
-            val.clone(),
-            ttl.try_into().unwrap(),
-        ));
-    } else {
-        db.set(key.clone(), val.clone());
-        offset = count_resp_command_type_offset(RespCommandType::Set(key.clone(), val.clone()));
+    Ok(CommandHandlerResponse::Propagate {
+        message: ret,
+        cmds: vec![repl_args],
+    })
+}
+
+// DEL key [key ...]
+pub(crate) fn handle_del(
+    db: &Arc<StoreEngine>,
+    cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    let args = cmd.read().unwrap().args();
+    let removed = args[1..].iter().filter(|k| db.del(k)).count();
+
+    Ok(CommandHandlerResponse::Propagate {
+        message: RespReply::Integer(removed as i64),
+        cmds: if removed > 0 { vec![args] } else { Vec::new() },
+    })
+}
+
+// EXPIRE/PEXPIRE/EXPIREAT/PEXPIREAT key time [NX | XX | GT | LT]
+pub(crate) fn handle_expire(
+    db: &Arc<StoreEngine>,
+    cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    let args = cmd.read().unwrap().args();
+    let name = args[0].to_lowercase();
+    let key = args[1].clone();
+
+    let t = args[2]
+        .parse::<i64>()
+        .map_err(|_| CommandError::NotInteger)?;
+    // a time which doesn't fit in milliseconds is refused as redis does
+    let now = now_ms() as i64;
+    let at = match name.as_str() {
+        "expire" => t.checked_mul(1000).and_then(|ms| ms.checked_add(now)),
+        "pexpire" => t.checked_add(now),
+        "expireat" => t.checked_mul(1000),
+        _ => Some(t),
+    }
+    .ok_or_else(|| CommandError::InvalidExpire(name.clone()))?;
+
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    for opt in args[3..].iter() {
+        match opt.to_lowercase().as_str() {
+            "nx" => nx = true,
+            "xx" => xx = true,
+            "gt" => gt = true,
+            "lt" => lt = true,
+            _ => return Err(CommandError::UnsupportedOption(opt.clone()).into()),
+        }
+    }
+    if nx && (xx || gt || lt) {
+        return Err(CommandError::IncompatibleOptions("NX and XX, GT or LT".to_string()).into());
+    }
+    if gt && lt {
+        return Err(CommandError::IncompatibleOptions("GT and LT".to_string()).into());
     }
 
-    resp_vec.push(RESP_OK.to_string().as_bytes().to_vec());
+    let not_set = CommandHandlerResponse::Propagate {
+        message: RespReply::Integer(0),
+        cmds: Vec::new(),
+    };
+    if !db.exists(&key) {
+        return Ok(not_set);
+    }
 
-    // println!("set offset {}", offset);
+    // a key without ttl counts as an infinite ttl for GT and LT
+    let at = at.max(0) as u128;
+    let current = db.get_expire(&key);
+    let skip = match current {
+        Some(cur) => nx || (gt && at <= cur) || (lt && at >= cur),
+        None => xx || gt,
+    };
+    if skip {
+        return Ok(not_set);
+    }
 
-    if db.should_sync_command() {
-        Ok(CommandHandlerResponse::Replica {
-            message: resp_vec,
-            cmd: repl_command,
-            offset: offset as u64,
-        })
-    } else {
-        Ok(CommandHandlerResponse::Set {
-            message: resp_vec,
-            offset: offset as u64,
-        })
+    // an expire time in the past deletes the key right away
+    if at <= now_ms() {
+        db.del(&key);
+        return Ok(CommandHandlerResponse::Propagate {
+            message: RespReply::Integer(1),
+            cmds: vec![vec!["DEL".to_string(), key]],
+        });
     }
+
+    db.expire_at(&key, at);
+    Ok(CommandHandlerResponse::Propagate {
+        message: RespReply::Integer(1),
+        cmds: vec![vec!["PEXPIREAT".to_string(), key, at.to_string()]],
+    })
+}
+
+pub(crate) fn handle_persist(
+    db: &Arc<StoreEngine>,
+    cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    let args = cmd.read().unwrap().args();
+    let removed = db.exists(&args[1]) && db.persist(&args[1]);
+
+    Ok(CommandHandlerResponse::Propagate {
+        message: RespReply::Integer(removed as i64),
+        cmds: if removed { vec![args] } else { Vec::new() },
+    })
+}
+
+// TTL/PTTL key, -2 for a missing key and -1 for a key without ttl
+pub(crate) fn handle_ttl(
+    db: &Arc<StoreEngine>,
+    cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    let args = cmd.read().unwrap().args();
+    let key = &args[1];
+    db.lookup_read(key);
+
+    let ttl = match db.get_expire(key) {
+        _ if !db.exists(key) => -2,
+        None => -1,
+        Some(at) => {
+            let ms = at.saturating_sub(now_ms()) as i64;
+            if args[0].eq_ignore_ascii_case("ttl") {
+                ms.saturating_add(500) / 1000
+            } else {
+                ms
+            }
+        }
+    };
+
+    Ok(CommandHandlerResponse::Basic(RespReply::Integer(ttl)))
 }
 
 pub fn handle_psync(
     db: &Arc<StoreEngine>,
     cmd: Arc<RwLock<RespMessage>>,
 ) -> Result<CommandHandlerResponse> {
-    let myid = db.get_master_id();
+    // PSYNC replicationid offset
+    let args = cmd.read().unwrap().args();
 
-    // stage 1: return +FULLRESYNC and myid
-    let ret = format!("+FULLRESYNC {} 0\r\n", myid);
-    let mut resp_vec = Vec::new();
-    resp_vec.push(ret.as_bytes().to_vec());
-    let rdb_snapshot = hex::decode(EMPTY_RDB).unwrap();
-    let mut rdb_vec: Vec<u8> = string_to_bulk_string_for_psync(EMPTY_RDB.to_string()).into();
-    rdb_vec.extend(&rdb_snapshot);
+    // a replica only has something to serve once it is in sync with its master
+    if !db.is_master() && db.get_master_link_info().0 != LinkStatus::Up {
+        return Err(CommandError::NoMasterLink.into());
+    }
+    let myid = db.get_master_id();
 
     // update slave node handshake state
     let host = cmd.read().unwrap().remote_addr.clone();
     // no stream port needed
     db.set_slave_node(host.clone(), String::from(""), HandshakeState::Psync);
 
-    resp_vec.push(rdb_vec);
+    // +CONTINUE with what the replica missed if the backlog still has it
+    let (sender, receiver) = unbounded_channel();
+    let sender = match args[2].parse::<u64>() {
+        Ok(psync_offset) => match db.partial_resync(host.clone(), sender, &args[1], psync_offset) {
+            Ok(payload) => {
+                return Ok(CommandHandlerResponse::Psync {
+                    message: RespReply::simple(format!("CONTINUE {}", myid)),
+                    payload: PsyncPayload::Backlog(payload),
+                    receiver,
+                });
+            }
+            Err(sender) => sender,
+        },
+        Err(_) => sender,
+    };
+
+    // no write runs between the snapshot and the offset the stream starts at
+    // only the copy is taken under the lock, the connection encodes it
+    let (offset, snapshot) = {
+        let _write = db.write_lock.lock().unwrap();
+        (db.full_resync(host, sender), db.snapshot())
+    };
+
     Ok(CommandHandlerResponse::Psync {
-        message: resp_vec,
-        host,
+        message: RespReply::simple(format!("FULLRESYNC {} {}", myid, offset)),
+        payload: PsyncPayload::Rdb(snapshot),
+        receiver,
     })
 }
 
@@ -155,12 +363,16 @@ pub fn handle_replica(
     db: &Arc<StoreEngine>,
     cmd: Arc<RwLock<RespMessage>>,
 ) -> Result<CommandHandlerResponse> {
-    let mut resp_vec = Vec::new();
-
-    let ret = RESP_OK;
+    let mut ret = None;
     let mut is_getack = false;
 
-    if cmd.read().unwrap().vec_data.len() > 2 {
+    // REPLCONF <option> <value> [<option> <value> ...]
+    let cmd_len = cmd.read().unwrap().vec_data.len();
+    if cmd_len.is_multiple_of(2) {
+        return Err(CommandError::Syntax.into());
+    }
+
+    if cmd_len > 2 {
         let host = cmd.read().unwrap().remote_addr.clone();
 
         match cmd.read().unwrap().vec_data[1]
@@ -170,42 +382,41 @@ pub fn handle_replica(
         {
             "listening-port" => {
                 let stream_port = cmd.read().unwrap().vec_data[2].str_data.clone();
-                db.set_replica_as_master();
                 db.set_slave_node(host.clone(), stream_port.clone(), HandshakeState::Replconf);
-                resp_vec.push(ret.to_string().as_bytes().to_vec());
+                ret = Some(RespReply::ok());
             }
             "capa" => {
                 db.set_slave_node(host.clone(), String::from(""), HandshakeState::ReplconfCapa);
-                resp_vec.push(ret.to_string().as_bytes().to_vec());
+                ret = Some(RespReply::ok());
             }
             "getack" => {
-                let ack_cmd = array_to_resp_array(vec![
+                ret = Some(RespReply::bulk_array(vec![
                     "REPLCONF".to_string(),
                     "GETACK".to_string(),
                     "*".to_string(),
-                ]);
-                resp_vec.push(ack_cmd.as_bytes().to_vec());
+                ]));
                 is_getack = true;
             }
             "ack" => {
-                if cmd.read().unwrap().vec_data.len() > 2 {
-                    let offset = cmd.read().unwrap().vec_data[2]
-                        .str_data
-                        .clone()
-                        .parse::<u64>()?;
-                    // println!("{} ack offset {}", host.clone(), offset);
-                    db.set_slave_offset(host.clone(), offset);
-                    // resp_vec.push(ret.to_string().as_bytes().to_vec());
-                }
+                let offset = cmd.read().unwrap().vec_data[2]
+                    .str_data
+                    .clone()
+                    .parse::<u64>()
+                    .map_err(|_| CommandError::NotInteger)?;
+                db.set_slave_offset(host.clone(), offset);
+                db.notify_ack();
             }
-            _ => {}
+            opt => return Err(anyhow::anyhow!("Unrecognized REPLCONF option: {}", opt)),
         }
+    } else {
+        ret = Some(RespReply::ok());
     }
 
-    if is_getack {
-        Ok(CommandHandlerResponse::GetAck(resp_vec))
-    } else {
-        Ok(CommandHandlerResponse::Basic(resp_vec))
+    // ack from replicas expects no reply
+    match ret {
+        Some(ret) if is_getack => Ok(CommandHandlerResponse::GetAck(ret)),
+        Some(ret) => Ok(CommandHandlerResponse::Basic(ret)),
+        None => Ok(CommandHandlerResponse::NoReply),
     }
 }
 
@@ -213,28 +424,25 @@ pub fn handle_wait(
     db: &Arc<StoreEngine>,
     cmd: Arc<RwLock<RespMessage>>,
 ) -> Result<CommandHandlerResponse> {
-    // let mut resp_vec = Vec::new();
-    let mut timeout: u64 = 0;
-    let mut count: u64 = 0;
-    if cmd.read().unwrap().vec_data.len() > 2 {
-        timeout = cmd.read().unwrap().vec_data[1]
-            .str_data
-            .clone()
-            .parse::<u64>()
-            .unwrap();
-        count = cmd.read().unwrap().vec_data[2]
-            .str_data
-            .clone()
-            .parse::<u64>()
-            .unwrap();
+    // WAIT numreplicas timeout
+    let args = cmd.read().unwrap().args();
+
+    let count = args[1]
+        .parse::<i64>()
+        .map_err(|_| CommandError::NotInteger)?;
+    let timeout = args[2]
+        .parse::<i64>()
+        .map_err(|_| CommandError::InvalidTimeout)?;
+    if timeout < 0 {
+        return Err(CommandError::NegativeTimeout.into());
     }
 
-    let ret = format!(":{}\r\n", db.get_connected_replica_count());
+    let ret = RespReply::Integer(db.get_connected_replica_count() as i64);
 
     Ok(CommandHandlerResponse::Wait {
-        _message: vec![ret.as_bytes().to_vec()],
-        wait_count: count,
-        wait_time: timeout,
+        _message: ret,
+        wait_count: count.max(0) as u64,
+        wait_time: timeout as u64,
     })
 }
 
@@ -242,87 +450,198 @@ pub fn handle_config(
     db: &Arc<StoreEngine>,
     cmd: Arc<RwLock<RespMessage>>,
 ) -> Result<CommandHandlerResponse> {
-    let mut resp_vec = Vec::new();
+    let args = cmd.read().unwrap().args();
 
-    if cmd.read().unwrap().vec_data.len() > 2 {
-        if cmd.read().unwrap().vec_data[1]
-            .str_data
-            .to_lowercase()
-            .as_str()
-            != "get"
-        {
-            return Err(anyhow::anyhow!("unknown config command"));
-        }
+    match args[1].to_lowercase().as_str() {
+        "get" => {
+            if args.len() < 3 {
+                return Err(CommandError::WrongArity("config|get".to_string()).into());
+            }
 
-        match cmd.read().unwrap().vec_data[2]
-            .str_data
-            .to_lowercase()
-            .as_str()
-        {
-            "dir" => {
-                let dir_resp = array_to_resp_array(vec![String::from("dir"), db.get_dir()]);
-                resp_vec.push(dir_resp.as_bytes().to_vec());
+            // unknown parameters are skipped
+            let mut ret = Vec::new();
+            for pattern in args[2..].iter() {
+                for (name, value) in db.config_get(pattern) {
+                    if !ret.contains(&name) {
+                        ret.push(name);
+                        ret.push(value);
+                    }
+                }
             }
-            "dbfilename" => {
-                let dbfilename_resp =
-                    array_to_resp_array(vec![String::from("dbfilename"), db.get_filename()]);
-                resp_vec.push(dbfilename_resp.as_bytes().to_vec());
+            Ok(CommandHandlerResponse::Basic(RespReply::bulk_array(ret)))
+        }
+        "set" => {
+            if args.len() < 4 || !args.len().is_multiple_of(2) {
+                return Err(CommandError::WrongArity("config|set".to_string()).into());
             }
-            _ => return Err(anyhow::anyhow!("unknown config command")),
+
+            let pairs: Vec<(&str, &str)> = args[2..]
+                .chunks(2)
+                .map(|pair| (pair[0].as_str(), pair[1].as_str()))
+                .collect();
+            db.config_set_pairs(&pairs)?;
+            Ok(CommandHandlerResponse::Basic(RespReply::ok()))
         }
-        Ok(CommandHandlerResponse::Basic(resp_vec))
-    } else {
-        Err(anyhow::anyhow!("command too short"))
+        _ => Err(CommandError::UnknownSubcommand(args[1].clone(), "CONFIG".to_string()).into()),
     }
 }
 
-pub fn handle_keys(
+// REPLICAOF host port | REPLICAOF NO ONE, SLAVEOF is the same
+pub fn handle_replicaof(
     db: &Arc<StoreEngine>,
     cmd: Arc<RwLock<RespMessage>>,
 ) -> Result<CommandHandlerResponse> {
-    let mut resp_vec = Vec::new();
+    let args = cmd.read().unwrap().args();
 
-    if cmd.read().unwrap().vec_data.len() > 1 {
-        if cmd.read().unwrap().vec_data[1]
-            .str_data
-            .to_lowercase()
-            .as_str()
-            == "*"
-        {
-            resp_vec.push(array_to_resp_array(db.get_keys()).as_bytes().to_vec());
+    if args[1].eq_ignore_ascii_case("no") && args[2].eq_ignore_ascii_case("one") {
+        // the data and the replication history are kept
+        if let ReplicaType::Slave(_) = db.get_replica() {
+            db.stop_master_link();
+            db.set_replica_as_master();
         }
-        Ok(CommandHandlerResponse::Basic(resp_vec))
-    } else {
-        Err(anyhow::anyhow!("command too short"))
+        return Ok(CommandHandlerResponse::Basic(RespReply::ok()));
+    }
+
+    let port = args[2]
+        .parse::<u16>()
+        .map_err(|_| anyhow::anyhow!("Invalid master port"))?;
+    let master = format!("{}:{}", args[1], port);
+    if db.get_replica() == ReplicaType::Slave(master.clone()) {
+        return Ok(CommandHandlerResponse::Basic(RespReply::simple(
+            "OK Already connected to specified master",
+        )));
     }
+
+    db.set_replica(master)?;
+    db.start_master_link();
+    Ok(CommandHandlerResponse::Basic(RespReply::ok()))
 }
 
-pub(crate) fn handle_type(
+// SAVE blocks the client until the rdb is on disk
+pub fn handle_save(
+    db: &Arc<StoreEngine>,
+    _cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    if !db.stats.begin_save() {
+        return Err(anyhow::anyhow!("Background save already in progress"));
+    }
+    let saved = db.save();
+    db.stats.end_save(saved.is_ok());
+    saved?;
+    Ok(CommandHandlerResponse::Basic(RespReply::ok()))
+}
+
+// BGSAVE [SCHEDULE], the snapshot is written off the runtime threads
+pub fn handle_bgsave(
     db: &Arc<StoreEngine>,
     cmd: Arc<RwLock<RespMessage>>,
 ) -> Result<CommandHandlerResponse> {
-    let mut resp_vec = Vec::new();
+    let args = cmd.read().unwrap().args();
+    match args.get(1) {
+        None => {}
+        Some(opt) if args.len() == 2 && opt.eq_ignore_ascii_case("schedule") => {}
+        Some(_) => return Err(CommandError::Syntax.into()),
+    }
+    if !db.bgsave() {
+        return Err(anyhow::anyhow!("Background save already in progress"));
+    }
+    Ok(CommandHandlerResponse::Basic(RespReply::simple(
+        "Background saving started",
+    )))
+}
 
-    if cmd.read().unwrap().vec_data.len() > 1 {
-        let key = &cmd.read().unwrap().vec_data[1].str_data;
+// the writes go on to a new incr file while the base is written, see AOFWriter
+pub fn handle_bgrewriteaof(
+    db: &Arc<StoreEngine>,
+    _cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    db.bgrewrite_aof()?;
+    Ok(CommandHandlerResponse::Basic(RespReply::simple(
+        "Background append only file rewriting started",
+    )))
+}
 
-        let type_str = match db.get(key.as_str()) {
-            Some(_) => value_type_string::STRING,
-            None => match db.get_stream_key(key) {
-                Some(_) => value_type_string::STREAM,
-                None => value_type_string::NONE,
-            },
-        };
+pub fn handle_lastsave(
+    db: &Arc<StoreEngine>,
+    _cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    Ok(CommandHandlerResponse::Basic(RespReply::Integer(
+        ServerStats::get(&db.stats.last_save_time) as i64,
+    )))
+}
 
-        resp_vec.push(
-            string_to_simple_string(type_str.to_string())
-                .as_bytes()
-                .to_vec(),
-        );
-        Ok(CommandHandlerResponse::Basic(resp_vec))
-    } else {
-        Err(anyhow::anyhow!("command too short"))
+// master: offset and [ip, port, offset] of each replica
+// replica: master host and port, state of the link and offset
+pub fn handle_role(
+    db: &Arc<StoreEngine>,
+    _cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    let (_, _, offset, _) = db.get_repl_ids();
+    let reply = match db.get_replica() {
+        ReplicaType::Master => {
+            let replicas = db
+                .get_replica_list()
+                .into_iter()
+                .map(|(ip, port, _, ack_offset, _)| {
+                    RespReply::bulk_array(vec![ip, port, ack_offset.to_string()])
+                })
+                .collect();
+            RespReply::Array(vec![
+                RespReply::bulk("master"),
+                RespReply::Integer(offset as i64),
+                RespReply::Array(replicas),
+            ])
+        }
+        ReplicaType::Slave(master) => {
+            let (host, port) = master.rsplit_once(':').unwrap_or((master.as_str(), "0"));
+            let state = match db.get_master_link_info().0 {
+                LinkStatus::Down => "connect",
+                LinkStatus::Connecting => "connecting",
+                LinkStatus::Sync => "sync",
+                LinkStatus::Up => "connected",
+            };
+            RespReply::Array(vec![
+                RespReply::bulk("slave"),
+                RespReply::bulk(host),
+                RespReply::Integer(port.parse::<i64>().unwrap_or(0)),
+                RespReply::bulk(state),
+                RespReply::Integer(offset as i64),
+            ])
+        }
+    };
+    Ok(CommandHandlerResponse::Basic(reply))
+}
+
+pub fn handle_keys(
+    db: &Arc<StoreEngine>,
+    cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    let args = cmd.read().unwrap().args();
+
+    let mut keys = Vec::new();
+    if args[1] == "*" {
+        keys = db.get_keys();
     }
+    Ok(CommandHandlerResponse::Basic(RespReply::bulk_array(keys)))
+}
+
+pub(crate) fn handle_type(
+    db: &Arc<StoreEngine>,
+    cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    let args = cmd.read().unwrap().args();
+    let key = &args[1];
+    db.lookup_read(key);
+
+    let type_str = match db.get(key.as_str()) {
+        Some(_) => value_type_string::STRING,
+        None => match db.get_stream_key(key) {
+            Some(_) => value_type_string::STREAM,
+            None => value_type_string::NONE,
+        },
+    };
+
+    Ok(CommandHandlerResponse::Basic(RespReply::simple(type_str)))
 }
 
 static XDD_ID_ERROR: &str =
@@ -333,31 +652,30 @@ pub(crate) fn handle_xadd(
     db: &Arc<StoreEngine>,
     cmd: Arc<RwLock<RespMessage>>,
 ) -> Result<CommandHandlerResponse> {
-    let mut resp_vec = Vec::new();
+    // XADD key id field value [field value ...]
     let cmd_len = cmd.read().unwrap().vec_data.len();
-    if cmd_len > 3 {
+    if !(cmd_len - 3).is_multiple_of(2) {
+        return Err(CommandError::WrongArity("xadd".to_string()).into());
+    }
+
+    {
         let key = &cmd.read().unwrap().vec_data[1].str_data;
         let id = &cmd.read().unwrap().vec_data[2].str_data;
 
-        #[warn(unused_assignments)]
+        if db.get(key).is_some() {
+            return Err(CommandError::WrongType.into());
+        }
+
         let stream_id;
 
         match StreamID::validate(id) {
             StreamIDState::FirstStreamID(_) | StreamIDState::LastStreamID | StreamIDState::Err => {
-                resp_vec.push(
-                    string_error_simple_string(XDD_ID_ERROR.to_string())
-                        .as_bytes()
-                        .to_vec(),
-                );
-                return Ok(CommandHandlerResponse::Basic(resp_vec));
+                return Err(CommandError::InvalidStreamID.into());
             }
             StreamIDState::MillisecondOnly(_ts) => {
-                resp_vec.push(
-                    string_error_simple_string(XDD_ID_ERROR.to_string())
-                        .as_bytes()
-                        .to_vec(),
-                );
-                return Ok(CommandHandlerResponse::Basic(resp_vec));
+                return Ok(CommandHandlerResponse::Basic(RespReply::error(
+                    XDD_ID_ERROR,
+                )));
             }
             StreamIDState::GenerateSequence(ts) => {
                 match db.next_stream_sequence_id(key.clone(), ts) {
@@ -365,12 +683,9 @@ pub(crate) fn handle_xadd(
                         stream_id = sid;
                     }
                     None => {
-                        resp_vec.push(
-                            string_error_simple_string(XDD_ID_ERROR.to_string())
-                                .as_bytes()
-                                .to_vec(),
-                        );
-                        return Ok(CommandHandlerResponse::Basic(resp_vec));
+                        return Ok(CommandHandlerResponse::Basic(RespReply::error(
+                            XDD_ID_ERROR,
+                        )));
                     }
                 }
             }
@@ -384,24 +699,16 @@ pub(crate) fn handle_xadd(
 
         // post validation
         if stream_id == StreamID::default() {
-            resp_vec.push(
-                string_error_simple_string(XDD_ID_ERROR_0.to_string())
-                    .as_bytes()
-                    .to_vec(),
-            );
-
-            return Ok(CommandHandlerResponse::Basic(resp_vec));
+            return Ok(CommandHandlerResponse::Basic(RespReply::error(
+                XDD_ID_ERROR_0,
+            )));
         }
 
         // invalid stream id
         if !db.valid_stream_id(key.clone(), stream_id.clone()) {
-            resp_vec.push(
-                string_error_simple_string(XDD_ID_ERROR.to_string())
-                    .as_bytes()
-                    .to_vec(),
-            );
-
-            return Ok(CommandHandlerResponse::Basic(resp_vec));
+            return Ok(CommandHandlerResponse::Basic(RespReply::error(
+                XDD_ID_ERROR,
+            )));
         }
 
         let mut val_list = Vec::new();
@@ -411,10 +718,6 @@ pub(crate) fn handle_xadd(
         }
         let val_len = val_list.len();
 
-        if val_len == 0 || val_len % 2 != 0 {
-            return Err(anyhow::anyhow!("key/value is not a pair"));
-        }
-
         let mut hmap = HashMap::new();
         let mut idx = 0;
         let mut last_key = String::new();
@@ -430,11 +733,25 @@ pub(crate) fn handle_xadd(
         // insert the map to stream
         let resp = db.set_stream_key(key.clone(), stream_id, hmap)?;
 
-        resp_vec.push(string_to_bulk_string(resp).as_bytes().to_vec());
+        // replicas get the generated id instead of * or ms-*
+        let mut repl_args = cmd.read().unwrap().args();
+        repl_args[2] = resp.clone();
 
-        Ok(CommandHandlerResponse::Basic(resp_vec))
-    } else {
-        Err(anyhow::anyhow!("command too short"))
+        Ok(CommandHandlerResponse::Propagate {
+            message: RespReply::bulk(resp),
+            cmds: vec![repl_args],
+        })
+    }
+}
+
+// parse an id of XRANGE/XREAD, "-" and "+" are only accepted by XRANGE
+fn parse_range_id(db: &Arc<StoreEngine>, key: &str, id: &str) -> Result<StreamID> {
+    match StreamID::validate(id) {
+        StreamIDState::Ok => Ok(StreamID::from(id)),
+        StreamIDState::MillisecondOnly(ts) => Ok(StreamID::new(ts, 0)),
+        StreamIDState::FirstStreamID(sid) => Ok(sid),
+        StreamIDState::LastStreamID => Ok(db.get_last_stream_id(key).unwrap_or_default()),
+        _ => Err(CommandError::InvalidStreamID.into()),
     }
 }
 
@@ -442,152 +759,466 @@ pub(crate) fn handle_xrange(
     db: &Arc<StoreEngine>,
     cmd: Arc<RwLock<RespMessage>>,
 ) -> Result<CommandHandlerResponse> {
-    let mut resp_vec = Vec::new();
-    let cmd_len = cmd.read().unwrap().vec_data.len();
-    if cmd_len < 4 {
-        return Err(anyhow::anyhow!("command too short"));
-    }
-
-    let k = &cmd.read().unwrap().vec_data[1].str_data;
-    let from = &cmd.read().unwrap().vec_data[2].str_data;
-    let to = &cmd.read().unwrap().vec_data[3].str_data;
-    let from_stream_key = match StreamID::validate(from) {
-        StreamIDState::Ok => StreamID::from(from.clone().as_str()),
-        StreamIDState::MillisecondOnly(ts) => StreamID::new(ts, 0),
-        StreamIDState::FirstStreamID(sid) => sid,
-        _ => return Err(anyhow::anyhow!("invalid stream id")),
-    };
-
-    let to_stream_key = match StreamID::validate(to) {
-        StreamIDState::Ok => StreamID::from(to.clone().as_str()),
-        StreamIDState::MillisecondOnly(ts) => StreamID::new(ts, 0),
-        StreamIDState::LastStreamID => db.get_last_stream_id(k.clone()).unwrap_or_default(),
-        _ => return Err(anyhow::anyhow!("invalid stream id")),
-    };
-
-    // println!("from {:?} to {:?}", from_stream_key, to_stream_key);
+    // XRANGE key start end [COUNT count]
+    let args = cmd.read().unwrap().args();
+
+    let mut count = None;
+    match args.len() {
+        4 => {}
+        6 if args[4].to_lowercase() == "count" => {
+            let c = args[5]
+                .parse::<i64>()
+                .map_err(|_| CommandError::NotInteger)?;
+            count = Some(c.max(0) as usize);
+        }
+        _ => return Err(CommandError::Syntax.into()),
+    }
 
-    let stream_range = db.get_stream_by_range(k.clone(), &from_stream_key, &to_stream_key);
-    // println!("{:?}", array_to_resp_array_for_xrange(&stream_range));
+    let k = &args[1];
+    db.lookup_read(k);
+    if db.get(k).is_some() {
+        return Err(CommandError::WrongType.into());
+    }
 
-    resp_vec.push(
-        array_to_resp_array_for_xrange(&stream_range)
-            .as_bytes()
-            .to_vec(),
-    );
+    let from_stream_key = parse_range_id(db, k, &args[2])?;
+    let to_stream_key = parse_range_id(db, k, &args[3])?;
 
-    Ok(CommandHandlerResponse::Basic(resp_vec))
-}
+    let mut stream_range = db.get_stream_by_range(k.clone(), &from_stream_key, &to_stream_key);
+    if let Some(c) = count {
+        stream_range.truncate(c);
+    }
 
-#[derive(PartialEq)]
-enum XReadMode {
-    Block,
-    Stream,
-    Streams,
+    Ok(CommandHandlerResponse::Basic(stream_range_reply(
+        &stream_range,
+    )))
 }
 
 pub(crate) fn handle_xread(
     db: &Arc<StoreEngine>,
     cmd: Arc<RwLock<RespMessage>>,
 ) -> Result<CommandHandlerResponse> {
-    let mut resp_vec = Vec::new();
-    let cmd_len = cmd.read().unwrap().vec_data.len();
-    if cmd_len < 4 {
-        return Err(anyhow::anyhow!("command too short"));
-    }
+    // XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id ...]
     // block case which has several cases
     // 1. has data: return immediately
     // 2. no data: wait for period of time. if no data return nil
+    let args = cmd.read().unwrap().args();
+
+    let mut count = None;
+    let mut block = None;
+    let mut streams_idx = None;
+    let mut idx = 1;
+    while idx < args.len() {
+        match args[idx].to_lowercase().as_str() {
+            "count" => {
+                let Some(c) = args.get(idx + 1) else {
+                    return Err(CommandError::Syntax.into());
+                };
+                let c = c.parse::<i64>().map_err(|_| CommandError::NotInteger)?;
+                count = Some(c.max(0) as usize);
+                idx += 2;
+            }
+            "block" => {
+                let Some(ms) = args.get(idx + 1) else {
+                    return Err(CommandError::Syntax.into());
+                };
+                let ms = ms
+                    .parse::<i64>()
+                    .map_err(|_| CommandError::InvalidTimeout)?;
+                if ms < 0 {
+                    return Err(CommandError::NegativeTimeout.into());
+                }
+                block = Some(ms as u64);
+                idx += 2;
+            }
+            "streams" => {
+                streams_idx = Some(idx + 1);
+                break;
+            }
+            _ => return Err(CommandError::Syntax.into()),
+        }
+    }
 
-    // 1 is streams which allows return multiple streams
-    let xread_mode;
-    xread_mode = match cmd.read().unwrap().vec_data[1].str_data.as_str() {
-        "streams" => XReadMode::Streams,
-        "block" => XReadMode::Block,
-        _ => XReadMode::Stream,
+    let Some(start_key) = streams_idx else {
+        return Err(CommandError::Syntax.into());
     };
 
-    let mut waiting_time = 0;
-    let mut start_key = 2;
-    match xread_mode {
-        XReadMode::Block => {
-            waiting_time = match cmd.read().unwrap().vec_data[2].str_data.parse::<u64>() {
-                Ok(t) => t,
-                Err(_) => return Err(anyhow::anyhow!("invalid waiting time")),
-            };
-            // expect to have another streams string
-            start_key = 4;
+    // the key and id should be in pair
+    let streams = &args[start_key..];
+    if streams.is_empty() || !streams.len().is_multiple_of(2) {
+        return Err(anyhow::anyhow!(
+            "Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified."
+        ));
+    }
+
+    let (keys, ids) = streams.split_at(streams.len() / 2);
+    let mut key_vec = Vec::new();
+    let mut id_vec = Vec::new();
+    for (k, id) in keys.iter().zip(ids.iter()) {
+        db.lookup_read(k);
+        if db.get(k).is_some() {
+            return Err(CommandError::WrongType.into());
         }
-        XReadMode::Streams => {
-            start_key = 2;
+
+        // $ only reads entries added after this call
+        let sid = match id.as_str() {
+            "$" => db.get_last_stream_id(k).unwrap_or_default(),
+            "+" => return Err(CommandError::InvalidStreamID.into()),
+            _ => parse_range_id(db, k, id)?,
+        };
+        key_vec.push(k.clone());
+        id_vec.push(sid);
+    }
+
+    // for block case
+    // let actor handle this
+    // special response type for handler
+    if let Some(ms) = block {
+        let xread_arr = db.get_xread_streams(key_vec.clone(), id_vec.clone(), count)?;
+        if !xread_arr.is_empty() {
+            return Ok(CommandHandlerResponse::Basic(xread_reply(&xread_arr)));
         }
-        _ => {}
+
+        return Ok(CommandHandlerResponse::StreamBlock {
+            ms,
+            count,
+            key_vec,
+            stream_id_vec: id_vec,
+        });
+    }
+
+    // for streams case
+    let xread_arr = db.get_xread_streams(key_vec, id_vec, count)?;
+    if xread_arr.is_empty() {
+        return Ok(CommandHandlerResponse::Basic(RespReply::Null));
+    }
+
+    Ok(CommandHandlerResponse::Basic(xread_reply(&xread_arr)))
+}
+
+// HELLO [protover], switch the connection between RESP2 and RESP3
+pub(crate) fn handle_hello(
+    db: &Arc<StoreEngine>,
+    cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    let mut protocol = RespProtocol::Resp2;
+    if let Some(ver) = cmd.read().unwrap().vec_data.get(1) {
+        let Ok(ver) = ver.str_data.parse::<i64>() else {
+            return Err(anyhow::anyhow!(
+                "Protocol version is not an integer or out of range"
+            ));
+        };
+        protocol = match ver {
+            2 => RespProtocol::Resp2,
+            3 => RespProtocol::Resp3,
+            _ => {
+                return Ok(CommandHandlerResponse::Basic(RespReply::error(
+                    "NOPROTO unsupported protocol version",
+                )))
+            }
+        };
+    }
+
+    let proto = match protocol {
+        RespProtocol::Resp2 => 2,
+        RespProtocol::Resp3 => 3,
+    };
+    let role = match db.get_replica() {
+        ReplicaType::Master => "master",
+        ReplicaType::Slave(_) => "replica",
     };
 
-    if xread_mode == XReadMode::Block || xread_mode == XReadMode::Streams {
-        let mut key_vec = Vec::new();
-        let mut id_vec = Vec::new();
+    let message = RespReply::Map(vec![
+        (RespReply::bulk("server"), RespReply::bulk("redis")),
+        (RespReply::bulk("version"), RespReply::bulk(REDIS_VERSION)),
+        (RespReply::bulk("proto"), RespReply::Integer(proto)),
+        (RespReply::bulk("mode"), RespReply::bulk("standalone")),
+        (RespReply::bulk("role"), RespReply::bulk(role)),
+        (RespReply::bulk("modules"), RespReply::Array(Vec::new())),
+    ]);
 
-        for i in start_key..cmd_len {
-            let v = &cmd.read().unwrap().vec_data[i].str_data;
-            match StreamID::validate(v) {
-                StreamIDState::Ok => {
-                    id_vec.push(StreamID::from(v.clone().as_str()));
-                }
-                StreamIDState::MillisecondOnly(ts) => {
-                    id_vec.push(StreamID::new(ts, 0));
-                }
-                StreamIDState::FirstStreamID(sid) => {
-                    id_vec.push(sid);
-                }
-                _ => {
-                    key_vec.push(v.clone());
-                }
+    Ok(CommandHandlerResponse::Hello { message, protocol })
+}
+
+pub(crate) fn handle_multi(
+    _db: &Arc<StoreEngine>,
+    _cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    Ok(CommandHandlerResponse::Multi(RespReply::ok()))
+}
+
+// EXEC and DISCARD are handled by the connection inside a transaction
+pub(crate) fn handle_exec(
+    _db: &Arc<StoreEngine>,
+    _cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    Err(anyhow::anyhow!("EXEC without MULTI"))
+}
+
+pub(crate) fn handle_discard(
+    _db: &Arc<StoreEngine>,
+    _cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    Err(anyhow::anyhow!("DISCARD without MULTI"))
+}
+
+// COMMAND [COUNT | LIST | INFO name... | DOCS name... | GETKEYS cmd args...]
+pub(crate) fn handle_command(
+    _db: &Arc<StoreEngine>,
+    cmd: Arc<RwLock<RespMessage>>,
+) -> Result<CommandHandlerResponse> {
+    let args = cmd.read().unwrap().args();
+    let Some(sub) = args.get(1) else {
+        return Ok(CommandHandlerResponse::Basic(RespReply::Array(
+            COMMAND_TABLE.iter().map(|spec| spec.info_reply()).collect(),
+        )));
+    };
+
+    let ret = match sub.to_lowercase().as_str() {
+        "count" if args.len() == 2 => RespReply::Integer(COMMAND_TABLE.len() as i64),
+        "list" if args.len() == 2 => RespReply::Array(
+            COMMAND_TABLE
+                .iter()
+                .map(|spec| RespReply::bulk(spec.name))
+                .collect(),
+        ),
+        "info" => {
+            if args.len() == 2 {
+                RespReply::Array(COMMAND_TABLE.iter().map(|spec| spec.info_reply()).collect())
+            } else {
+                RespReply::Array(
+                    args[2..]
+                        .iter()
+                        .map(|name| match lookup_command(name) {
+                            Some(spec) => spec.info_reply(),
+                            None => RespReply::Null,
+                        })
+                        .collect(),
+                )
             }
         }
+        "docs" => {
+            let specs: Vec<_> = if args.len() == 2 {
+                COMMAND_TABLE.iter().collect()
+            } else {
+                // unknown commands are skipped
+                args[2..]
+                    .iter()
+                    .filter_map(|name| lookup_command(name))
+                    .collect()
+            };
+            RespReply::Map(
+                specs
+                    .into_iter()
+                    .map(|spec| (RespReply::bulk(spec.name), spec.docs_reply()))
+                    .collect(),
+            )
+        }
+        "getkeys" if args.len() > 2 => {
+            let Some(spec) = lookup_command(&args[2]) else {
+                return Err(anyhow::anyhow!("Invalid command specified"));
+            };
+            if !spec.check_arity(args.len() - 2) {
+                return Err(anyhow::anyhow!(
+                    "Invalid number of arguments specified for command"
+                ));
+            }
+            let keys = spec.get_keys(&args[2..]);
+            if keys.is_empty() {
+                return Err(anyhow::anyhow!("The command has no key arguments"));
+            }
+            RespReply::bulk_array(keys)
+        }
+        "count" | "list" | "getkeys" => {
+            return Err(CommandError::WrongArity(format!("command|{}", sub.to_lowercase())).into())
+        }
+        _ => {
+            return Err(CommandError::UnknownSubcommand(sub.clone(), "COMMAND".to_string()).into())
+        }
+    };
+
+    Ok(CommandHandlerResponse::Basic(ret))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        // the key and id should be in pair
-        if key_vec.len() != id_vec.len() {
-            return Err(anyhow::anyhow!("key and id mismatch"));
+    fn message(args: &[&str]) -> Arc<RwLock<RespMessage>> {
+        let args = args.iter().map(|a| a.to_string()).collect();
+        Arc::new(RwLock::new(RespMessage::from_args(String::new(), args)))
+    }
+
+    fn reply(resps: Result<CommandHandlerResponse>) -> RespReply {
+        match resps.unwrap() {
+            CommandHandlerResponse::Basic(message) => message,
+            _ => panic!("not a basic reply"),
         }
-        // for block case
-        // let actor handle this
-        // special response type for handler
-        if xread_mode == XReadMode::Block {
-            return Ok(CommandHandlerResponse::StreamBlock {
-                ms: waiting_time,
-                key_vec,
-                stream_id_vec: id_vec,
-            });
+    }
+
+    fn run_set(db: &Arc<StoreEngine>, args: &[&str]) -> Result<CommandHandlerResponse> {
+        let args = args.iter().map(|a| a.to_string()).collect();
+        handle_set(
+            db,
+            Arc::new(RwLock::new(RespMessage::from_args(String::new(), args))),
+        )
+    }
+
+    fn run(db: &Arc<StoreEngine>, args: &[&str]) -> Result<CommandHandlerResponse> {
+        let args = args.iter().map(|a| a.to_string()).collect();
+        handle_expire(
+            db,
+            Arc::new(RwLock::new(RespMessage::from_args(String::new(), args))),
+        )
+    }
+
+    #[test]
+    fn test_expire_overflow() {
+        let db = Arc::new(StoreEngine::new());
+        db.set("k".to_string(), "v".to_string());
+
+        let max = i64::MAX.to_string();
+        for name in ["EXPIRE", "PEXPIRE", "EXPIREAT"] {
+            let err = run(&db, &[name, "k", &max]).err().unwrap();
+            assert_eq!(
+                err.downcast_ref::<CommandError>(),
+                Some(&CommandError::InvalidExpire(name.to_lowercase()))
+            );
         }
+        // the key is left alone
+        assert!(db.exists("k"));
+        assert_eq!(db.get_expire("k"), None);
 
-        // for streams case
-        let xread_arr = db.get_xread_streams(key_vec, id_vec)?;
-        resp_vec.push(array_to_simple_resp_array(xread_arr).as_bytes().to_vec());
+        assert!(run(&db, &["PEXPIREAT", "k", &max]).is_ok());
+        assert!(db.exists("k"));
+    }
 
-        return Ok(CommandHandlerResponse::Basic(resp_vec));
+    #[test]
+    fn test_expire_options() {
+        let db = Arc::new(StoreEngine::new());
+        db.set("k".to_string(), "v".to_string());
+
+        let cases = [
+            (
+                vec!["EXPIRE", "k", "10", "foo"],
+                CommandError::UnsupportedOption("foo".to_string()),
+            ),
+            (
+                vec!["EXPIRE", "k", "10", "NX", "XX"],
+                CommandError::IncompatibleOptions("NX and XX, GT or LT".to_string()),
+            ),
+            (
+                vec!["EXPIRE", "k", "10", "GT", "LT"],
+                CommandError::IncompatibleOptions("GT and LT".to_string()),
+            ),
+        ];
+        for (args, expected) in cases {
+            let err = run(&db, &args).err().unwrap();
+            assert_eq!(err.downcast_ref::<CommandError>(), Some(&expected));
+        }
+        assert_eq!(
+            CommandError::IncompatibleOptions("GT and LT".to_string()).to_string(),
+            "ERR GT and LT options at the same time are not compatible"
+        );
+        assert_eq!(db.get_expire("k"), None);
     }
 
-    // stream case
-    let k = &cmd.read().unwrap().vec_data[2].str_data;
-    let from = &cmd.read().unwrap().vec_data[3].str_data;
+    #[test]
+    fn test_set_expire_overflow() {
+        let db = Arc::new(StoreEngine::new());
 
-    let from_stream_key = match StreamID::validate(from) {
-        StreamIDState::Ok => StreamID::from(from.clone().as_str()),
-        StreamIDState::MillisecondOnly(ts) => StreamID::new(ts, 0),
-        StreamIDState::FirstStreamID(sid) => sid,
-        _ => return Err(anyhow::anyhow!("invalid stream id")),
-    };
+        let max = i64::MAX.to_string();
+        for opt in ["EX", "PX", "EXAT"] {
+            let err = run_set(&db, &["SET", "k", "v", opt, &max]).err().unwrap();
+            assert_eq!(
+                err.downcast_ref::<CommandError>(),
+                Some(&CommandError::InvalidExpire("set".to_string()))
+            );
+        }
+        // nothing is written
+        assert!(!db.exists("k"));
+
+        assert!(run_set(&db, &["SET", "k", "v", "PXAT", &max]).is_ok());
+        assert_eq!(db.get_expire("k"), Some(i64::MAX as u128));
+    }
+
+    #[tokio::test]
+    async fn test_replicaof() {
+        let db = Arc::new(StoreEngine::new());
+        assert!(handle_replicaof(&db, message(&["REPLICAOF", "127.0.0.1", "x"])).is_err());
+        assert!(db.get_replica() == ReplicaType::Master);
+
+        let ok = handle_replicaof(&db, message(&["REPLICAOF", "127.0.0.1", "6390"]));
+        assert_eq!(reply(ok), RespReply::ok());
+        assert!(db.get_replica() == ReplicaType::Slave("127.0.0.1:6390".to_string()));
+
+        let again = handle_replicaof(&db, message(&["REPLICAOF", "127.0.0.1", "6390"]));
+        assert_eq!(
+            reply(again),
+            RespReply::simple("OK Already connected to specified master")
+        );
+
+        // promoted with its data, the link is torn down
+        let promoted = handle_replicaof(&db, message(&["REPLICAOF", "NO", "ONE"]));
+        assert_eq!(reply(promoted), RespReply::ok());
+        assert!(db.get_replica() == ReplicaType::Master);
+        assert!(db.get_master_link_info().0 == LinkStatus::Down);
+        assert!(db.master_link_task.lock().unwrap().is_none());
+    }
 
-    // we need to pack one more layer of array for xread
+    #[test]
+    fn test_set_replica_address() {
+        let db = Arc::new(StoreEngine::new());
+        assert!(db.set_replica("localhost".to_string()).is_err());
+        assert!(db.get_replica() == ReplicaType::Master);
 
-    let stream_range = db.get_xread(k, &from_stream_key);
-    let key_stream_wrap = xrange_to_read_wrap(
-        k.as_str(),
-        array_to_resp_array_for_xrange(&stream_range).as_str(),
-    );
-    let xadd_arr = vec![key_stream_wrap];
-    resp_vec.push(array_to_simple_resp_array(xadd_arr).as_bytes().to_vec());
+        // the port is after the last colon
+        db.set_replica("::1:6390".to_string()).unwrap();
+        assert!(db.get_replica() == ReplicaType::Slave("::1:6390".to_string()));
+    }
 
-    Ok(CommandHandlerResponse::Basic(resp_vec))
+    #[test]
+    fn test_role() {
+        let db = Arc::new(StoreEngine::new());
+        let host = "127.0.0.1:50001".to_string();
+        db.set_slave_node(host.clone(), "6380".to_string(), HandshakeState::Psync);
+        let set = vec!["SET".to_string(), "k".to_string(), "v".to_string()];
+        let offset = db.sync_command(vec![set]);
+        db.set_slave_offset(host, offset);
+
+        assert_eq!(
+            reply(handle_role(&db, message(&["ROLE"]))),
+            RespReply::Array(vec![
+                RespReply::bulk("master"),
+                RespReply::Integer(offset as i64),
+                RespReply::Array(vec![RespReply::bulk_array(vec![
+                    "127.0.0.1".to_string(),
+                    "6380".to_string(),
+                    offset.to_string(),
+                ])]),
+            ])
+        );
+
+        let slave_role = |state: &str, offset: i64| {
+            RespReply::Array(vec![
+                RespReply::bulk("slave"),
+                RespReply::bulk("127.0.0.1"),
+                RespReply::Integer(6390),
+                RespReply::bulk(state),
+                RespReply::Integer(offset),
+            ])
+        };
+        db.set_replica("127.0.0.1:6390".to_string()).unwrap();
+        db.set_link_status(LinkStatus::Connecting);
+        assert_eq!(
+            reply(handle_role(&db, message(&["ROLE"]))),
+            slave_role("connecting", offset as i64)
+        );
+
+        db.set_master_replid("a".repeat(40), 100);
+        db.set_link_status(LinkStatus::Up);
+        assert_eq!(
+            reply(handle_role(&db, message(&["ROLE"]))),
+            slave_role("connected", 100)
+        );
+    }
 }
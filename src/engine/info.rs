@@ -0,0 +1,373 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::REDIS_VERSION;
+use crate::store::engine::StoreEngine;
+use crate::store::master_engine::MasterEngine;
+use crate::store::slave_engine::SlaveEngine;
+use crate::store::stats::{human_bytes, process_cpu, process_rss, ServerStats};
+use crate::store::{LinkStatus, ReplicaType};
+
+// every section in the order INFO prints them
+pub const INFO_SECTIONS: &[&str] = &[
+    "server",
+    "clients",
+    "memory",
+    "persistence",
+    "stats",
+    "replication",
+    "cpu",
+    "commandstats",
+    "keyspace",
+];
+
+// what INFO and INFO default print, all and everything add the rest
+pub fn is_default_section(name: &str) -> bool {
+    name != "commandstats"
+}
+
+// "# Name" followed by its fields, each line ends with CRLF
+pub fn info_section(db: &Arc<StoreEngine>, name: &str) -> Option<String> {
+    let (title, lines) = match name {
+        "server" => ("Server", info_server(db)),
+        "clients" => ("Clients", info_clients(db)),
+        "memory" => ("Memory", info_memory(db)),
+        "persistence" => ("Persistence", info_persistence(db)),
+        "stats" => ("Stats", info_stats(db)),
+        "replication" => ("Replication", info_replication(db)),
+        "cpu" => ("CPU", info_cpu()),
+        "commandstats" => ("Commandstats", info_commandstats(db)),
+        "keyspace" => ("Keyspace", info_keyspace(db)),
+        _ => return None,
+    };
+
+    let mut section = format!("# {}\r\n", title);
+    for line in lines {
+        section.push_str(&line);
+        section.push_str("\r\n");
+    }
+    Some(section)
+}
+
+fn info_server(db: &Arc<StoreEngine>) -> Vec<String> {
+    let uptime = db.stats.uptime().as_secs();
+    let now_usec = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros();
+    let executable = std::env::current_exe()
+        .map(|path| path.display().to_string())
+        .unwrap_or_default();
+    vec![
+        format!("redis_version:{}", REDIS_VERSION),
+        "redis_mode:standalone".to_string(),
+        format!("os:{} {}", std::env::consts::OS, std::env::consts::ARCH),
+        format!("arch_bits:{}", usize::BITS),
+        "multiplexing_api:tokio".to_string(),
+        format!("process_id:{}", std::process::id()),
+        format!("run_id:{}", db.stats.run_id),
+        format!("tcp_port:{}", db.get_port()),
+        format!("server_time_usec:{}", now_usec),
+        format!("uptime_in_seconds:{}", uptime),
+        format!("uptime_in_days:{}", uptime / (24 * 3600)),
+        "hz:10".to_string(),
+        format!("executable:{}", executable),
+        "config_file:".to_string(),
+    ]
+}
+
+fn info_clients(db: &Arc<StoreEngine>) -> Vec<String> {
+    vec![
+        format!(
+            "connected_clients:{}",
+            ServerStats::get(&db.stats.connected_clients)
+        ),
+        format!(
+            "blocked_clients:{}",
+            ServerStats::get(&db.stats.blocked_clients)
+        ),
+        "maxclients:10000".to_string(),
+    ]
+}
+
+fn info_memory(db: &Arc<StoreEngine>) -> Vec<String> {
+    // no allocator stats, the memory is what the data and the backlog hold
+    let dataset = db.dataset_memory();
+    let (_, _, backlog) = db.get_backlog_info();
+    let used = dataset + backlog;
+    let peak = db.stats.update_memory_peak(used);
+    let rss = process_rss();
+    vec![
+        format!("used_memory:{}", used),
+        format!("used_memory_human:{}", human_bytes(used)),
+        format!("used_memory_rss:{}", rss),
+        format!("used_memory_rss_human:{}", human_bytes(rss)),
+        format!("used_memory_peak:{}", peak),
+        format!("used_memory_peak_human:{}", human_bytes(peak)),
+        format!("used_memory_dataset:{}", dataset),
+        format!("mem_replication_backlog:{}", backlog),
+        "maxmemory:0".to_string(),
+        "maxmemory_human:0B".to_string(),
+        "maxmemory_policy:noeviction".to_string(),
+    ]
+}
+
+fn info_persistence(db: &Arc<StoreEngine>) -> Vec<String> {
+    let stats = &db.stats;
+    let saving = stats.save_in_progress.load(Ordering::Relaxed);
+    let mut lines = vec![
+        format!(
+            "loading:{}",
+            u8::from(stats.loading.load(Ordering::Relaxed))
+        ),
+        format!(
+            "rdb_changes_since_last_save:{}",
+            ServerStats::get(&stats.dirty)
+        ),
+        format!("rdb_bgsave_in_progress:{}", u8::from(saving)),
+        format!(
+            "rdb_last_save_time:{}",
+            ServerStats::get(&stats.last_save_time)
+        ),
+        format!("rdb_last_bgsave_status:{}", ok_err(&stats.last_save_ok)),
+    ];
+    let aof_enabled = db.config.read().unwrap().appendonly;
+    lines.extend([
+        format!("aof_enabled:{}", u8::from(aof_enabled)),
+        format!(
+            "aof_rewrite_in_progress:{}",
+            u8::from(stats.aof_rewrite_in_progress.load(Ordering::Relaxed))
+        ),
+        format!(
+            "aof_rewrite_scheduled:{}",
+            u8::from(stats.aof_rewrite_scheduled.load(Ordering::Relaxed))
+        ),
+        format!("aof_rewrites:{}", ServerStats::get(&stats.aof_rewrites)),
+        format!(
+            "aof_last_bgrewrite_status:{}",
+            ok_err(&stats.aof_last_rewrite_ok)
+        ),
+        format!("aof_last_write_status:{}", ok_err(&stats.aof_last_write_ok)),
+    ]);
+    // redis adds the sizes only while the aof is on
+    if let Some(aof) = db.aof.lock().unwrap().as_ref() {
+        lines.push(format!("aof_current_size:{}", aof.size));
+        lines.push(format!("aof_base_size:{}", aof.base_size));
+    }
+    lines
+}
+
+fn ok_err(ok: &AtomicBool) -> &'static str {
+    match ok.load(Ordering::Relaxed) {
+        true => "ok",
+        false => "err",
+    }
+}
+
+fn info_stats(db: &Arc<StoreEngine>) -> Vec<String> {
+    let stats = &db.stats;
+    [
+        (
+            "total_connections_received",
+            &stats.total_connections_received,
+        ),
+        ("total_commands_processed", &stats.total_commands_processed),
+        ("total_net_input_bytes", &stats.total_net_input_bytes),
+        ("total_net_output_bytes", &stats.total_net_output_bytes),
+        ("expired_keys", &stats.expired_keys),
+        ("keyspace_hits", &stats.keyspace_hits),
+        ("keyspace_misses", &stats.keyspace_misses),
+        ("total_error_replies", &stats.total_error_replies),
+    ]
+    .iter()
+    .map(|(name, counter)| format!("{}:{}", name, ServerStats::get(counter)))
+    .chain([
+        "rejected_connections:0".to_string(),
+        "evicted_keys:0".to_string(),
+    ])
+    .collect()
+}
+
+// the fields and their order follow redis, tools like sentinel parse them
+fn info_replication(db: &Arc<StoreEngine>) -> Vec<String> {
+    let (replid, replid2, offset, second_offset) = db.get_repl_ids();
+    let mut lines = Vec::new();
+
+    match db.get_replica() {
+        ReplicaType::Master => lines.push("role:master".to_string()),
+        ReplicaType::Slave(master) => {
+            let (host, port) = master.rsplit_once(':').unwrap_or((master.as_str(), ""));
+            let (status, last_io, down_since) = db.get_master_link_info();
+            let config = db.config.read().unwrap();
+            lines.extend([
+                "role:slave".to_string(),
+                format!("master_host:{}", host),
+                format!("master_port:{}", port),
+                format!(
+                    "master_link_status:{}",
+                    if status == LinkStatus::Up {
+                        "up"
+                    } else {
+                        "down"
+                    }
+                ),
+                format!("master_last_io_seconds_ago:{}", last_io),
+                format!(
+                    "master_sync_in_progress:{}",
+                    u8::from(status == LinkStatus::Sync)
+                ),
+                format!("slave_read_repl_offset:{}", db.get_read_repl_offset()),
+                format!("slave_repl_offset:{}", offset),
+            ]);
+            if status != LinkStatus::Up {
+                lines.push(format!("master_link_down_since_seconds:{}", down_since));
+            }
+            lines.extend([
+                format!("slave_priority:{}", config.replica_priority),
+                format!("slave_read_only:{}", u8::from(config.replica_read_only)),
+                "replica_announced:1".to_string(),
+            ]);
+        }
+    }
+
+    let replicas = db.get_replica_list();
+    lines.push(format!("connected_slaves:{}", replicas.len()));
+    for (i, (ip, port, state, ack_offset, lag)) in replicas.iter().enumerate() {
+        lines.push(format!(
+            "slave{}:ip={},port={},state={},offset={},lag={}",
+            i, ip, port, state, ack_offset, lag
+        ));
+    }
+
+    let (backlog_size, first_byte_offset, histlen) = db.get_backlog_info();
+    lines.extend([
+        "master_failover_state:no-failover".to_string(),
+        format!("master_replid:{}", replid),
+        format!("master_replid2:{}", replid2),
+        format!("master_repl_offset:{}", offset),
+        format!("second_repl_offset:{}", second_offset),
+        "repl_backlog_active:1".to_string(),
+        format!("repl_backlog_size:{}", backlog_size),
+        format!("repl_backlog_first_byte_offset:{}", first_byte_offset),
+        format!("repl_backlog_histlen:{}", histlen),
+    ]);
+    lines
+}
+
+fn info_cpu() -> Vec<String> {
+    let (user, sys) = process_cpu();
+    vec![
+        format!("used_cpu_sys:{:.6}", sys),
+        format!("used_cpu_user:{:.6}", user),
+    ]
+}
+
+fn info_commandstats(db: &Arc<StoreEngine>) -> Vec<String> {
+    db.stats
+        .command_stats()
+        .into_iter()
+        .map(|(name, stat)| {
+            let per_call = match stat.calls {
+                0 => 0.0,
+                calls => stat.usec as f64 / calls as f64,
+            };
+            format!(
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}",
+                name, stat.calls, stat.usec, per_call, stat.rejected_calls, stat.failed_calls
+            )
+        })
+        .collect()
+}
+
+// redis leaves out the empty databases
+fn info_keyspace(db: &Arc<StoreEngine>) -> Vec<String> {
+    let (keys, expires, avg_ttl) = db.keyspace_info();
+    if keys == 0 {
+        return Vec::new();
+    }
+    vec![format!(
+        "db0:keys={},expires={},avg_ttl={}",
+        keys, expires, avg_ttl
+    )]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::HandshakeState;
+
+    fn replication(db: &Arc<StoreEngine>) -> Vec<String> {
+        info_section(db, "replication")
+            .unwrap()
+            .split("\r\n")
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_info_replication_master() {
+        let db = Arc::new(StoreEngine::new());
+        let host = "127.0.0.1:50001".to_string();
+        db.set_slave_node(host.clone(), "6380".to_string(), HandshakeState::Psync);
+        let set = vec!["SET".to_string(), "k".to_string(), "v".to_string()];
+        let offset = db.sync_command(vec![set]);
+        db.set_slave_offset(host.clone(), offset - 1);
+
+        let lines = replication(&db);
+        assert_eq!(lines[0], "# Replication");
+        assert_eq!(lines[1], "role:master");
+        assert_eq!(lines[2], "connected_slaves:1");
+        let slave = format!(
+            "slave0:ip=127.0.0.1,port=6380,state=send_bulk,offset={},lag=0",
+            offset - 1
+        );
+        assert_eq!(lines[3], slave);
+        assert!(lines.contains(&format!("master_repl_offset:{}", offset)));
+        assert!(lines.contains(&format!("repl_backlog_histlen:{}", offset)));
+        assert!(lines.contains(&"second_repl_offset:-1".to_string()));
+
+        // online once its resync payload is out
+        db.set_replica_online(&host);
+        assert!(replication(&db)[3].contains("state=online"));
+    }
+
+    #[test]
+    fn test_info_replication_replica() {
+        let db = Arc::new(StoreEngine::new());
+        db.set_replica("127.0.0.1:6390".to_string()).unwrap();
+        db.set_link_status(LinkStatus::Connecting);
+
+        let lines = replication(&db);
+        assert_eq!(
+            lines[1..6],
+            [
+                "role:slave",
+                "master_host:127.0.0.1",
+                "master_port:6390",
+                "master_link_status:down",
+                "master_last_io_seconds_ago:-1",
+            ]
+        );
+        assert!(lines.contains(&"master_link_down_since_seconds:0".to_string()));
+
+        // synced from offset 100 with 5 bytes of a split frame read ahead
+        db.set_master_replid("a".repeat(40), 100);
+        db.set_link_status(LinkStatus::Up);
+        db.touch_master_link();
+        db.master_link.write().unwrap().read_ahead = 5;
+
+        let lines = replication(&db);
+        assert!(lines.contains(&"master_link_status:up".to_string()));
+        assert!(lines.contains(&"master_last_io_seconds_ago:0".to_string()));
+        assert!(lines.contains(&"slave_read_repl_offset:105".to_string()));
+        assert!(lines.contains(&"slave_repl_offset:100".to_string()));
+        assert!(lines.contains(&"master_repl_offset:100".to_string()));
+        assert!(lines.contains(&format!("master_replid:{}", "a".repeat(40))));
+        assert!(lines.contains(&"slave_priority:100".to_string()));
+        assert!(lines.contains(&"slave_read_only:1".to_string()));
+        assert!(!lines
+            .iter()
+            .any(|l| l.starts_with("master_link_down_since_seconds")));
+    }
+}
@@ -1,29 +1,20 @@
+pub mod command_table;
 pub mod commands;
 pub mod connection;
+pub mod error;
 mod handler;
+mod info;
 pub mod parser;
+pub mod reply;
 
-use hex;
+use crate::rdb::writer::RdbSnapshot;
+use crate::store::engine::StreamID;
+use error::CommandError;
+use reply::{RespProtocol, RespReply};
+use tokio::sync::mpsc::UnboundedReceiver;
 
-use crate::store::{engine::StreamID, stream_engine::StreamRange};
-
-// const CRLR: &str = "\r\n";
-
-const RESP_OK: &str = "+OK\r\n";
-const RESP_ERR: &str = "-ERR\r\n";
-const RESP_PONG: &str = "+PONG\r\n";
-const RESP_EMPTY: &str = "*0\r\n";
-const RESP_NULL: &str = "$-1\r\n";
-
-// hardcoded lenth
-pub const PING_LEN: usize = 14;
-pub const REPL_GETACK_LEN: usize = 37;
-
-// preset id of master node (40 chars long)
-// it will be changed to a random value in the future
-const MYID: &str = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
-
-const EMPTY_RDB: &str = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
+// the redis version we answer as in HELLO and INFO
+pub const REDIS_VERSION: &str = "7.2.0";
 
 #[derive(PartialEq, Clone)]
 pub enum RespParsingState {
@@ -42,35 +33,30 @@ pub enum RespType {
     Array,
 }
 
-#[allow(dead_code)]
-#[derive(PartialEq, Clone, Debug)]
-pub enum RespCommandType {
-    Error,
-    Get(String),
-    Set(String, String),
-    SetPx(String, String, u64),
-    Ping,
-    Replconf(String),
-}
-
 pub enum CommandHandlerResponse {
-    Basic(Vec<Vec<u8>>),
-    Set {
-        message: Vec<Vec<u8>>,
+    Basic(RespReply),
+    // e.g. REPLCONF ACK from replicas
+    NoReply,
+    // a write fed to the replication stream, offset is the master offset right after it
+    Write {
+        message: RespReply,
         offset: u64,
     },
-    Psync {
-        message: Vec<Vec<u8>>,
-        host: String,
+    // returned by a write handler to override what is sent to the replicas
+    // the original arguments are sent otherwise, no command at all for an empty list
+    Propagate {
+        message: RespReply,
+        cmds: Vec<Vec<String>>,
     },
-    Replica {
-        message: Vec<Vec<u8>>,
-        cmd: String,
-        offset: u64,
+    // the writes after the payload wait in the receiver until it is sent
+    Psync {
+        message: RespReply,
+        payload: PsyncPayload,
+        receiver: UnboundedReceiver<Vec<u8>>,
     },
-    GetAck(Vec<Vec<u8>>),
+    GetAck(RespReply),
     Wait {
-        _message: Vec<Vec<u8>>,
+        _message: RespReply,
         wait_time: u64,
         wait_count: u64,
     },
@@ -78,9 +64,27 @@ pub enum CommandHandlerResponse {
     // for xread block async operation
     StreamBlock {
         ms: u64,
+        count: Option<usize>,
         key_vec: Vec<String>,
         stream_id_vec: Vec<StreamID>,
     },
+
+    // MULTI starts queueing the commands of the connection
+    Multi(RespReply),
+
+    // HELLO switches the protocol of the connection
+    Hello {
+        message: RespReply,
+        protocol: RespProtocol,
+    },
+}
+
+// what a replica gets right after +FULLRESYNC or +CONTINUE
+pub enum PsyncPayload {
+    // the part of the backlog it missed
+    Backlog(Vec<u8>),
+    // the keyspace of a full resync, encoded away from the runtime threads
+    Rdb(RdbSnapshot),
 }
 
 #[derive(PartialEq, Clone)]
@@ -105,9 +109,47 @@ impl RespMessage {
         }
     }
 
-    pub fn parse(&mut self, data: &str) {
+    // build a finished array command out of already split arguments
+    pub fn from_args(addr: String, args: Vec<String>) -> Self {
+        let mut msg = RespMessage::new(addr.clone());
+        msg.resp_type = RespType::Array;
+        msg.state = RespParsingState::End;
+        for arg in args {
+            let mut elem = RespMessage::new(addr.clone());
+            elem.resp_type = RespType::BulkString;
+            elem.state = RespParsingState::End;
+            elem.str_data = arg;
+            msg.vec_data.push(elem);
+        }
+        msg
+    }
+
+    // arguments of an array command, the command name included
+    pub fn args(&self) -> Vec<String> {
+        self.vec_data.iter().map(|m| m.str_data.clone()).collect()
+    }
+
+    // a fresh message which hasn't seen any RESP type prefix yet
+    pub fn is_empty(&self) -> bool {
+        self.state == RespParsingState::ParsingMeta && self.resp_type == RespType::Unknown
+    }
+
+    // lengths and integers must be made of digits only, anything else is a protocol error
+    pub fn parse(&mut self, data: &str) -> Result<(), CommandError> {
         let iter = data.chars().peekable();
         for c in iter {
+            let in_length = self.state == RespParsingState::ParsingMeta
+                && matches!(self.resp_type, RespType::BulkString | RespType::Array);
+            if in_length {
+                let what = if self.resp_type == RespType::Array {
+                    "invalid multibulk length"
+                } else {
+                    "invalid bulk length"
+                };
+                self.push_digit(c, what)?;
+                continue;
+            }
+
             match c {
                 '+' => {
                     if self.state == RespParsingState::ParsingMeta {
@@ -150,31 +192,19 @@ impl RespMessage {
                 _ => {
                     // parsing logic
                     match self.resp_type {
-                        RespType::SimpleString | RespType::Error => {
-                            if self.state == RespParsingState::ParsingData {
-                                self.str_data.push(c);
-                            }
-                        }
-                        RespType::Integer => {
-                            if self.state == RespParsingState::ParsingData {
-                                self.int_data = self.int_data * 10 + c.to_digit(10).unwrap() as i64;
-                            }
+                        RespType::SimpleString | RespType::Error
+                            if self.state == RespParsingState::ParsingData =>
+                        {
+                            self.str_data.push(c);
                         }
-                        RespType::BulkString => {
-                            if self.state == RespParsingState::ParsingMeta {
-                                self.int_data = self.int_data * 10 + c.to_digit(10).unwrap() as i64;
-                            } else if self.state == RespParsingState::ParsingData
-                                && self.int_data > 0
-                            {
-                                self.str_data.push(c);
-                                self.int_data -= 1;
-                            }
+                        RespType::Integer if self.state == RespParsingState::ParsingData => {
+                            self.push_digit(c, "invalid integer")?;
                         }
-                        RespType::Array => {
-                            if self.state == RespParsingState::ParsingMeta {
-                                self.int_data = self.int_data * 10 + c.to_digit(10).unwrap() as i64;
-                            } else if self.state == RespParsingState::ParsingData {
-                            }
+                        RespType::BulkString
+                            if self.state == RespParsingState::ParsingData && self.int_data > 0 =>
+                        {
+                            self.str_data.push(c);
+                            self.int_data -= 1;
                         }
                         _ => {}
                     }
@@ -187,110 +217,28 @@ impl RespMessage {
         } else if self.state == RespParsingState::ParsingMeta {
             self.state = RespParsingState::ParsingData;
         }
+        Ok(())
     }
-}
-
-pub fn string_to_bulk_string(s: String) -> String {
-    format!("${}\r\n{}\r\n", s.len(), s)
-}
-
-pub fn string_to_bulk_string_for_psync(s: String) -> String {
-    let rdb_decode = hex::decode(s).unwrap();
-    format!("${}\r\n", rdb_decode.len())
-}
-
-pub fn string_to_simple_string(s: String) -> String {
-    format!("+{}\r\n", s)
-}
-
-pub fn string_error_simple_string(s: String) -> String {
-    format!("-{}\r\n", s)
-}
-
-pub fn array_to_resp_array(vec: Vec<String>) -> String {
-    let mut ret = String::new();
-    ret.push_str(format!("*{}\r\n", vec.len()).as_str());
-
-    for v in vec {
-        ret.push_str(&string_to_bulk_string(v));
-    }
-
-    ret
-}
-pub fn array_to_simple_resp_array(vec: Vec<String>) -> String {
-    let mut ret = String::new();
-    ret.push_str(format!("*{}\r\n", vec.len()).as_str());
 
-    for v in vec {
-        ret.push_str(&v);
+    fn push_digit(&mut self, c: char, what: &str) -> Result<(), CommandError> {
+        self.int_data = c
+            .to_digit(10)
+            .and_then(|d| self.int_data.checked_mul(10)?.checked_add(d as i64))
+            .ok_or_else(|| CommandError::Protocol(what.to_string()))?;
+        Ok(())
     }
-
-    ret
 }
-pub fn array_to_resp_array_for_xrange(v: &Vec<StreamRange>) -> String {
-    let mut ret = String::new();
-    ret.push_str(format!("*{}\r\n", v.len()).as_str());
-
-    for r in v {
-        // hardcoded for StreamID, hash array
-        ret.push_str("*2\r\n");
-        ret.push_str(&string_to_bulk_string((&r.stream_id).into()));
-
-        let hash_total_len = r.hash.len() * 2;
-        ret.push_str(format!("*{}\r\n", hash_total_len).as_str());
 
-        for (k, v) in r.hash.iter() {
-            ret.push_str(&string_to_bulk_string(k.clone()));
-            ret.push_str(&string_to_bulk_string(v.clone()));
-        }
-    }
-
-    ret
+// encode a command as a RESP array of bulk strings
+pub fn array_to_resp_array(vec: Vec<String>) -> String {
+    String::from_utf8(RespReply::bulk_array(vec).encode(RespProtocol::Resp2)).unwrap_or_default()
 }
 
-pub(crate) fn xrange_to_read_wrap(k: &str, v: &str) -> String {
-    let mut ret = String::new();
-    ret.push_str("*2\r\n");
-    ret.push_str(&string_to_bulk_string(k.to_string()));
-    ret.push_str(v);
-
-    ret
-}
-pub fn count_resp_command_type_offset(resp_command_type: RespCommandType) -> usize {
-    match resp_command_type {
-        RespCommandType::Error => 0,
-        RespCommandType::Get(k) => {
-            // *2\r\n$3\r\nGET\r\n$3\r\nkey\r\n
-            // 13 + 5
-            let mut base = 18;
-            base += k.len();
-            base += format!("{}", k.len()).len();
-            base
-        }
-        RespCommandType::Set(k, v) => {
-            // *3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\n123\r\n
-            // 13 + 5 + 5
-            let mut base = 23;
-            base += k.len() + v.len();
-            base += format!("{}{}", k.len(), v.len()).len();
-            base
-        }
-        RespCommandType::SetPx(k, v, ttl) => {
-            // *5\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\n123\r\n$2\r\nPX\r\n$3\r\n100\r\n
-            // 13 + 5 + 5 + 5 + 8
-            // 8 is $2\r\nPX\r\n
-            let mut base = 36;
-            base += k.len() + v.len();
-            base += format!("{}{}", k.len(), v.len()).len();
-            base += format!("{}", ttl).len();
-            base
-        }
-        RespCommandType::Ping => PING_LEN,
-        RespCommandType::Replconf(k) => match k.as_str() {
-            "getack" => REPL_GETACK_LEN,
-            _ => 0,
-        },
-    }
+// psync sends the rdb as a bulk string without the trailing CRLF
+pub fn rdb_to_psync_payload(rdb: &[u8]) -> Vec<u8> {
+    let mut payload = format!("${}\r\n", rdb.len()).into_bytes();
+    payload.extend_from_slice(rdb);
+    payload
 }
 
 #[cfg(test)]
@@ -299,7 +247,29 @@ mod test {
 
     #[test]
     fn test_resp_message_parse() {
-        let cmd = RespCommandType::Get(String::from("foo"));
-        assert_eq!(count_resp_command_type_offset(cmd), 22);
+        // the replication offset moves by the exact size of the encoded command
+        let cmd = array_to_resp_array(vec!["GET".to_string(), "foo".to_string()]);
+        assert_eq!(cmd, "*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n");
+        assert_eq!(cmd.len(), 22);
+    }
+
+    #[test]
+    fn test_resp_message_malformed() {
+        for (line, what) in [
+            ("*x", "invalid multibulk length"),
+            ("*-1", "invalid multibulk length"),
+            ("*99999999999999999999", "invalid multibulk length"),
+            ("$1a", "invalid bulk length"),
+            (":abc", "invalid integer"),
+        ] {
+            let err = RespMessage::new(String::new()).parse(line).unwrap_err();
+            assert_eq!(err, CommandError::Protocol(what.to_string()), "{}", line);
+        }
+
+        let mut msg = RespMessage::new(String::new());
+        assert!(msg.parse("$3").is_ok());
+        assert!(msg.parse("GET").is_ok());
+        assert!(msg.state == RespParsingState::End);
+        assert_eq!(msg.str_data, "GET");
     }
 }
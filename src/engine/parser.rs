@@ -1,153 +1,173 @@
-use super::{RespCommandType, RespParsingState, RespType};
 use anyhow;
 
-// we assume that the input is always a RESP array
-pub fn command_parser(input: &mut [u8]) -> anyhow::Result<Vec<RespCommandType>> {
-    let mut iter = input.iter().enumerate().peekable();
-    let mut parsing_state = RespParsingState::ParsingMeta;
-
-    let mut current_resp_type = RespType::Array;
-
-    let mut cmd_vec = Vec::new();
-    let mut cmd_number = 0;
+// a frame read from the replication link
+#[derive(Debug, PartialEq)]
+pub enum ReplFrame {
+    // reply of PSYNC, e.g. +FULLRESYNC <replid> <offset>
+    Simple(String),
+    // the rdb following FULLRESYNC, a bulk string without the trailing CRLF
+    Rdb(Vec<u8>),
+    Command(Vec<String>),
+}
 
-    let mut resp_vec = Vec::new();
-    while let Some((pos, c)) = iter.next() {
-        let c = *c as char;
-        match c {
-            '*' => {
-                if parsing_state == RespParsingState::ParsingMeta {
-                    current_resp_type = RespType::Array;
-                }
-            }
-            '$' => {
-                if parsing_state == RespParsingState::ParsingMeta {
-                    current_resp_type = RespType::BulkString;
-                }
+// parse one frame at the start of buf, Ok(None) if more bytes are needed
+// the number of bytes consumed is returned with the frame
+pub fn parse_repl_frame(buf: &[u8]) -> anyhow::Result<Option<(ReplFrame, usize)>> {
+    let Some(first) = buf.first() else {
+        return Ok(None);
+    };
+
+    match first {
+        b'+' | b'-' => {
+            let Some(end) = find_crlf(buf, 1) else {
+                return Ok(None);
+            };
+            let line = String::from_utf8_lossy(&buf[1..end]).to_string();
+            if *first == b'-' {
+                return Err(anyhow::anyhow!("{}", line));
             }
-            '+' => {
-                if parsing_state == RespParsingState::ParsingMeta {
-                    let simple_string = iter
-                        .by_ref()
-                        .take_while(|(_pos, c)| (**c as char) != '\r')
-                        .map(|(_pos, c)| *c as char)
-                        .collect();
-                    // expect next is \r\n
-                    cmd_vec = Vec::new();
-                    cmd_vec.push(simple_string);
-                    resp_vec.push(process_command_vec(cmd_vec));
-                    cmd_vec = Vec::new();
-                    parsing_state = RespParsingState::ParsingMeta;
-                }
+            Ok(Some((ReplFrame::Simple(line), end + 2)))
+        }
+        b'$' => {
+            let Some((len, start)) = parse_frame_len(buf, 1)? else {
+                return Ok(None);
+            };
+            if buf.len() < start + len {
+                return Ok(None);
             }
-            '0'..='9' => {
-                if parsing_state == RespParsingState::ParsingMeta {
-                    let start = pos;
-                    let mut end = pos;
-                    while iter
-                        .next_if(|&(_pos, ch)| (*ch as char).is_numeric())
-                        .is_some()
-                    {
-                        end += 1;
-                    }
-                    if end < input.len() {
-                        end += 1;
-                    }
-                    // turn out to str first
-                    let num = std::str::from_utf8(&input[start..end])
-                        .to_owned()?
-                        .parse::<usize>()?;
-                    // println!("collect number: {}", num);
-
-                    if iter.next_if_eq(&(end, &('\r' as u8))).is_some()
-                        && iter.next_if_eq(&(end + 1, &('\n' as u8))).is_some()
-                    {
-                        if current_resp_type == RespType::Array {
-                            cmd_number = num;
-                        } else {
-                            parsing_state = RespParsingState::ParsingData;
-                            let str_data = iter
-                                .by_ref()
-                                .take(num)
-                                .map(|(_pos, ch)| *ch as char)
-                                .collect::<String>();
-
-                            cmd_vec.push(str_data);
-                            // new command
-                            // include string case
-                            if cmd_number <= 1 {
-                                resp_vec.push(process_command_vec(cmd_vec));
-                                cmd_vec = Vec::new();
-                                parsing_state = RespParsingState::ParsingMeta;
-                            } else {
-                                cmd_number -= 1;
-                            }
-                        }
-                    } else {
-                        return Err(anyhow::anyhow!("Invalid RESP command"));
-                    }
+            Ok(Some((
+                ReplFrame::Rdb(buf[start..start + len].to_vec()),
+                start + len,
+            )))
+        }
+        b'*' => {
+            let Some((num, mut pos)) = parse_frame_len(buf, 1)? else {
+                return Ok(None);
+            };
+            let mut args = Vec::with_capacity(num);
+            for _ in 0..num {
+                let Some(c) = buf.get(pos) else {
+                    return Ok(None);
+                };
+                if *c != b'$' {
+                    return Err(anyhow::anyhow!("expected '$', got '{}'", *c as char));
                 }
-            }
-            _ => {
-                if c == '\r' {
-                    if iter.next_if_eq(&(pos + 1, &('\n' as u8))).is_some() {
-                        parsing_state = RespParsingState::ParsingMeta;
-                    }
+                let Some((len, start)) = parse_frame_len(buf, pos + 1)? else {
+                    return Ok(None);
+                };
+                if buf.len() < start + len + 2 {
+                    return Ok(None);
                 }
+                args.push(String::from_utf8_lossy(&buf[start..start + len]).to_string());
+                pos = start + len + 2;
             }
+            Ok(Some((ReplFrame::Command(args), pos)))
         }
+        c => Err(anyhow::anyhow!(
+            "unexpected '{}' on the replication link",
+            *c as char
+        )),
     }
-
-    Ok(resp_vec)
 }
 
-fn process_command_vec(cmd_vec: Vec<String>) -> RespCommandType {
-    if cmd_vec.len() == 0 {
-        return RespCommandType::Error;
-    }
-
-    // println!("cmd_vec: {:?}", cmd_vec);
+fn find_crlf(buf: &[u8], from: usize) -> Option<usize> {
+    buf.get(from..)?
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .map(|p| p + from)
+}
 
-    match cmd_vec[0].to_lowercase().as_str() {
-        "ping" => RespCommandType::Ping,
-        "set" => {
-            if cmd_vec.len() < 3 {
-                return RespCommandType::Error;
-            }
+// length of a bulk string or an array, returned with the position right after its CRLF
+fn parse_frame_len(buf: &[u8], from: usize) -> anyhow::Result<Option<(usize, usize)>> {
+    let Some(end) = find_crlf(buf, from) else {
+        return Ok(None);
+    };
+    let len = std::str::from_utf8(&buf[from..end])?.parse::<usize>()?;
+    Ok(Some((len, end + 2)))
+}
 
-            if cmd_vec.len() == 3 {
-                return RespCommandType::Set(cmd_vec[1].clone(), cmd_vec[2].clone());
-            }
-            if cmd_vec.len() != 5 {
-                return RespCommandType::Error;
-            }
-            if cmd_vec[3].to_lowercase() != "px" {
-                return RespCommandType::Error;
-            }
-            if let Ok(ttl) = cmd_vec[4].parse::<u64>() {
-                RespCommandType::SetPx(cmd_vec[1].clone(), cmd_vec[2].clone(), ttl)
-            } else {
-                return RespCommandType::Error;
-            }
+// split an inline command (telnet/netcat) the same way redis does
+// arguments are separated by spaces and can be quoted with "..." or '...'
+pub fn split_inline_args(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut iter = line.chars().peekable();
+
+    loop {
+        // skip blanks
+        while iter.next_if(|c| c.is_whitespace()).is_some() {}
+        if iter.peek().is_none() {
+            return Ok(args);
         }
-        "get" => {
-            if cmd_vec.len() != 2 {
-                return RespCommandType::Error;
-            }
-            RespCommandType::Get(cmd_vec[1].clone())
-        }
-        // return replconf
-        "replconf" => {
-            if cmd_vec.len() < 3 {
-                return RespCommandType::Error;
-            }
 
-            match cmd_vec[1].to_lowercase().as_str() {
-                "getack" => RespCommandType::Replconf(String::from("getack")),
-                _ => RespCommandType::Error,
+        let mut arg = String::new();
+        let mut in_dquotes = false;
+        let mut in_squotes = false;
+        loop {
+            let Some(c) = iter.next() else {
+                if in_dquotes || in_squotes {
+                    return Err(anyhow::anyhow!("unbalanced quotes in request"));
+                }
+                break;
+            };
+
+            if in_dquotes {
+                match c {
+                    '\\' => match iter.next() {
+                        // arguments are strings, so \xHH is limited to the ascii bytes
+                        Some('x') => {
+                            let hex: String = iter.by_ref().take(2).collect();
+                            let byte = match hex.as_bytes() {
+                                [h, l] if h.is_ascii_hexdigit() && l.is_ascii_hexdigit() => {
+                                    u8::from_str_radix(&hex, 16).ok()
+                                }
+                                _ => None,
+                            };
+                            match byte {
+                                Some(b) if b.is_ascii() => arg.push(b as char),
+                                _ => return Err(anyhow::anyhow!("invalid \\x escape in request")),
+                            }
+                        }
+                        Some('n') => arg.push('\n'),
+                        Some('r') => arg.push('\r'),
+                        Some('t') => arg.push('\t'),
+                        Some('b') => arg.push('\u{8}'),
+                        Some('a') => arg.push('\u{7}'),
+                        Some(e) => arg.push(e),
+                        None => return Err(anyhow::anyhow!("unbalanced quotes in request")),
+                    },
+                    '"' => {
+                        // closing quote must be followed by a space or nothing at all
+                        if iter.peek().is_some_and(|n| !n.is_whitespace()) {
+                            return Err(anyhow::anyhow!("unbalanced quotes in request"));
+                        }
+                        break;
+                    }
+                    _ => arg.push(c),
+                }
+            } else if in_squotes {
+                match c {
+                    '\\' if iter.peek() == Some(&'\'') => {
+                        iter.next();
+                        arg.push('\'');
+                    }
+                    '\'' => {
+                        if iter.peek().is_some_and(|n| !n.is_whitespace()) {
+                            return Err(anyhow::anyhow!("unbalanced quotes in request"));
+                        }
+                        break;
+                    }
+                    _ => arg.push(c),
+                }
+            } else {
+                match c {
+                    ' ' | '\n' | '\r' | '\t' | '\0' => break,
+                    '"' => in_dquotes = true,
+                    '\'' => in_squotes = true,
+                    _ => arg.push(c),
+                }
             }
         }
-        _ => RespCommandType::Error,
+        args.push(arg);
     }
 }
 
@@ -157,62 +177,53 @@ mod test {
     use super::*;
 
     #[test]
-    fn command_parser_test() {
-        unsafe {
-            let mut input1 = String::from("*1\r\n$4\r\nping\r\n");
-            let mut input2 = String::from("*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$5\r\nvalue\r\n");
-            let mut input3 = String::from(
-                "*5\r\n$3\r\nset\r\n$3\r\nkey\r\n$5\r\nvalue\r\n$2\r\npx\r\n$3\r\n100\r\n",
-            );
-            let mut input4 = String::from("*2\r\n$3\r\nget\r\n$3\r\nkey\r\n");
-            let mut input5 =
-                String::from("+FULLRESYNC 75cd7bc10c49047e0d163660f3b90625b1af31dc 0\r\n");
-            let mut input6 = String::from("$3\r\nabc\r\n");
-
-            let mut d1 = unsafe { input1.as_bytes_mut() };
-            let mut d2 = unsafe { input2.as_bytes_mut() };
-            let mut d3 = unsafe { input3.as_bytes_mut() };
-            let mut d4 = unsafe { input4.as_bytes_mut() };
-            let mut d5 = unsafe { input5.as_bytes_mut() };
-            let mut d6 = unsafe { input6.as_bytes_mut() };
-
-            assert_eq!(command_parser(&mut d1).unwrap()[0], RespCommandType::Ping);
-
-            assert_eq!(
-                command_parser(&mut d2).unwrap()[0],
-                RespCommandType::Set("key".to_string(), "value".to_string())
-            );
-
-            assert_eq!(
-                command_parser(&mut d3).unwrap()[0],
-                RespCommandType::SetPx("key".to_string(), "value".to_string(), 100)
-            );
-
-            assert_eq!(
-                command_parser(&mut d4).unwrap()[0],
-                RespCommandType::Get("key".to_string())
-            );
-
-            assert_eq!(command_parser(&mut d5).unwrap()[0], RespCommandType::Error);
-
-            assert_eq!(command_parser(&mut d6).unwrap()[0], RespCommandType::Error);
-        }
+    fn parse_repl_frame_test() {
+        let buf = b"+FULLRESYNC abc 0\r\n$3\r\nRDB*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$3\r\na b\r\n*1\r\n$4\r\nPI";
+
+        let (frame, len) = parse_repl_frame(buf).unwrap().unwrap();
+        assert_eq!(frame, ReplFrame::Simple("FULLRESYNC abc 0".to_string()));
+        let buf = &buf[len..];
+
+        let (frame, len) = parse_repl_frame(buf).unwrap().unwrap();
+        assert_eq!(frame, ReplFrame::Rdb(b"RDB".to_vec()));
+        let buf = &buf[len..];
+
+        let (frame, len) = parse_repl_frame(buf).unwrap().unwrap();
+        assert_eq!(
+            frame,
+            ReplFrame::Command(vec!["SET".into(), "k".into(), "a b".into()])
+        );
+        assert_eq!(len, 29);
+        let buf = &buf[len..];
+
+        // incomplete
+        assert!(parse_repl_frame(buf).unwrap().is_none());
+        assert!(parse_repl_frame(b"-ERR no\r\n").is_err());
     }
 
     #[test]
-    fn command_parser_test2() {
-        let mut input1 = String::from("*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\n123\r\n*3\r\n$3\r\nSET\r\n$3\r\nbar\r\n$3\r\n456\r\n");
-        let mut input2 = String::from("*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n");
-        let mut d1 = unsafe { input1.as_bytes_mut() };
-        let mut d2 = unsafe { input2.as_bytes_mut() };
-
-        let mut vec1 = Vec::new();
-        vec1.push(RespCommandType::Set("foo".to_string(), "123".to_string()));
-        vec1.push(RespCommandType::Set("bar".to_string(), "456".to_string()));
-        assert_eq!(command_parser(&mut d1).unwrap(), vec1,);
-
-        let mut vec2 = Vec::new();
-        vec2.push(RespCommandType::Replconf(("getack").to_string()));
-        assert_eq!(command_parser(&mut d2).unwrap(), vec2,);
+    fn split_inline_args_test() {
+        assert_eq!(split_inline_args("PING").unwrap(), vec!["PING"]);
+        assert_eq!(
+            split_inline_args("  SET  foo bar ").unwrap(),
+            vec!["SET", "foo", "bar"]
+        );
+        assert_eq!(
+            split_inline_args("set \"hello world\" 'it\\'s'").unwrap(),
+            vec!["set", "hello world", "it's"]
+        );
+        assert_eq!(
+            split_inline_args("echo \"a\\x41\\n\"").unwrap(),
+            vec!["echo", "aA\n"]
+        );
+        assert_eq!(split_inline_args("echo \"\"").unwrap(), vec!["echo", ""]);
+        // two hex digits of an ascii byte, nothing else
+        for escape in ["\\x+1", "\\x4", "\\xzz", "\\x80", "\\xff"] {
+            let line = format!("echo \"{}\"", escape);
+            assert!(split_inline_args(&line).is_err(), "{}", line);
+        }
+        assert!(split_inline_args("get \"foo").is_err());
+        assert!(split_inline_args("get \"foo\"bar").is_err());
+        assert!(split_inline_args("   ").unwrap().is_empty());
     }
 }
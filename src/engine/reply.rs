@@ -0,0 +1,174 @@
+use super::error::CommandError;
+use crate::store::stream_engine::StreamRange;
+
+// protocol negotiated by HELLO, RESP2 is the default for every new connection
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub enum RespProtocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+// typed reply returned by the handlers
+// it is only turned into bytes at the connection layer
+#[derive(PartialEq, Clone, Debug)]
+pub enum RespReply {
+    Null,
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Array(Vec<RespReply>),
+    Map(Vec<(RespReply, RespReply)>),
+    Push(Vec<RespReply>),
+}
+
+impl RespReply {
+    pub fn ok() -> Self {
+        RespReply::Simple("OK".to_string())
+    }
+
+    pub fn simple(s: impl Into<String>) -> Self {
+        RespReply::Simple(s.into())
+    }
+
+    pub fn error(s: impl Into<String>) -> Self {
+        RespReply::Error(s.into())
+    }
+
+    pub fn bulk(s: impl Into<String>) -> Self {
+        RespReply::Bulk(s.into())
+    }
+
+    // array of bulk strings, the shape of every command sent on the wire
+    pub fn bulk_array(vec: Vec<String>) -> Self {
+        RespReply::Array(vec.into_iter().map(RespReply::Bulk).collect())
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, RespReply::Error(_))
+    }
+
+    pub fn encode(&self, protocol: RespProtocol) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_to(&mut buf, protocol);
+        buf
+    }
+
+    fn encode_to(&self, buf: &mut Vec<u8>, protocol: RespProtocol) {
+        match self {
+            RespReply::Null => match protocol {
+                RespProtocol::Resp2 => buf.extend_from_slice(b"$-1\r\n"),
+                RespProtocol::Resp3 => buf.extend_from_slice(b"_\r\n"),
+            },
+            RespReply::Simple(s) => {
+                buf.extend_from_slice(format!("+{}\r\n", s).as_bytes());
+            }
+            RespReply::Error(s) => {
+                buf.extend_from_slice(format!("-{}\r\n", s).as_bytes());
+            }
+            RespReply::Integer(i) => {
+                buf.extend_from_slice(format!(":{}\r\n", i).as_bytes());
+            }
+            RespReply::Bulk(s) => {
+                buf.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+                buf.extend_from_slice(s.as_bytes());
+                buf.extend_from_slice(b"\r\n");
+            }
+            RespReply::Array(v) => {
+                buf.extend_from_slice(format!("*{}\r\n", v.len()).as_bytes());
+                for r in v {
+                    r.encode_to(buf, protocol);
+                }
+            }
+            RespReply::Map(v) => {
+                // RESP2 has no map type, flatten it to key value pairs
+                match protocol {
+                    RespProtocol::Resp2 => {
+                        buf.extend_from_slice(format!("*{}\r\n", v.len() * 2).as_bytes())
+                    }
+                    RespProtocol::Resp3 => {
+                        buf.extend_from_slice(format!("%{}\r\n", v.len()).as_bytes())
+                    }
+                }
+                for (k, val) in v {
+                    k.encode_to(buf, protocol);
+                    val.encode_to(buf, protocol);
+                }
+            }
+            RespReply::Push(v) => {
+                match protocol {
+                    RespProtocol::Resp2 => {
+                        buf.extend_from_slice(format!("*{}\r\n", v.len()).as_bytes())
+                    }
+                    RespProtocol::Resp3 => {
+                        buf.extend_from_slice(format!(">{}\r\n", v.len()).as_bytes())
+                    }
+                }
+                for r in v {
+                    r.encode_to(buf, protocol);
+                }
+            }
+        }
+    }
+}
+
+impl From<&anyhow::Error> for RespReply {
+    fn from(e: &anyhow::Error) -> Self {
+        match e.downcast_ref::<CommandError>() {
+            Some(err) => RespReply::Error(err.to_string()),
+            None => RespReply::Error(format!("ERR {}", e)),
+        }
+    }
+}
+
+impl From<&StreamRange> for RespReply {
+    // [id, [field, value, ...]]
+    fn from(r: &StreamRange) -> Self {
+        let mut hash = Vec::with_capacity(r.hash.len() * 2);
+        for (k, v) in r.hash.iter() {
+            hash.push(RespReply::bulk(k.clone()));
+            hash.push(RespReply::bulk(v.clone()));
+        }
+
+        RespReply::Array(vec![
+            RespReply::bulk(String::from(&r.stream_id)),
+            RespReply::Array(hash),
+        ])
+    }
+}
+
+pub fn stream_range_reply(v: &[StreamRange]) -> RespReply {
+    RespReply::Array(v.iter().map(RespReply::from).collect())
+}
+
+// XREAD wraps every stream in [key, [entries...]]
+pub fn xread_reply(streams: &[(String, Vec<StreamRange>)]) -> RespReply {
+    RespReply::Array(
+        streams
+            .iter()
+            .map(|(k, v)| RespReply::Array(vec![RespReply::bulk(k.clone()), stream_range_reply(v)]))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reply_encode() {
+        assert_eq!(RespReply::ok().encode(RespProtocol::Resp2), b"+OK\r\n");
+        assert_eq!(RespReply::Null.encode(RespProtocol::Resp2), b"$-1\r\n");
+        assert_eq!(RespReply::Null.encode(RespProtocol::Resp3), b"_\r\n");
+        assert_eq!(
+            RespReply::bulk_array(vec!["a".to_string(), "bc".to_string()])
+                .encode(RespProtocol::Resp2),
+            b"*2\r\n$1\r\na\r\n$2\r\nbc\r\n"
+        );
+
+        let map = RespReply::Map(vec![(RespReply::bulk("k"), RespReply::Integer(1))]);
+        assert_eq!(map.encode(RespProtocol::Resp2), b"*2\r\n$1\r\nk\r\n:1\r\n");
+        assert_eq!(map.encode(RespProtocol::Resp3), b"%1\r\n$1\r\nk\r\n:1\r\n");
+    }
+}
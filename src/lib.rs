@@ -1,3 +1,4 @@
+pub mod aof;
 pub mod engine;
 pub mod rdb;
 pub mod store;
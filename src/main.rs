@@ -1,14 +1,19 @@
-mod engine;
-mod rdb;
-mod store;
-
 use clap::{Arg, Command};
-use engine::connection::handle_connection;
-use rdb::config::RDBConfigOps;
-use rdb::loader::RDBLoader;
+use redis_starter_rust::aof::loader::AOFLoader;
+use redis_starter_rust::aof::writer::AOFWriter;
+use redis_starter_rust::engine::connection::handle_connection;
+use redis_starter_rust::rdb::config::RDBConfigOps;
+use redis_starter_rust::rdb::loader::RDBLoader;
+use redis_starter_rust::rdb::writer::RDBWriter;
+use redis_starter_rust::store::config::ConfigOps;
+use redis_starter_rust::store::engine::StoreEngine;
+use redis_starter_rust::store::master_engine::MasterEngine;
+use redis_starter_rust::store::slave_engine::SlaveEngine;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use store::engine::StoreEngine;
-use store::master_engine::MasterEngine;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::sleep;
 use tokio::{net::TcpListener, spawn};
 
 const PROGRAM_NAME: &str = "rs-redis";
@@ -51,6 +56,62 @@ async fn main() -> std::io::Result<()> {
                 .value_name("DBFILENAME")
                 .required(false),
         )
+        .arg(
+            Arg::new("save")
+                .help("save rules, pairs of seconds and changes e.g. \"3600 1 300 100\", \"\" disables saving")
+                .long("save")
+                .value_name("RULES")
+                .required(false),
+        )
+        .arg(
+            Arg::new("appendonly")
+                .help("log the writes to the append only file, yes or no")
+                .long("appendonly")
+                .value_name("YES|NO")
+                .required(false),
+        )
+        .arg(
+            Arg::new("appendfilename")
+                .help("filename of the append only file")
+                .long("appendfilename")
+                .value_name("FILENAME")
+                .required(false),
+        )
+        .arg(
+            Arg::new("appenddirname")
+                .help("directory of the append only files in dir")
+                .long("appenddirname")
+                .value_name("DIRNAME")
+                .required(false),
+        )
+        .arg(
+            Arg::new("aof-use-rdb-preamble")
+                .help("write the base of the append only file as an rdb, yes or no")
+                .long("aof-use-rdb-preamble")
+                .value_name("YES|NO")
+                .required(false),
+        )
+        .arg(
+            Arg::new("appendfsync")
+                .help("fsync of the append only file: always, everysec or no")
+                .long("appendfsync")
+                .value_name("POLICY")
+                .required(false),
+        )
+        .arg(
+            Arg::new("aof-load-truncated")
+                .help("load a truncated append only file, yes or no")
+                .long("aof-load-truncated")
+                .value_name("YES|NO")
+                .required(false),
+        )
+        .arg(
+            Arg::new("repl-backlog-size")
+                .help("size of the replication backlog, e.g. 1mb")
+                .long("repl-backlog-size")
+                .value_name("SIZE")
+                .required(false),
+        )
         .get_matches();
 
     let binding = DEFAULT_PORT.to_string();
@@ -70,39 +131,128 @@ async fn main() -> std::io::Result<()> {
         db.set_filename(filename.clone());
     }
 
-    // load RDB
-    let full_path = format!("{}/{}", db.get_dir(), db.get_filename());
-    let _ = db.load(full_path).unwrap_or(false);
+    db.stats.loading.store(true, Ordering::Relaxed);
+    for name in [
+        "save",
+        "appendonly",
+        "appendfilename",
+        "appenddirname",
+        "appendfsync",
+        "aof-load-truncated",
+        "aof-use-rdb-preamble",
+        "repl-backlog-size",
+    ] {
+        if let Some(value) = args.get_one::<String>(name) {
+            if let Err(e) = db.config_set(name, value) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // the aof holds the latest writes, the rdb is only loaded without it
+    let appendonly = db.config.read().unwrap().appendonly;
+    let manifest = match appendonly {
+        true => db.load_aof().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }),
+        false => None,
+    };
+    if manifest.is_none() {
+        // load RDB
+        let full_path = format!("{}/{}", db.get_dir(), db.get_filename());
+        let _ = db.load(full_path).unwrap_or(false);
+    }
+    if appendonly {
+        let opened = match manifest {
+            Some(manifest) => db.open_aof(manifest),
+            None => db.start_aof(),
+        };
+        if let Err(e) = opened {
+            eprintln!("can't open the append only file: {}", e);
+            std::process::exit(1);
+        }
+    }
+    db.stats.loading.store(false, Ordering::Relaxed);
 
     // collect replicaof argument
     if let Some(replica_info) = args.get_many::<String>("replicaof") {
         let values: Vec<&String> = replica_info.collect();
         let replica_host = format!("{}:{}", values[0], values[1]);
-        db.set_replica(replica_host);
-
-        let db = db.clone();
-        spawn(async move {
-            let _ = db.handshake_to_master().await;
-        });
-    } else {
-        let healthcheck_db = db.clone();
-        spawn(async move {
-            let _ = healthcheck_db.healthcheck_to_slave().await;
-        });
+        if let Err(e) = db.set_replica(replica_host) {
+            eprintln!("can't replicate: {}", e);
+            std::process::exit(1);
+        }
+        db.start_master_link();
     }
 
+    // pings the replicas whenever we are a master, the role can change at runtime
+    let healthcheck_db = db.clone();
+    spawn(async move {
+        let _ = healthcheck_db.healthcheck_to_slave().await;
+    });
+
     // reaper thread
     let reaper_db = db.clone();
     spawn(async move {
         reaper_db.expired_reaper().await;
     });
 
-    while let Ok((socket, addr)) = listener.accept().await {
-        let cdb = db.clone();
-        // let std_stream = socket.into_std()?;
-        // let stream = Arc::new(Mutex::new(socket));
-        tokio::spawn(async move { handle_connection(&cdb, socket, addr).await });
+    // fsync of the aof under everysec
+    let aof_db = db.clone();
+    spawn(async move {
+        aof_db.aof_cron().await;
+    });
+
+    // snapshots on the save rules
+    let cron_db = db.clone();
+    spawn(async move {
+        cron_db.save_cron().await;
+    });
+
+    let accept_loop = async {
+        while let Ok((socket, addr)) = listener.accept().await {
+            let cdb = db.clone();
+            // let std_stream = socket.into_std()?;
+            // let stream = Arc::new(Mutex::new(socket));
+            tokio::spawn(async move { handle_connection(&cdb, socket, addr).await });
+        }
+    };
+    tokio::select! {
+        _ = accept_loop => {}
+        _ = shutdown_signal() => {
+            println!("shutting down");
+            db.fsync_aof();
+            if !db.config.read().unwrap().save_params.is_empty() {
+                save_before_exit(&db).await;
+            }
+        }
     }
 
     Ok(())
 }
+
+// SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+// a running background save is waited for, its snapshot may already be stale
+async fn save_before_exit(db: &Arc<StoreEngine>) {
+    while !db.stats.begin_save() {
+        sleep(Duration::from_millis(10)).await;
+    }
+    let saved = db.save();
+    if let Err(e) = &saved {
+        eprintln!("err: saving before exit: {}", e);
+    }
+    db.stats.end_save(saved.is_ok());
+    if saved.is_err() {
+        std::process::exit(1);
+    }
+}
@@ -10,18 +10,18 @@ pub trait RDBConfigOps {
 
 impl RDBConfigOps for StoreEngine {
     fn set_dir(&self, dir: String) {
-        (*self).rdb_info.lock().unwrap().dir = dir.clone();
+        self.rdb_info.lock().unwrap().dir = dir.clone();
     }
 
     fn set_filename(&self, filename: String) {
-        (*self).rdb_info.lock().unwrap().filename = filename.clone();
+        self.rdb_info.lock().unwrap().filename = filename.clone();
     }
 
     fn get_dir(&self) -> String {
-        (*self).rdb_info.lock().unwrap().dir.clone()
+        self.rdb_info.lock().unwrap().dir.clone()
     }
 
     fn get_filename(&self) -> String {
-        (*self).rdb_info.lock().unwrap().filename.clone()
+        self.rdb_info.lock().unwrap().filename.clone()
     }
 }
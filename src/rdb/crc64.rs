@@ -0,0 +1,81 @@
+use std::io::Read;
+
+// crc-64-jones as redis computes the rdb checksum: reflected, no final xor
+// 0x95ac9329ac4bc9b5 is the reflected form of the polynomial 0xad93d23594c935a9
+const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+const fn make_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static TABLE: [u64; 256] = make_table();
+
+// continue the checksum crc over data, start with 0
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    for b in data {
+        crc = TABLE[((crc ^ *b as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+// checksum of everything read through it
+pub struct Crc64Reader<R> {
+    inner: R,
+    crc: u64,
+}
+
+impl<R: Read> Crc64Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Crc64Reader { inner, crc: 0 }
+    }
+
+    pub fn crc(&self) -> u64 {
+        self.crc
+    }
+
+    // the trailer isn't part of the checksum
+    pub fn inner(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+impl<R: Read> Read for Crc64Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc = crc64(self.crc, &buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crc64() {
+        // the check value of crc-64-jones, also in the tests of redis
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+        assert_eq!(crc64(crc64(0, b"1234"), b"56789"), 0xe9c6d914c4b8d9ca);
+
+        let mut reader = Crc64Reader::new(&b"123456789"[..]);
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(reader.crc(), 0xe9c6d914c4b8d9ca);
+    }
+}
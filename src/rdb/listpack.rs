@@ -0,0 +1,243 @@
+use anyhow::Result;
+
+// listpack, the serialized container redis uses for the stream nodes
+// <total bytes u32> <num elements u16> <entry> ... <0xFF>
+// every entry is <encoding + data> <backlen>
+
+const LP_HEADER_SIZE: usize = 6;
+const LP_EOF: u8 = 0xFF;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ListpackEntry {
+    Int(i64),
+    Str(Vec<u8>),
+}
+
+impl ListpackEntry {
+    pub fn as_int(&self) -> Result<i64> {
+        match self {
+            ListpackEntry::Int(i) => Ok(*i),
+            ListpackEntry::Str(s) => Ok(std::str::from_utf8(s)?.parse::<i64>()?),
+        }
+    }
+
+    pub fn as_string(&self) -> Result<String> {
+        match self {
+            ListpackEntry::Int(i) => Ok(i.to_string()),
+            ListpackEntry::Str(s) => Ok(String::from_utf8(s.clone())?),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Listpack {
+    entries: Vec<u8>,
+    count: usize,
+}
+
+impl Listpack {
+    pub fn new() -> Self {
+        Listpack::default()
+    }
+
+    pub fn append_int(&mut self, v: i64) {
+        let mut enc = Vec::with_capacity(9);
+        if (0..=127).contains(&v) {
+            enc.push(v as u8);
+        } else if (-4096..=4095).contains(&v) {
+            let v = (v as u64) & 0x1FFF;
+            enc.push(0xC0 | (v >> 8) as u8);
+            enc.push(v as u8);
+        } else if (i16::MIN as i64..=i16::MAX as i64).contains(&v) {
+            enc.push(0xF1);
+            enc.extend_from_slice(&(v as i16).to_le_bytes());
+        } else if (-(1 << 23)..(1 << 23)).contains(&v) {
+            enc.push(0xF2);
+            enc.extend_from_slice(&(v as i32).to_le_bytes()[..3]);
+        } else if (i32::MIN as i64..=i32::MAX as i64).contains(&v) {
+            enc.push(0xF3);
+            enc.extend_from_slice(&(v as i32).to_le_bytes());
+        } else {
+            enc.push(0xF4);
+            enc.extend_from_slice(&v.to_le_bytes());
+        }
+        self.push_entry(enc);
+    }
+
+    pub fn append_str(&mut self, s: &[u8]) {
+        let len = s.len();
+        let mut enc = Vec::with_capacity(len + 5);
+        if len < 64 {
+            enc.push(0x80 | len as u8);
+        } else if len < 4096 {
+            enc.push(0xE0 | (len >> 8) as u8);
+            enc.push(len as u8);
+        } else {
+            enc.push(0xF0);
+            enc.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+        enc.extend_from_slice(s);
+        self.push_entry(enc);
+    }
+
+    fn push_entry(&mut self, enc: Vec<u8>) {
+        let len = enc.len();
+        self.entries.extend_from_slice(&enc);
+        self.entries.extend_from_slice(&encode_backlen(len));
+        self.count += 1;
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        let total = LP_HEADER_SIZE + self.entries.len() + 1;
+        let mut buf = Vec::with_capacity(total);
+        buf.extend_from_slice(&(total as u32).to_le_bytes());
+        // the count saturates, readers have to walk the entries then
+        buf.extend_from_slice(&(self.count.min(u16::MAX as usize) as u16).to_le_bytes());
+        buf.extend_from_slice(&self.entries);
+        buf.push(LP_EOF);
+        buf
+    }
+}
+
+// the length of the entry is stored backwards 7 bits per byte
+fn encode_backlen(len: usize) -> Vec<u8> {
+    if len <= 127 {
+        vec![len as u8]
+    } else if len < 16383 {
+        vec![(len >> 7) as u8, (len & 127) as u8 | 128]
+    } else if len < 2097151 {
+        vec![
+            (len >> 14) as u8,
+            ((len >> 7) & 127) as u8 | 128,
+            (len & 127) as u8 | 128,
+        ]
+    } else if len < 268435455 {
+        vec![
+            (len >> 21) as u8,
+            ((len >> 14) & 127) as u8 | 128,
+            ((len >> 7) & 127) as u8 | 128,
+            (len & 127) as u8 | 128,
+        ]
+    } else {
+        vec![
+            (len >> 28) as u8,
+            ((len >> 21) & 127) as u8 | 128,
+            ((len >> 14) & 127) as u8 | 128,
+            ((len >> 7) & 127) as u8 | 128,
+            (len & 127) as u8 | 128,
+        ]
+    }
+}
+
+fn backlen_size(len: usize) -> usize {
+    encode_backlen(len).len()
+}
+
+pub fn decode_listpack(buf: &[u8]) -> Result<Vec<ListpackEntry>> {
+    if buf.len() < LP_HEADER_SIZE + 1 {
+        return Err(anyhow::anyhow!("listpack too short"));
+    }
+
+    let mut entries = Vec::new();
+    let mut pos = LP_HEADER_SIZE;
+    let byte = |p: usize| -> Result<u8> {
+        buf.get(p)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("listpack truncated"))
+    };
+    let slice = |from: usize, len: usize| -> Result<&[u8]> {
+        buf.get(from..from + len)
+            .ok_or_else(|| anyhow::anyhow!("listpack truncated"))
+    };
+
+    loop {
+        let b = byte(pos)?;
+        if b == LP_EOF {
+            break;
+        }
+
+        let (entry, enc_len) = if b & 0x80 == 0 {
+            (ListpackEntry::Int((b & 0x7F) as i64), 1)
+        } else if b & 0xC0 == 0x80 {
+            let len = (b & 0x3F) as usize;
+            (ListpackEntry::Str(slice(pos + 1, len)?.to_vec()), 1 + len)
+        } else if b & 0xE0 == 0xC0 {
+            let v = (((b & 0x1F) as u16) << 8) | byte(pos + 1)? as u16;
+            // sign extend the 13 bits
+            let v = ((v << 3) as i16 >> 3) as i64;
+            (ListpackEntry::Int(v), 2)
+        } else if b & 0xF0 == 0xE0 {
+            let len = (((b & 0x0F) as usize) << 8) | byte(pos + 1)? as usize;
+            (ListpackEntry::Str(slice(pos + 2, len)?.to_vec()), 2 + len)
+        } else {
+            match b {
+                0xF0 => {
+                    let len = u32::from_le_bytes(slice(pos + 1, 4)?.try_into()?) as usize;
+                    (ListpackEntry::Str(slice(pos + 5, len)?.to_vec()), 5 + len)
+                }
+                0xF1 => {
+                    let v = i16::from_le_bytes(slice(pos + 1, 2)?.try_into()?);
+                    (ListpackEntry::Int(v as i64), 3)
+                }
+                0xF2 => {
+                    let s = slice(pos + 1, 3)?;
+                    let v = i32::from_le_bytes([0, s[0], s[1], s[2]]) >> 8;
+                    (ListpackEntry::Int(v as i64), 4)
+                }
+                0xF3 => {
+                    let v = i32::from_le_bytes(slice(pos + 1, 4)?.try_into()?);
+                    (ListpackEntry::Int(v as i64), 5)
+                }
+                0xF4 => {
+                    let v = i64::from_le_bytes(slice(pos + 1, 8)?.try_into()?);
+                    (ListpackEntry::Int(v), 9)
+                }
+                _ => return Err(anyhow::anyhow!("invalid listpack encoding {:#x}", b)),
+            }
+        };
+
+        entries.push(entry);
+        pos += enc_len + backlen_size(enc_len);
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_listpack_roundtrip() {
+        let ints = [
+            0,
+            127,
+            128,
+            -1,
+            4095,
+            -4096,
+            30000,
+            -30000,
+            1 << 20,
+            1 << 30,
+            1 << 40,
+        ];
+        let long_str = vec![b'x'; 5000];
+
+        let mut lp = Listpack::new();
+        for i in ints {
+            lp.append_int(i);
+        }
+        lp.append_str(b"field");
+        lp.append_str(&[b'y'; 200]);
+        lp.append_str(&long_str);
+
+        let entries = decode_listpack(&lp.into_bytes()).unwrap();
+        assert_eq!(entries.len(), ints.len() + 3);
+        for (i, v) in ints.iter().enumerate() {
+            assert_eq!(entries[i], ListpackEntry::Int(*v));
+        }
+        assert_eq!(entries[ints.len()], ListpackEntry::Str(b"field".to_vec()));
+        assert_eq!(entries[ints.len() + 2], ListpackEntry::Str(long_str));
+    }
+}
@@ -1,5 +1,8 @@
-use super::{length_encode_code, op_code, value_type};
-use crate::store::engine::StoreEngine;
+use super::crc64::Crc64Reader;
+use super::listpack::{decode_listpack, ListpackEntry};
+use super::{length_encode_code, op_code, stream_item_flag, value_type};
+use crate::store::engine::{StoreEngine, StreamEntries, StreamID};
+use crate::store::stream_engine::StreamEngine;
 use anyhow::Result;
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
 use std::collections::HashMap;
@@ -7,7 +10,7 @@ use std::fs::File;
 use std::io::{BufReader, Read};
 use std::str;
 
-pub const RDB_MAGIC: &'static str = "REDIS";
+pub const RDB_MAGIC: &str = "REDIS";
 // enum RDBParseResult {
 //     Skip,
 //     Ok,
@@ -48,8 +51,14 @@ pub trait RDBLoader {
     fn verify_expire_ms<R: Read>(&self, reader: &mut R) -> Result<KeyType>;
 
     fn parse_value_encoding<R: Read>(&self, reader: &mut R) -> Result<String>;
-    fn parse_length_encoding<R: Read>(&self, reader: &mut R) -> Result<(u32, bool)>;
+    fn parse_length_encoding<R: Read>(&self, reader: &mut R) -> Result<(u64, bool)>;
     fn parse_string_encoding<R: Read>(&self, reader: &mut R) -> Result<String>;
+    fn parse_raw_string<R: Read>(&self, reader: &mut R) -> Result<Vec<u8>>;
+    fn parse_stream<R: Read>(
+        &self,
+        reader: &mut R,
+        value_type: u8,
+    ) -> Result<(StreamEntries, StreamID)>;
 }
 
 impl RDBLoader for StoreEngine {
@@ -62,6 +71,10 @@ impl RDBLoader for StoreEngine {
     }
 
     fn parse<R: Read>(&self, reader: &mut R) -> Result<bool> {
+        // everything up to EOF is covered by the checksum in the trailer
+        let mut checked = Crc64Reader::new(reader);
+        let reader = &mut checked;
+
         // chain of rules to parse the RDB file
         if !self.verify_magic(reader) {
             return Err(anyhow::anyhow!("wrong magic number"));
@@ -88,14 +101,14 @@ impl RDBLoader for StoreEngine {
                     // println!("aux: {:?}", aux);
                 }
                 op_code::EXPIRETIME => {
-                    if cur_expire_hash_size <= 0 {
+                    if cur_expire_hash_size == 0 {
                         return Err(anyhow::anyhow!("wrong exipred length"));
                     }
                     // println!("expiretime");
                     key_type = self.verify_expire_sec(reader)?;
                 }
                 op_code::EXPIRETIME_MS => {
-                    if cur_expire_hash_size <= 0 {
+                    if cur_expire_hash_size == 0 {
                         return Err(anyhow::anyhow!("wrong exipred length"));
                     }
                     // println!("expiretime_ms");
@@ -103,22 +116,16 @@ impl RDBLoader for StoreEngine {
                 }
                 op_code::RESIZEDB => {
                     let state = self.verify_resize_db(reader)?;
-                    match state.parse_type {
-                        RDBParseType::ResizeDB((l1, l2)) => {
-                            cur_hash_size = l1;
-                            cur_expire_hash_size = l2;
-                        }
-                        _ => {}
+                    if let RDBParseType::ResizeDB((l1, l2)) = state.parse_type {
+                        cur_hash_size = l1;
+                        cur_expire_hash_size = l2;
                     }
                     // println!("resizedb {} {}", cur_hash_size, cur_expire_hash_size);
                 }
                 op_code::SELECTDB => {
                     let state = self.verify_db_selector(reader)?;
-                    match state.parse_type {
-                        RDBParseType::DB(num) => {
-                            _curdb = num;
-                        }
-                        _ => {}
+                    if let RDBParseType::DB(num) = state.parse_type {
+                        _curdb = num;
                     }
                     // println!("selectdb {}", curdb);
                 }
@@ -136,32 +143,58 @@ impl RDBLoader for StoreEngine {
                     // put k,v into  db
                     match key_type {
                         KeyType::ExpireSec(s) => {
-                            let ttl: u128 = (s * 1_000_000).into();
+                            let ttl = s as u128 * 1000;
                             self.set_with_expire_exact(key, value, ttl);
-                            cur_expire_hash_size -= 1;
+                            cur_expire_hash_size = cur_expire_hash_size.saturating_sub(1);
                         }
                         // millisecond
                         KeyType::ExpireMs(ttl) => {
                             self.set_with_expire_exact(key, value, ttl as u128);
-                            cur_expire_hash_size -= 1;
+                            cur_expire_hash_size = cur_expire_hash_size.saturating_sub(1);
                         }
                         KeyType::Normal => {
                             self.set(key, value);
-                            cur_hash_size -= 1;
+                            cur_hash_size = cur_hash_size.saturating_sub(1);
                         }
                     }
                     // assume we are usually Key without expiration
                     key_type = KeyType::Normal;
                 }
+                value_type::STREAM_LISTPACKS
+                | value_type::STREAM_LISTPACKS_2
+                | value_type::STREAM_LISTPACKS_3 => {
+                    let key = self.parse_string_encoding(reader)?;
+                    let (entries, last_id) = self.parse_stream(reader, next_op)?;
+                    self.set_stream(&key, entries, last_id);
+
+                    match key_type {
+                        KeyType::ExpireSec(s) => {
+                            self.expire_at(&key, s as u128 * 1000);
+                        }
+                        KeyType::ExpireMs(ttl) => {
+                            self.expire_at(&key, ttl as u128);
+                        }
+                        KeyType::Normal => {}
+                    }
+                    key_type = KeyType::Normal;
+                }
                 1_u8..=14_u8 => {
                     // parsing type
                     // println!("unsupported type");
                 }
-                15_u8..=249_u8 => {
+                16_u8..=249_u8 => {
                     // println!("no such op code");
                 }
             }
         }
+
+        // a zero checksum means the checksum was disabled, old versions have no trailer
+        let crc = checked.crc();
+        if let Ok(expected) = checked.inner().read_u64::<LittleEndian>() {
+            if expected != 0 && expected != crc {
+                return Err(anyhow::anyhow!("wrong checksum"));
+            }
+        }
         Ok(true)
     }
 
@@ -230,7 +263,10 @@ impl RDBLoader for StoreEngine {
         let expire_hashtable_size = self.parse_length_encoding(reader)?;
 
         Ok(RDBParseState {
-            parse_type: RDBParseType::ResizeDB((hashtable_size.0, expire_hashtable_size.0)),
+            parse_type: RDBParseType::ResizeDB((
+                hashtable_size.0 as u32,
+                expire_hashtable_size.0 as u32,
+            )),
             is_finished: true,
         })
     }
@@ -246,28 +282,33 @@ impl RDBLoader for StoreEngine {
         Ok(KeyType::ExpireMs(ttl))
     }
 
-    fn parse_length_encoding<R: Read>(&self, reader: &mut R) -> Result<(u32, bool)> {
+    fn parse_length_encoding<R: Read>(&self, reader: &mut R) -> Result<(u64, bool)> {
         let enc_type = reader.read_u8()?;
-        let length: u32;
+        let length: u64;
         let mut is_encode = false;
 
         // take first 2 bits
         match (enc_type & 0xC0) >> 6 {
             length_encode_code::SIX_BITS => {
-                length = (enc_type & 0x3F) as u32;
+                length = (enc_type & 0x3F) as u64;
             }
             length_encode_code::FORTEEN_BITS => {
                 let next_byte = reader.read_u8()?;
-                length = (((enc_type & 0x3F) as u32) << 8) as u32 | next_byte as u32;
+                length = (((enc_type & 0x3F) as u64) << 8) | next_byte as u64;
             }
             // least byte isn't the lowest
-            length_encode_code::FOUR_BYTES => {
-                let next_4_bytes = reader.read_u32::<BigEndian>()?;
-                length = next_4_bytes;
-            }
+            length_encode_code::FOUR_BYTES => match enc_type {
+                length_encode_code::LEN_32BIT => {
+                    length = reader.read_u32::<BigEndian>()? as u64;
+                }
+                length_encode_code::LEN_64BIT => {
+                    length = reader.read_u64::<BigEndian>()?;
+                }
+                _ => return Err(anyhow::anyhow!("parse_length_encoding err")),
+            },
             length_encode_code::ENCODED => {
                 is_encode = true;
-                length = (enc_type & 0x3F) as u32;
+                length = (enc_type & 0x3F) as u64;
             }
             _ => return Err(anyhow::anyhow!("parse_length_encoding err")),
         }
@@ -304,29 +345,97 @@ impl RDBLoader for StoreEngine {
             };
         }
 
-        let mut buf = [0; 1024];
-        // take limits of the length
-        let mut handle = reader.take(length as u64);
-        let mut remain = length;
-        let mut s = String::new();
-        loop {
-            if remain <= 0 {
-                break;
+        let buf = read_string(reader, length)?;
+        Ok(str::from_utf8(&buf)?.to_string())
+    }
+
+    // binary string, e.g. a listpack or a stream node key
+    fn parse_raw_string<R: Read>(&self, reader: &mut R) -> Result<Vec<u8>> {
+        let (length, encoding) = self.parse_length_encoding(reader)?;
+        if encoding {
+            return Err(anyhow::anyhow!("encoded binary string not supported"));
+        }
+
+        read_string(reader, length)
+    }
+
+    // stream saved as listpack nodes, consumer groups aren't supported
+    fn parse_stream<R: Read>(
+        &self,
+        reader: &mut R,
+        value_type: u8,
+    ) -> Result<(StreamEntries, StreamID)> {
+        let mut entries = StreamEntries::new();
+
+        let (nodes, _) = self.parse_length_encoding(reader)?;
+        for _ in 0..nodes {
+            let node_key = self.parse_raw_string(reader)?;
+            if node_key.len() != 16 {
+                return Err(anyhow::anyhow!("wrong stream node key"));
+            }
+            let master_ms = u64::from_be_bytes(node_key[..8].try_into()?);
+            let master_seq = u64::from_be_bytes(node_key[8..].try_into()?);
+
+            let lp = decode_listpack(&self.parse_raw_string(reader)?)?;
+            let mut iter = lp.iter();
+            let mut next = || -> Result<&ListpackEntry> {
+                iter.next()
+                    .ok_or_else(|| anyhow::anyhow!("stream listpack truncated"))
+            };
+
+            // master entry: count, deleted, fields, 0
+            let count = next()?.as_int()?;
+            let deleted = next()?.as_int()?;
+            let num_master_fields = next()?.as_int()?;
+            let mut master_fields = Vec::new();
+            for _ in 0..num_master_fields {
+                master_fields.push(next()?.as_string()?);
             }
+            next()?;
 
-            match handle.read(&mut buf) {
-                Ok(n) => {
-                    remain -= n as u32;
-                    let s1 = str::from_utf8(&buf[..n])?;
-                    s = format!("{}{}", s, s1);
+            for _ in 0..count + deleted {
+                let flags = next()?.as_int()?;
+                let ms = master_ms.wrapping_add(next()?.as_int()? as u64);
+                let seq = master_seq.wrapping_add(next()?.as_int()? as u64);
+
+                let mut hash = std::collections::HashMap::new();
+                if flags & stream_item_flag::SAMEFIELDS != 0 {
+                    for f in master_fields.iter() {
+                        hash.insert(f.clone(), next()?.as_string()?);
+                    }
+                } else {
+                    let num_fields = next()?.as_int()?;
+                    for _ in 0..num_fields {
+                        let f = next()?.as_string()?;
+                        hash.insert(f, next()?.as_string()?);
+                    }
                 }
-                Err(_) => {
-                    return Err(anyhow::anyhow!("error when parsing string"));
+                // lp-count
+                next()?;
+
+                if flags & stream_item_flag::DELETED == 0 {
+                    entries.insert(StreamID::new(ms as u128, seq), hash);
                 }
             }
         }
 
-        Ok(s)
+        let _length = self.parse_length_encoding(reader)?;
+        let last_ms = self.parse_length_encoding(reader)?.0;
+        let last_seq = self.parse_length_encoding(reader)?.0;
+
+        if value_type != value_type::STREAM_LISTPACKS {
+            // first id, max deleted id and entries added
+            for _ in 0..5 {
+                self.parse_length_encoding(reader)?;
+            }
+        }
+
+        let (cgroups, _) = self.parse_length_encoding(reader)?;
+        if cgroups > 0 {
+            return Err(anyhow::anyhow!("stream consumer groups not supported"));
+        }
+
+        Ok((entries, StreamID::new(last_ms as u128, last_seq)))
     }
 
     fn parse_value_encoding<R: Read>(&self, reader: &mut R) -> Result<String> {
@@ -339,6 +448,19 @@ impl RDBLoader for StoreEngine {
     }
 }
 
+// the length comes from the file, the buffer only grows with what is actually read
+fn read_string<R: Read>(reader: &mut R, length: u64) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.by_ref().take(length).read_to_end(&mut buf)?;
+    if buf.len() as u64 != length {
+        return Err(anyhow::anyhow!(
+            "unexpected end of the rdb reading a string of {} bytes",
+            length
+        ));
+    }
+    Ok(buf)
+}
+
 impl Default for RDBParseState {
     fn default() -> Self {
         RDBParseState {
@@ -358,7 +480,7 @@ mod test {
         let file = "./files/empty_database.rdb";
         let engine = StoreEngine::new();
 
-        assert_eq!(engine.load(file.to_owned()).unwrap_or(false), true);
+        assert!(engine.load(file.to_owned()).unwrap_or(false));
     }
 
     #[test]
@@ -367,4 +489,27 @@ mod test {
         let engine = StoreEngine::new();
         let _ = engine.load(file.to_owned());
     }
+
+    #[test]
+    fn test_parse_flushed() {
+        let engine = StoreEngine::new();
+        engine.set("stale".to_string(), "1".to_string());
+
+        let rdb = std::fs::read("./files/one_key.rdb").unwrap();
+        engine.flush_all();
+        assert!(engine.parse(&mut std::io::Cursor::new(rdb)).unwrap());
+        assert_eq!(engine.get_keys(), vec!["foo".to_string()]);
+        assert_eq!(engine.get("foo"), Some("bar".to_string()));
+    }
+
+    #[test]
+    fn test_string_longer_than_file() {
+        // a key claiming u64::MAX bytes is refused instead of allocated
+        let mut rdb = b"REDIS0011\xfe\x00\xfb\x01\x00\x00\x81".to_vec();
+        rdb.extend_from_slice(&u64::MAX.to_be_bytes());
+        rdb.extend_from_slice(b"foo");
+        let engine = StoreEngine::new();
+        let err = engine.parse(&mut std::io::Cursor::new(rdb)).unwrap_err();
+        assert!(err.to_string().contains("unexpected end"));
+    }
 }
@@ -1,5 +1,8 @@
 pub mod config;
+pub mod crc64;
+pub mod listpack;
 pub mod loader;
+pub mod writer;
 
 pub struct RdbConf {
     dir: String,
@@ -35,6 +38,9 @@ pub mod value_type {
     pub const SORTED_SET_ZIPLIST: u8 = 12;
     pub const HASH_ZIPLIST: u8 = 13;
     pub const LIST_QUICKLIST: u8 = 14;
+    pub const STREAM_LISTPACKS: u8 = 15;
+    pub const STREAM_LISTPACKS_2: u8 = 19;
+    pub const STREAM_LISTPACKS_3: u8 = 21;
 }
 
 pub mod op_code {
@@ -53,4 +59,14 @@ pub mod length_encode_code {
     pub const FORTEEN_BITS: u8 = 1;
     pub const FOUR_BYTES: u8 = 2;
     pub const ENCODED: u8 = 3;
+
+    // full bytes following the 10 prefix
+    pub const LEN_32BIT: u8 = 0x80;
+    pub const LEN_64BIT: u8 = 0x81;
+}
+
+// flags of the entries inside a stream listpack
+pub mod stream_item_flag {
+    pub const DELETED: i64 = 1;
+    pub const SAMEFIELDS: i64 = 2;
 }
@@ -0,0 +1,321 @@
+use super::config::RDBConfigOps;
+use super::crc64::crc64;
+use super::listpack::Listpack;
+use super::loader::RDB_MAGIC;
+use super::{length_encode_code, op_code, value_type};
+use crate::engine::REDIS_VERSION;
+use crate::store::engine::{now_ms, StoreEngine, StreamEntries, StreamID};
+use crate::store::stats::{unix_time, ServerStats};
+use crate::store::stream_engine::StreamEngine;
+use anyhow::Result;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+// the save rules are checked ten times a second like the redis cron
+const SAVE_CRON_PERIOD: Duration = Duration::from_millis(100);
+// seconds before a failed background save is tried again
+const SAVE_RETRY_DELAY: u64 = 5;
+
+pub const RDB_VERSION: &str = "0011";
+
+// the keyspace as it was when the snapshot was taken
+pub struct RdbSnapshot {
+    used_mem: u64,
+    strings: Vec<(String, String, Option<u128>)>,
+    streams: Vec<(String, StreamEntries, StreamID, Option<u128>)>,
+}
+
+impl RdbSnapshot {
+    // aof_base marks it as the base of an aof, as its aof-base aux field tells
+    pub fn encode(&self, aof_base: bool) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(RDB_MAGIC.as_bytes());
+        buf.extend_from_slice(RDB_VERSION.as_bytes());
+
+        write_aux(&mut buf, "redis-ver", REDIS_VERSION);
+        write_aux(&mut buf, "redis-bits", &usize::BITS.to_string());
+        write_aux(&mut buf, "ctime", &(now_ms() / 1000).to_string());
+        write_aux(&mut buf, "used-mem", &self.used_mem.to_string());
+        write_aux(&mut buf, "aof-base", if aof_base { "1" } else { "0" });
+
+        let total = self.strings.len() + self.streams.len();
+        let expires = self.strings.iter().filter(|s| s.2.is_some()).count()
+            + self.streams.iter().filter(|s| s.3.is_some()).count();
+
+        buf.push(op_code::SELECTDB);
+        write_length(&mut buf, 0);
+        buf.push(op_code::RESIZEDB);
+        write_length(&mut buf, total as u64);
+        write_length(&mut buf, expires as u64);
+
+        for (key, value, at) in &self.strings {
+            write_expire(&mut buf, *at);
+            buf.push(value_type::STRING);
+            write_string(&mut buf, key.as_bytes());
+            write_string(&mut buf, value.as_bytes());
+        }
+
+        for (key, entries, last_id, at) in &self.streams {
+            write_expire(&mut buf, *at);
+            buf.push(value_type::STREAM_LISTPACKS);
+            write_string(&mut buf, key.as_bytes());
+            write_stream(&mut buf, entries, last_id);
+        }
+
+        buf.push(op_code::EOF);
+        let checksum = crc64(0, &buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        buf
+    }
+}
+
+pub trait RDBWriter {
+    // snapshot of the whole keyspace in the rdb format
+    // aof_base marks it as the base of an aof, as its aof-base aux field tells
+    fn dump(&self, aof_base: bool) -> Vec<u8>;
+    // copy of the keyspace to encode later, out of whatever lock it was taken under
+    fn snapshot(&self) -> RdbSnapshot;
+    // write the snapshot to a temp file in dir and rename it over dir/dbfilename
+    // the previous file stays in place until the new one is complete
+    fn save(&self) -> Result<()>;
+    // save in a blocking task, false if a save is already running
+    fn bgsave(self: &Arc<Self>) -> bool;
+    // starts a background save whenever one of the save rules fires
+    fn save_cron(self: &Arc<Self>) -> impl std::future::Future<Output = ()> + Send;
+}
+
+impl RDBWriter for StoreEngine {
+    fn dump(&self, aof_base: bool) -> Vec<u8> {
+        self.snapshot().encode(aof_base)
+    }
+
+    fn snapshot(&self) -> RdbSnapshot {
+        // keys which are already expired are skipped
+        let now = now_ms();
+        let strings: Vec<_> = self
+            .get_string_entries()
+            .into_iter()
+            .filter(|(_, _, at)| at.is_none_or(|at| at > now))
+            .collect();
+        let streams: Vec<_> = self
+            .get_streams()
+            .into_iter()
+            .map(|(k, entries, last_id)| {
+                let at = self.get_expire(&k);
+                (k, entries, last_id, at)
+            })
+            .filter(|(_, _, _, at)| at.is_none_or(|at| at > now))
+            .collect();
+
+        RdbSnapshot {
+            used_mem: self.dataset_memory(),
+            strings,
+            streams,
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let dir = self.get_dir();
+        fs::create_dir_all(&dir)?;
+        let path = Path::new(&dir).join(self.get_filename());
+        let tmp_path = Path::new(&dir).join(format!("temp-{}.rdb", std::process::id()));
+
+        replace_file(&tmp_path, &path, &self.dump(false))?;
+        Ok(())
+    }
+
+    fn bgsave(self: &Arc<Self>) -> bool {
+        if !self.stats.begin_save() {
+            return false;
+        }
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let saved = db.save();
+            if let Err(e) = &saved {
+                println!("err: background save: {}", e);
+            }
+            db.stats.end_save(saved.is_ok());
+        });
+        true
+    }
+
+    async fn save_cron(self: &Arc<Self>) {
+        let mut ticker = tokio::time::interval(SAVE_CRON_PERIOD);
+        loop {
+            ticker.tick().await;
+            let stats = &self.stats;
+            let now = unix_time();
+            let dirty = ServerStats::get(&stats.dirty);
+            let since_save = now.saturating_sub(ServerStats::get(&stats.last_save_time));
+            // as redis, after a failed save wait a bit instead of retrying on every tick
+            let can_retry = stats.last_save_ok.load(Ordering::Relaxed)
+                || now.saturating_sub(ServerStats::get(&stats.last_save_try)) >= SAVE_RETRY_DELAY;
+            let due = self
+                .config
+                .read()
+                .unwrap()
+                .save_params
+                .iter()
+                .any(|(seconds, changes)| dirty >= *changes && since_save > *seconds);
+            if due && can_retry && self.bgsave() {
+                println!("{} changes since the last save, saving", dirty);
+            }
+        }
+    }
+}
+
+// write content to tmp_path and rename it over path, nothing is left behind on error
+// the directory is synced too, else the rename itself may not survive a crash
+pub fn replace_file(tmp_path: &Path, path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let written = File::create(tmp_path).and_then(|mut file| {
+        file.write_all(content)?;
+        file.sync_all()
+    });
+    let replaced = written.and_then(|_| fs::rename(tmp_path, path));
+    if replaced.is_err() {
+        let _ = fs::remove_file(tmp_path);
+    }
+    replaced?;
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+fn write_aux(buf: &mut Vec<u8>, key: &str, value: &str) {
+    buf.push(op_code::AUX);
+    write_string(buf, key.as_bytes());
+    write_string(buf, value.as_bytes());
+}
+
+fn write_expire(buf: &mut Vec<u8>, at: Option<u128>) {
+    if let Some(at) = at {
+        buf.push(op_code::EXPIRETIME_MS);
+        buf.extend_from_slice(&(at as u64).to_le_bytes());
+    }
+}
+
+pub fn write_length(buf: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        buf.push((length_encode_code::SIX_BITS << 6) | len as u8);
+    } else if len < 1 << 14 {
+        buf.push((length_encode_code::FORTEEN_BITS << 6) | (len >> 8) as u8);
+        buf.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        buf.push(length_encode_code::LEN_32BIT);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        buf.push(length_encode_code::LEN_64BIT);
+        buf.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+pub fn write_string(buf: &mut Vec<u8>, s: &[u8]) {
+    write_length(buf, s.len() as u64);
+    buf.extend_from_slice(s);
+}
+
+// a stream is saved as a single listpack node
+// the first entry is the master entry and the ids are stored as diffs to it
+fn write_stream(buf: &mut Vec<u8>, entries: &StreamEntries, last_id: &StreamID) {
+    match entries.first_key_value() {
+        None => write_length(buf, 0),
+        Some((master_id, master_fields)) => {
+            write_length(buf, 1);
+
+            // node key: 128 bits big endian id
+            let mut node_key = Vec::with_capacity(16);
+            node_key.extend_from_slice(&(master_id.millisecond as u64).to_be_bytes());
+            node_key.extend_from_slice(&master_id.sequence.to_be_bytes());
+            write_string(buf, &node_key);
+
+            let master_fields: Vec<&String> = master_fields.keys().collect();
+            let mut lp = Listpack::new();
+            lp.append_int(entries.len() as i64);
+            // deleted entries
+            lp.append_int(0);
+            lp.append_int(master_fields.len() as i64);
+            for f in master_fields.iter() {
+                lp.append_str(f.as_bytes());
+            }
+            lp.append_int(0);
+
+            for (id, hash) in entries.iter() {
+                // fields are always written, no SAMEFIELDS compression
+                lp.append_int(0);
+                lp.append_int((id.millisecond - master_id.millisecond) as i64);
+                lp.append_int(id.sequence as i64 - master_id.sequence as i64);
+                lp.append_int(hash.len() as i64);
+                for (f, v) in hash.iter() {
+                    lp.append_str(f.as_bytes());
+                    lp.append_str(v.as_bytes());
+                }
+                // lp-count: flags, ms, seq, num fields and the fields and values
+                lp.append_int(hash.len() as i64 * 2 + 4);
+            }
+            write_string(buf, &lp.into_bytes());
+        }
+    }
+
+    write_length(buf, entries.len() as u64);
+    write_length(buf, last_id.millisecond as u64);
+    write_length(buf, last_id.sequence);
+    // consumer groups
+    write_length(buf, 0);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rdb::loader::RDBLoader;
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_dump_and_load() {
+        let engine = StoreEngine::new();
+        engine.set("foo".to_string(), "bar".to_string());
+        engine.set_with_expire_exact("tmp".to_string(), "1".to_string(), now_ms() + 100_000);
+        let long_value = "v".repeat(20_000);
+        engine.set("long".to_string(), long_value.clone());
+
+        let fields = HashMap::from([("a".to_string(), "1".to_string())]);
+        let id1 = StreamID::new(1_700_000_000_000, 1);
+        let id2 = StreamID::new(1_700_000_000_005, 0);
+        engine
+            .set_stream_key("s", id1.clone(), fields.clone())
+            .unwrap();
+        engine.set_stream_key("s", id2.clone(), fields).unwrap();
+
+        let rdb = engine.dump(false);
+        let loaded = StoreEngine::new();
+        assert!(loaded.parse(&mut Cursor::new(rdb)).unwrap());
+
+        assert_eq!(loaded.get("foo"), Some("bar".to_string()));
+        assert_eq!(loaded.get("long"), Some(long_value));
+        assert_eq!(loaded.get_expire("tmp"), engine.get_expire("tmp"));
+        assert_eq!(loaded.get_stream_key("s"), engine.get_stream_key("s"));
+        assert_eq!(loaded.get_last_stream_id("s"), Some(id2));
+    }
+
+    #[test]
+    fn test_dump_checksum() {
+        let engine = StoreEngine::new();
+        engine.set("foo".to_string(), "bar".to_string());
+        let mut rdb = engine.dump(false);
+        assert!(StoreEngine::new()
+            .parse(&mut Cursor::new(rdb.clone()))
+            .is_ok());
+
+        // flip a byte of the value, the trailer no longer matches
+        let at = rdb.windows(3).position(|w| w == b"bar").unwrap();
+        rdb[at] = b'c';
+        assert!(StoreEngine::new().parse(&mut Cursor::new(rdb)).is_err());
+    }
+}
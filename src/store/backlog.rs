@@ -0,0 +1,97 @@
+use std::collections::VecDeque;
+
+pub const DEFAULT_BACKLOG_SIZE: u64 = 1024 * 1024;
+
+// circular buffer with the latest bytes of the replication stream
+// start_offset is the replication offset before the first byte kept
+pub struct ReplBacklog {
+    buf: VecDeque<u8>,
+    size: usize,
+    start_offset: u64,
+}
+
+impl ReplBacklog {
+    pub fn new(size: u64) -> Self {
+        ReplBacklog {
+            buf: VecDeque::new(),
+            size: size as usize,
+            start_offset: 0,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend(data);
+        self.trim();
+    }
+
+    // the oldest bytes are dropped when the backlog shrinks
+    pub fn resize(&mut self, size: u64) {
+        self.size = size as usize;
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        if self.buf.len() > self.size {
+            let excess = self.buf.len() - self.size;
+            self.buf.drain(..excess);
+            self.start_offset += excess as u64;
+        }
+    }
+
+    // an empty backlog continuing a history at offset
+    pub fn reset(&mut self, offset: u64) {
+        self.buf.clear();
+        self.start_offset = offset;
+    }
+
+    // replication offset after the last byte fed
+    pub fn end_offset(&self) -> u64 {
+        self.start_offset + self.buf.len() as u64
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size as u64
+    }
+
+    pub fn histlen(&self) -> u64 {
+        self.buf.len() as u64
+    }
+
+    // offset of the first byte kept, 1 based as in INFO repl_backlog_first_byte_offset
+    pub fn first_byte_offset(&self) -> u64 {
+        self.start_offset + 1
+    }
+
+    // bytes following offset, None once they are no longer kept
+    pub fn range_from(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset < self.start_offset || offset > self.end_offset() {
+            return None;
+        }
+        let from = (offset - self.start_offset) as usize;
+        Some(self.buf.range(from..).copied().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backlog_wraps() {
+        let mut backlog = ReplBacklog::new(8);
+        backlog.feed(b"hello");
+        assert_eq!(backlog.range_from(0), Some(b"hello".to_vec()));
+
+        backlog.feed(b"world");
+        assert_eq!(backlog.end_offset(), 10);
+        assert_eq!(backlog.first_byte_offset(), 3);
+        assert_eq!(backlog.range_from(1), None);
+        assert_eq!(backlog.range_from(5), Some(b"world".to_vec()));
+        assert_eq!(backlog.range_from(10), Some(Vec::new()));
+        assert_eq!(backlog.range_from(11), None);
+
+        backlog.resize(3);
+        assert_eq!(backlog.range_from(7), Some(b"rld".to_vec()));
+        assert_eq!(backlog.range_from(6), None);
+    }
+}
@@ -0,0 +1,467 @@
+use super::backlog::DEFAULT_BACKLOG_SIZE;
+use super::engine::StoreEngine;
+use crate::aof::writer::AOFWriter;
+use crate::engine::error::CommandError;
+use crate::rdb::config::RDBConfigOps;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+// when the append only file is flushed to disk
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AppendFsync {
+    // after every write
+    Always,
+    // once a second, at most a second of writes is lost
+    EverySec,
+    // whenever the os decides
+    No,
+}
+
+// runtime parameters, the command line and CONFIG SET both go through config_set
+pub struct ServerConfig {
+    pub repl_backlog_size: u64,
+    pub replica_read_only: bool,
+    // a replica whose link is down still answers, with data which may be stale
+    pub replica_serve_stale_data: bool,
+    // lower is preferred when promoting a replica, 0 never
+    pub replica_priority: u64,
+    // seconds without an ack before a replica is dropped
+    pub repl_timeout: u64,
+    pub min_replicas_to_write: u32,
+    // seconds
+    pub min_replicas_max_lag: u64,
+    // save after <seconds> if there were at least <changes>, empty disables saving
+    pub save_params: Vec<(u64, u64)>,
+    pub appendonly: bool,
+    // prefix of the files of the aof, in appenddirname
+    pub appendfilename: String,
+    // in dir, holds the base, the incr files and the manifest
+    pub appenddirname: String,
+    pub appendfsync: AppendFsync,
+    // the base written by a rewrite is an rdb instead of commands
+    pub aof_use_rdb_preamble: bool,
+    // rewrite once the aof has grown by this percentage since the last rewrite, 0 never
+    pub auto_aof_rewrite_percentage: u64,
+    // and is at least this big
+    pub auto_aof_rewrite_min_size: u64,
+    // load what precedes an incomplete command at the end of the aof instead of failing
+    pub aof_load_truncated: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            repl_backlog_size: DEFAULT_BACKLOG_SIZE,
+            replica_read_only: true,
+            replica_serve_stale_data: true,
+            replica_priority: 100,
+            repl_timeout: 60,
+            min_replicas_to_write: 0,
+            min_replicas_max_lag: 10,
+            save_params: vec![(3600, 1), (300, 100), (60, 10000)],
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appenddirname: "appendonlydir".to_string(),
+            appendfsync: AppendFsync::EverySec,
+            aof_use_rdb_preamble: true,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            aof_load_truncated: true,
+        }
+    }
+}
+
+// every parameter known to CONFIG GET, in the order they are listed
+pub const CONFIG_PARAMS: &[&str] = &[
+    "dir",
+    "dbfilename",
+    "save",
+    "appendonly",
+    "appendfilename",
+    "appenddirname",
+    "appendfsync",
+    "aof-load-truncated",
+    "aof-use-rdb-preamble",
+    "auto-aof-rewrite-percentage",
+    "auto-aof-rewrite-min-size",
+    "repl-backlog-size",
+    "replica-read-only",
+    "slave-read-only",
+    "replica-serve-stale-data",
+    "slave-serve-stale-data",
+    "replica-priority",
+    "slave-priority",
+    "repl-timeout",
+    "min-replicas-to-write",
+    "min-slaves-to-write",
+    "min-replicas-max-lag",
+    "min-slaves-max-lag",
+];
+
+pub trait ConfigOps {
+    // name and value of each parameter matching the glob pattern
+    fn config_get(&self, pattern: &str) -> Vec<(String, String)>;
+    fn config_set(self: &Arc<Self>, name: &str, value: &str) -> Result<(), CommandError>;
+    // every value is checked before any is applied, as CONFIG SET of several parameters
+    fn config_set_pairs(self: &Arc<Self>, pairs: &[(&str, &str)]) -> Result<(), CommandError>;
+}
+
+impl ConfigOps for StoreEngine {
+    fn config_get(&self, pattern: &str) -> Vec<(String, String)> {
+        let pattern = pattern.to_lowercase();
+        CONFIG_PARAMS
+            .iter()
+            .filter(|name| glob_match(pattern.as_bytes(), name.as_bytes()))
+            .filter_map(|name| {
+                let value = match *name {
+                    "dir" => self.get_dir(),
+                    "dbfilename" => self.get_filename(),
+                    "save" => self
+                        .config
+                        .read()
+                        .unwrap()
+                        .save_params
+                        .iter()
+                        .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                    "appendonly" => yes_no(self.config.read().unwrap().appendonly),
+                    "appendfilename" => self.config.read().unwrap().appendfilename.clone(),
+                    "appenddirname" => self.config.read().unwrap().appenddirname.clone(),
+                    "appendfsync" => match self.config.read().unwrap().appendfsync {
+                        AppendFsync::Always => "always",
+                        AppendFsync::EverySec => "everysec",
+                        AppendFsync::No => "no",
+                    }
+                    .to_string(),
+                    "aof-load-truncated" => yes_no(self.config.read().unwrap().aof_load_truncated),
+                    "aof-use-rdb-preamble" => {
+                        yes_no(self.config.read().unwrap().aof_use_rdb_preamble)
+                    }
+                    "auto-aof-rewrite-percentage" => self
+                        .config
+                        .read()
+                        .unwrap()
+                        .auto_aof_rewrite_percentage
+                        .to_string(),
+                    "auto-aof-rewrite-min-size" => self
+                        .config
+                        .read()
+                        .unwrap()
+                        .auto_aof_rewrite_min_size
+                        .to_string(),
+                    "repl-backlog-size" => {
+                        self.config.read().unwrap().repl_backlog_size.to_string()
+                    }
+                    "replica-read-only" | "slave-read-only" => {
+                        yes_no(self.config.read().unwrap().replica_read_only)
+                    }
+                    "replica-serve-stale-data" | "slave-serve-stale-data" => {
+                        yes_no(self.config.read().unwrap().replica_serve_stale_data)
+                    }
+                    "replica-priority" | "slave-priority" => {
+                        self.config.read().unwrap().replica_priority.to_string()
+                    }
+                    "repl-timeout" => self.config.read().unwrap().repl_timeout.to_string(),
+                    "min-replicas-to-write" | "min-slaves-to-write" => self
+                        .config
+                        .read()
+                        .unwrap()
+                        .min_replicas_to_write
+                        .to_string(),
+                    "min-replicas-max-lag" | "min-slaves-max-lag" => {
+                        self.config.read().unwrap().min_replicas_max_lag.to_string()
+                    }
+                    _ => return None,
+                };
+                Some((name.to_string(), value))
+            })
+            .collect()
+    }
+
+    fn config_set(self: &Arc<Self>, name: &str, value: &str) -> Result<(), CommandError> {
+        self.config_set_pairs(&[(name, value)])
+    }
+
+    fn config_set_pairs(self: &Arc<Self>, pairs: &[(&str, &str)]) -> Result<(), CommandError> {
+        let mut changes = Vec::with_capacity(pairs.len());
+        for (name, value) in pairs {
+            let name = name.to_lowercase();
+            if changes.iter().any(|(seen, _)| *seen == name) {
+                return Err(CommandError::InvalidConfig(
+                    name,
+                    "duplicate parameter".to_string(),
+                ));
+            }
+            let change = parse_config(self, &name, value)?;
+            changes.push((name, change));
+        }
+
+        // only starting the aof can fail from here, what was applied before it is undone
+        let previous: Vec<_> = changes
+            .iter()
+            .flat_map(|(name, _)| self.config_get(name))
+            .collect();
+        for (i, (name, change)) in changes.into_iter().enumerate() {
+            if let Err(e) = apply_config(self, change) {
+                for (name, value) in previous.iter().take(i) {
+                    if let Ok(change) = parse_config(self, name, value) {
+                        let _ = apply_config(self, change);
+                    }
+                }
+                return Err(CommandError::InvalidConfig(name, e.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+// a value checked by parse_config, nothing is changed until apply_config
+enum ConfigChange {
+    Dir(String),
+    DbFilename(String),
+    Save(Vec<(u64, u64)>),
+    AppendOnly(bool),
+    AppendFilename(String),
+    AppendDirname(String),
+    AppendFsync(AppendFsync),
+    AofLoadTruncated(bool),
+    AofUseRdbPreamble(bool),
+    AutoAofRewritePercentage(u64),
+    AutoAofRewriteMinSize(u64),
+    ReplBacklogSize(u64),
+    ReplicaReadOnly(bool),
+    ReplicaServeStaleData(bool),
+    ReplicaPriority(u64),
+    ReplTimeout(u64),
+    MinReplicasToWrite(u32),
+    MinReplicasMaxLag(u64),
+}
+
+fn parse_config(db: &StoreEngine, name: &str, value: &str) -> Result<ConfigChange, CommandError> {
+    let change = match name {
+        "dir" => ConfigChange::Dir(value.to_string()),
+        "dbfilename" => ConfigChange::DbFilename(value.to_string()),
+        "save" => ConfigChange::Save(parse_save_params(value).ok_or_else(|| {
+            CommandError::InvalidConfig(name.to_string(), "Invalid save parameters".to_string())
+        })?),
+        "appendonly" => ConfigChange::AppendOnly(parse_yes_no(name, value)?),
+        // the files of the aof are only looked for at startup
+        "appendfilename" | "appenddirname" => {
+            if !db.stats.loading.load(Ordering::Relaxed) {
+                return Err(CommandError::InvalidConfig(
+                    name.to_string(),
+                    "can't set immutable config".to_string(),
+                ));
+            }
+            if value.is_empty() || value.contains('/') {
+                return Err(CommandError::InvalidConfig(
+                    name.to_string(),
+                    format!("{} can't be a path, just a filename", name),
+                ));
+            }
+            match name {
+                "appendfilename" => ConfigChange::AppendFilename(value.to_string()),
+                _ => ConfigChange::AppendDirname(value.to_string()),
+            }
+        }
+        "appendfsync" => ConfigChange::AppendFsync(match value.to_lowercase().as_str() {
+            "always" => AppendFsync::Always,
+            "everysec" => AppendFsync::EverySec,
+            "no" => AppendFsync::No,
+            _ => {
+                return Err(CommandError::InvalidConfig(
+                    name.to_string(),
+                    "argument(s) must be one of the following: always, everysec, no".to_string(),
+                ))
+            }
+        }),
+        "aof-load-truncated" => ConfigChange::AofLoadTruncated(parse_yes_no(name, value)?),
+        "aof-use-rdb-preamble" => ConfigChange::AofUseRdbPreamble(parse_yes_no(name, value)?),
+        "auto-aof-rewrite-percentage" => ConfigChange::AutoAofRewritePercentage(
+            value.parse::<u64>().map_err(|_| invalid_integer(name))?,
+        ),
+        "auto-aof-rewrite-min-size" => ConfigChange::AutoAofRewriteMinSize(
+            parse_memory(value).ok_or_else(|| invalid_integer(name))?,
+        ),
+        "repl-backlog-size" => {
+            let size = parse_memory(value).ok_or_else(|| invalid_integer(name))?;
+            // the backlog needs room for at least one command
+            ConfigChange::ReplBacklogSize(size.max(16 * 1024))
+        }
+        "replica-read-only" | "slave-read-only" => {
+            ConfigChange::ReplicaReadOnly(parse_yes_no(name, value)?)
+        }
+        "replica-serve-stale-data" | "slave-serve-stale-data" => {
+            ConfigChange::ReplicaServeStaleData(parse_yes_no(name, value)?)
+        }
+        "replica-priority" | "slave-priority" => {
+            ConfigChange::ReplicaPriority(value.parse::<u64>().map_err(|_| invalid_integer(name))?)
+        }
+        "repl-timeout" => {
+            let timeout = value.parse::<u64>().map_err(|_| invalid_integer(name))?;
+            if timeout == 0 {
+                return Err(CommandError::InvalidConfig(
+                    name.to_string(),
+                    "argument must be greater than 0".to_string(),
+                ));
+            }
+            ConfigChange::ReplTimeout(timeout)
+        }
+        "min-replicas-to-write" | "min-slaves-to-write" => ConfigChange::MinReplicasToWrite(
+            value.parse::<u32>().map_err(|_| invalid_integer(name))?,
+        ),
+        "min-replicas-max-lag" | "min-slaves-max-lag" => ConfigChange::MinReplicasMaxLag(
+            value.parse::<u64>().map_err(|_| invalid_integer(name))?,
+        ),
+        _ => return Err(CommandError::UnknownConfig(name.to_string())),
+    };
+    Ok(change)
+}
+
+fn apply_config(db: &Arc<StoreEngine>, change: ConfigChange) -> anyhow::Result<()> {
+    let config = || db.config.write().unwrap();
+    match change {
+        ConfigChange::Dir(dir) => db.set_dir(dir),
+        ConfigChange::DbFilename(filename) => db.set_filename(filename),
+        ConfigChange::Save(params) => config().save_params = params,
+        ConfigChange::AppendOnly(on) => {
+            let was = std::mem::replace(&mut config().appendonly, on);
+            // at startup the file is opened once the data is loaded
+            if on != was && !db.stats.loading.load(Ordering::Relaxed) {
+                if !on {
+                    db.stop_aof();
+                } else if let Err(e) = db.start_aof() {
+                    config().appendonly = false;
+                    return Err(e);
+                }
+            }
+        }
+        ConfigChange::AppendFilename(filename) => config().appendfilename = filename,
+        ConfigChange::AppendDirname(dirname) => config().appenddirname = dirname,
+        ConfigChange::AppendFsync(fsync) => config().appendfsync = fsync,
+        ConfigChange::AofLoadTruncated(on) => config().aof_load_truncated = on,
+        ConfigChange::AofUseRdbPreamble(on) => config().aof_use_rdb_preamble = on,
+        ConfigChange::AutoAofRewritePercentage(percentage) => {
+            config().auto_aof_rewrite_percentage = percentage
+        }
+        ConfigChange::AutoAofRewriteMinSize(size) => config().auto_aof_rewrite_min_size = size,
+        ConfigChange::ReplBacklogSize(size) => {
+            config().repl_backlog_size = size;
+            db.master_info.write().unwrap().backlog.resize(size);
+        }
+        ConfigChange::ReplicaReadOnly(on) => config().replica_read_only = on,
+        ConfigChange::ReplicaServeStaleData(on) => config().replica_serve_stale_data = on,
+        ConfigChange::ReplicaPriority(priority) => config().replica_priority = priority,
+        ConfigChange::ReplTimeout(timeout) => config().repl_timeout = timeout,
+        ConfigChange::MinReplicasToWrite(n) => config().min_replicas_to_write = n,
+        ConfigChange::MinReplicasMaxLag(lag) => config().min_replicas_max_lag = lag,
+    }
+    Ok(())
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+fn parse_yes_no(name: &str, value: &str) -> Result<bool, CommandError> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(CommandError::InvalidConfig(
+            name.to_string(),
+            "argument must be 'yes' or 'no'".to_string(),
+        )),
+    }
+}
+
+fn invalid_integer(name: &str) -> CommandError {
+    CommandError::InvalidConfig(
+        name.to_string(),
+        "argument couldn't be parsed into an integer".to_string(),
+    )
+}
+
+// "3600 1 300 100", pairs of seconds and changes
+fn parse_save_params(value: &str) -> Option<Vec<(u64, u64)>> {
+    let values: Vec<&str> = value.split_whitespace().collect();
+    if !values.len().is_multiple_of(2) {
+        return None;
+    }
+    values
+        .chunks(2)
+        .map(|pair| Some((pair[0].parse().ok()?, pair[1].parse().ok()?)))
+        .collect()
+}
+
+// 1024, 1k (1000), 1kb (1024), 1m, 1mb, 1g, 1gb
+pub fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_lowercase();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit: u64 = match &value[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+// glob style matching with * and ?, as CONFIG GET does
+pub fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (0..=s.len()).any(|i| glob_match(rest, &s[i..])),
+        Some((b'?', rest)) => !s.is_empty() && glob_match(rest, &s[1..]),
+        Some((c, rest)) => s.first() == Some(c) && glob_match(rest, &s[1..]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config_parse() {
+        assert_eq!(parse_memory("100"), Some(100));
+        assert_eq!(parse_memory("1kb"), Some(1024));
+        assert_eq!(parse_memory("2MB"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_memory("1x"), None);
+
+        assert!(glob_match(b"*", b"dir"));
+        assert!(glob_match(b"repl-*", b"repl-backlog-size"));
+        assert!(glob_match(b"d?r", b"dir"));
+        assert!(!glob_match(b"db*", b"dir"));
+
+        assert_eq!(
+            parse_save_params("3600 1 300 100"),
+            Some(vec![(3600, 1), (300, 100)])
+        );
+        assert_eq!(parse_save_params(""), Some(vec![]));
+        assert_eq!(parse_save_params("3600"), None);
+        assert_eq!(parse_save_params("3600 x"), None);
+    }
+
+    #[test]
+    fn test_config_set_pairs() {
+        let db = Arc::new(StoreEngine::new());
+        // the invalid second value keeps the first from being applied
+        let err = db
+            .config_set_pairs(&[("repl-timeout", "5"), ("min-replicas-to-write", "x")])
+            .unwrap_err();
+        assert_eq!(err, invalid_integer("min-replicas-to-write"));
+        assert_eq!(db.config.read().unwrap().repl_timeout, 60);
+
+        assert!(db
+            .config_set_pairs(&[("repl-timeout", "5"), ("REPL-TIMEOUT", "6")])
+            .is_err());
+        db.config_set_pairs(&[("repl-timeout", "5"), ("min-replicas-to-write", "1")])
+            .unwrap();
+        let config = db.config.read().unwrap();
+        assert_eq!((config.repl_timeout, config.min_replicas_to_write), (5, 1));
+    }
+}
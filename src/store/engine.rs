@@ -1,34 +1,52 @@
-use super::{HandshakeState, MasterInfo, NodeInfo, ReplicaType, SlaveInfo};
-use crate::engine::parser::command_parser;
-use crate::engine::{array_to_resp_array, count_resp_command_type_offset, RespCommandType};
+use super::{
+    random_replid, HandshakeState, MasterInfo, MasterLink, NodeInfo, ReplicaType, SlaveInfo,
+};
 use priority_queue::PriorityQueue;
 use std::cmp::Reverse;
 use std::collections::{BTreeMap, HashMap};
-use tokio::net::tcp::OwnedWriteHalf;
 // use std::io::prelude::*;
+use super::config::ServerConfig;
+use super::master_engine::MasterEngine;
+use super::stats::ServerStats;
+use crate::aof::AofFile;
 use crate::rdb::RdbConf;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Mutex, RwLock};
 use std::time::*;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::TcpStream;
-use tokio::sync;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
 
 // https://github.com/tokio-rs/tokio/blob/master/examples/tinydb.rs
 
 // const FULLRESYNC: &str = "+FULLRESYNC";
 
+// id -> HashMap<field, value>
+pub type StreamEntries = BTreeMap<StreamID, HashMap<String, String>>;
+
 pub struct StoreEngine {
     dict: RwLock<HashMap<String, String>>,
     // key: stream key, value id -> HashMap<field, value>
-    pub stream_dict: RwLock<HashMap<String, BTreeMap<StreamID, HashMap<String, String>>>>,
+    pub stream_dict: RwLock<HashMap<String, StreamEntries>>,
     pub stream_last_key: RwLock<HashMap<String, StreamID>>,
     expiring_queue: RwLock<PriorityQueue<String, Reverse<u128>>>,
-    node_info: RwLock<NodeInfo>,
+    pub node_info: RwLock<NodeInfo>,
     pub rdb_info: Mutex<RdbConf>,
+    pub config: RwLock<ServerConfig>,
     pub replica_info: RwLock<ReplicaType>,
     pub master_info: RwLock<MasterInfo>,
     pub slave_info: RwLock<SlaveInfo>,
-    pub replicas: sync::RwLock<HashMap<String, Arc<sync::Mutex<OwnedWriteHalf>>>>,
+    pub master_link: RwLock<MasterLink>,
+    pub master_link_task: Mutex<Option<JoinHandle<()>>>,
+    // woken on every REPLCONF ACK, shared by all the WAITs
+    pub ack_notify: Notify,
+    // what is sent to a replica is queued, the connection writes it once the rdb is out
+    pub replicas: RwLock<HashMap<String, UnboundedSender<Vec<u8>>>>,
+    pub stats: ServerStats,
+    // open while appendonly is on
+    pub aof: Mutex<Option<AofFile>>,
+    // held from applying a write until it is fed to the aof and the replicas
+    // a full resync takes it too, so its snapshot ends where its stream starts
+    pub write_lock: Mutex<()>,
 }
 
 impl StoreEngine {
@@ -38,12 +56,19 @@ impl StoreEngine {
             stream_dict: RwLock::new(HashMap::new()),
             stream_last_key: RwLock::new(HashMap::new()),
             rdb_info: Mutex::new(RdbConf::default()),
+            config: RwLock::new(ServerConfig::default()),
             expiring_queue: RwLock::new(PriorityQueue::new()),
             replica_info: RwLock::new(ReplicaType::Master),
             node_info: RwLock::new(NodeInfo::default()),
             master_info: RwLock::new(MasterInfo::default()),
             slave_info: RwLock::new(SlaveInfo::default()),
-            replicas: sync::RwLock::new(HashMap::new()),
+            master_link: RwLock::new(MasterLink::default()),
+            master_link_task: Mutex::new(None),
+            ack_notify: Notify::new(),
+            replicas: RwLock::new(HashMap::new()),
+            stats: ServerStats::default(),
+            aof: Mutex::new(None),
+            write_lock: Mutex::new(()),
         }
     }
 
@@ -51,21 +76,87 @@ impl StoreEngine {
         *self.node_info.write().unwrap() = NodeInfo { port };
     }
 
-    pub fn set_replica(&self, host: String) {
-        *self.replica_info.write().unwrap() = ReplicaType::Slave(host.clone());
+    pub fn get_port(&self) -> String {
+        self.node_info.read().unwrap().port.clone()
+    }
+
+    // host is the ip:port of the master
+    pub fn set_replica(&self, host: String) -> anyhow::Result<()> {
+        let Some((ip, port)) = host.rsplit_once(':') else {
+            return Err(anyhow::anyhow!("invalid master address {}", host));
+        };
+        let (ip, port) = (ip.to_string(), port.to_string());
+
+        let previous = std::mem::replace(
+            &mut *self.replica_info.write().unwrap(),
+            ReplicaType::Slave(host.clone()),
+        );
+
+        // the history we have so far, the new master may be able to continue it
+        let (master_replid, slave_repl_offset) = match previous {
+            ReplicaType::Master => {
+                let master_info = self.master_info.read().unwrap();
+                (
+                    master_info.master_replid.clone(),
+                    master_info.backlog.end_offset(),
+                )
+            }
+            ReplicaType::Slave(_) => {
+                let slave_info = self.slave_info.read().unwrap();
+                (
+                    slave_info.master_replid.clone(),
+                    slave_info.slave_repl_offset,
+                )
+            }
+        };
+
         *self.slave_info.write().unwrap() = SlaveInfo {
-            host: host.split(":").collect::<Vec<&str>>()[0].to_string(),
-            port: host.split(":").collect::<Vec<&str>>()[1].to_string(),
-            master_replid: "?".to_string(),
-            slave_repl_offset: 0,
-            slave_ping_count: 0,
-            slave_ack_count: 0,
+            host: ip,
+            port,
+            master_replid,
+            slave_repl_offset,
+            last_ack: None,
             handshake_state: HandshakeState::Ping,
-        }
+            online: false,
+        };
+
+        // our replicas follow whatever the new master sends us
+        self.disconnect_replicas();
+        Ok(())
     }
 
     pub fn set_replica_as_master(&self) {
-        *self.replica_info.write().unwrap() = ReplicaType::Master;
+        let previous = std::mem::replace(
+            &mut *self.replica_info.write().unwrap(),
+            ReplicaType::Master,
+        );
+
+        // a promoted replica starts a new history where the one of its master stops
+        // the replicas of the old master can still resync partially with replid2
+        if let ReplicaType::Slave(_) = previous {
+            let (replid, offset) = {
+                let slave_info = self.slave_info.read().unwrap();
+                (
+                    slave_info.master_replid.clone(),
+                    slave_info.slave_repl_offset,
+                )
+            };
+            let mut master_info = self.master_info.write().unwrap();
+            master_info.master_replid2 = match replid.as_str() {
+                "?" => master_info.master_replid.clone(),
+                _ => replid,
+            };
+            master_info.second_repl_offset = offset as i64 + 1;
+            master_info.master_replid = random_replid();
+            // the backlog already holds the stream of the old master
+            if master_info.backlog.end_offset() != offset {
+                master_info.backlog.reset(offset);
+            }
+            drop(master_info);
+
+            // they can continue with replid2, but have to learn the new id
+            self.disconnect_replicas();
+        }
     }
 
     pub fn get_replica(&self) -> ReplicaType {
@@ -79,17 +170,18 @@ impl StoreEngine {
         d.get(key).cloned()
     }
 
+    // a plain set discards any previous ttl of the key
     pub fn set(&self, key: String, value: String) {
+        self.expiring_queue.write().unwrap().remove(&key);
+        self.dict.write().unwrap().insert(key, value);
+    }
+
+    pub fn set_keep_ttl(&self, key: String, value: String) {
         self.dict.write().unwrap().insert(key, value);
     }
 
     pub fn set_with_expire(&self, key: String, value: String, ttl: u128) {
-        let sys_time = SystemTime::now();
-        let expired_ms = sys_time
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
-            + ttl;
+        let expired_ms = now_ms() + ttl;
         self.dict.write().unwrap().insert(key.clone(), value);
         self.expiring_queue
             .write()
@@ -102,6 +194,109 @@ impl StoreEngine {
         self.expiring_queue.write().unwrap().push(key, Reverse(ttl));
     }
 
+    // remove a key of any type, true if it existed
+    pub fn del(&self, key: &str) -> bool {
+        self.expiring_queue.write().unwrap().remove(key);
+        let removed = self.dict.write().unwrap().remove(key).is_some();
+        let removed_stream = self.stream_dict.write().unwrap().remove(key).is_some();
+        self.stream_last_key.write().unwrap().remove(key);
+        removed || removed_stream
+    }
+
+    // every string key with its absolute expire time
+    pub fn get_string_entries(&self) -> Vec<(String, String, Option<u128>)> {
+        let dict = self.dict.read().unwrap();
+        let queue = self.expiring_queue.read().unwrap();
+        dict.iter()
+            .map(|(k, v)| (k.clone(), v.clone(), queue.get_priority(k).map(|at| at.0)))
+            .collect()
+    }
+
+    // drop every key, e.g. before loading the rdb of the master
+    pub fn flush_all(&self) {
+        self.dict.write().unwrap().clear();
+        self.stream_dict.write().unwrap().clear();
+        self.stream_last_key.write().unwrap().clear();
+        self.expiring_queue.write().unwrap().clear();
+    }
+
+    pub fn exists(&self, key: &str) -> bool {
+        self.dict.read().unwrap().contains_key(key)
+            || self.stream_dict.read().unwrap().contains_key(key)
+    }
+
+    // set an absolute expire time in ms on an existing key
+    pub fn expire_at(&self, key: &str, at: u128) -> bool {
+        if !self.exists(key) {
+            return false;
+        }
+        self.expiring_queue
+            .write()
+            .unwrap()
+            .push(key.to_string(), Reverse(at));
+        true
+    }
+
+    // absolute expire time in ms
+    pub fn get_expire(&self, key: &str) -> Option<u128> {
+        self.expiring_queue
+            .read()
+            .unwrap()
+            .get_priority(key)
+            .map(|at| at.0)
+    }
+
+    pub fn persist(&self, key: &str) -> bool {
+        self.expiring_queue.write().unwrap().remove(key).is_some()
+    }
+
+    // a key looked up by a read command, counted as a keyspace hit or miss
+    pub fn lookup_read(&self, key: &str) -> bool {
+        let found = self.exists(key);
+        self.stats.keyspace_lookup(found);
+        found
+    }
+
+    // number of keys, of keys with a ttl and their average ttl in ms
+    pub fn keyspace_info(&self) -> (u64, u64, u64) {
+        let keys = self.dict.read().unwrap().len() + self.stream_dict.read().unwrap().len();
+        let queue = self.expiring_queue.read().unwrap();
+        let now = now_ms();
+        let ttl_sum: u128 = queue.iter().map(|(_, at)| at.0.saturating_sub(now)).sum();
+        let avg_ttl = match queue.len() {
+            0 => 0,
+            n => (ttl_sum / n as u128) as u64,
+        };
+        (keys as u64, queue.len() as u64, avg_ttl)
+    }
+
+    // bytes of the keys and values, a rough estimate without allocator stats
+    pub fn dataset_memory(&self) -> u64 {
+        let strings: usize = self
+            .dict
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| k.len() + v.len())
+            .sum();
+        let streams: usize = self
+            .stream_dict
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, entries)| {
+                k.len()
+                    + entries
+                        .values()
+                        .flat_map(|fields| fields.iter())
+                        .map(|(f, v)| f.len() + v.len())
+                        .sum::<usize>()
+                    + entries.len() * std::mem::size_of::<StreamID>()
+            })
+            .sum();
+        (strings + streams) as u64
+    }
+
     pub fn get_keys(&self) -> Vec<String> {
         <HashMap<String, String> as Clone>::clone(&self.dict.read().unwrap())
             .into_keys()
@@ -136,145 +331,23 @@ impl StoreEngine {
                     .unwrap()
                     .0
                     .clone();
-                self.dict.write().unwrap().remove(&key);
                 self.expiring_queue.write().unwrap().pop();
+                if self.del(&key) {
+                    ServerStats::incr(&self.stats.expired_keys, 1);
+                    ServerStats::incr(&self.stats.dirty, 1);
+                }
             }
             tokio::time::sleep(sleep_time).await;
         }
     }
+}
 
-    pub async fn handshake_to_master(&self) -> anyhow::Result<()> {
-        if let ReplicaType::Slave(master) = self.get_replica() {
-            let mut stream = TcpStream::connect(master).await?;
-
-            let (rx, tx) = stream.split();
-            let mut reader = BufReader::new(rx);
-            let mut writer = BufWriter::new(tx);
-
-            let redis_port = self.node_info.read().unwrap().port.clone();
-
-            // phase 1: send PING to master
-            let ping_cmd = array_to_resp_array(vec!["PING".to_string()]);
-
-            // phase 2-1: send REPLCONF listening-port
-            let replconf_cmd = array_to_resp_array(vec![
-                "REPLCONF".to_string(),
-                "listening-port".to_string(),
-                redis_port.clone(),
-            ]);
-
-            // pase 2-2: send REPLCONF capa psync2
-            let replconf_capa_cmd = array_to_resp_array(vec![
-                "REPLCONF".to_string(),
-                "capa".to_string(),
-                "psync2".to_string(),
-            ]);
-
-            // phase 3: send PSYNC
-            let psync_cmd = array_to_resp_array(vec![
-                "PSYNC".to_string(),
-                self.slave_info.read().unwrap().master_replid.clone(),
-                "-1".to_string(),
-            ]);
-            let mut buf = [0; 1024];
-            writer.write(ping_cmd.as_bytes()).await?;
-            writer.flush().await?;
-
-            match reader.read(&mut buf).await {
-                Ok(buf_len) => {
-                    let resp = String::from_utf8_lossy(buf[..buf_len].as_ref());
-                    if !resp.contains("+PONG") {
-                        return Err(anyhow::anyhow!("Handshake PING failed"));
-                    }
-                }
-                Err(e) => {
-                    return Err(anyhow::Error::new(e));
-                }
-            }
-
-            writer.write(replconf_cmd.as_bytes()).await?;
-            writer.flush().await?;
-            match reader.read(&mut buf).await {
-                Ok(buf_len) => {
-                    let resp = String::from_utf8_lossy(buf[..buf_len].as_ref());
-                    if !resp.contains("+OK") {
-                        return Err(anyhow::anyhow!("Handshake REPLCONF listening-port failed"));
-                    }
-                }
-                Err(e) => {
-                    return Err(anyhow::Error::new(e));
-                }
-            }
-
-            writer.write(replconf_capa_cmd.as_bytes()).await?;
-            writer.flush().await?;
-            match reader.read(&mut buf).await {
-                Ok(buf_len) => {
-                    let resp = String::from_utf8_lossy(buf[..buf_len].as_ref());
-                    if !resp.contains("+OK") {
-                        return Err(anyhow::anyhow!("Handshake REPLCONF capa psync2 failed"));
-                    }
-                }
-                Err(e) => {
-                    return Err(anyhow::Error::new(e));
-                }
-            }
-
-            writer.write(psync_cmd.as_bytes()).await?;
-            writer.flush().await?;
-
-            // read the command
-            loop {
-                let Ok(buf_len) = reader.read(&mut buf).await else {
-                    continue;
-                };
-
-                if buf_len == 0 {
-                    break;
-                }
-
-                let Ok(cmds) = command_parser(&mut buf[..buf_len]) else {
-                    continue;
-                };
-
-                // println!("cmds: {:?}", cmds);
-
-                for cmd in cmds {
-                    match cmd.clone() {
-                        RespCommandType::Set(key, value) => {
-                            self.set(key, value);
-                        }
-                        RespCommandType::SetPx(key, value, ttl) => {
-                            self.set_with_expire(key, value, ttl.into());
-                        }
-                        // reply ack with offset to the master
-                        RespCommandType::Replconf(key) => {
-                            // println!("receive healthcheck from master");
-                            if key == "getack" {
-                                // send ack to master
-                                let ack_offset = self.slave_info.read().unwrap().slave_repl_offset;
-
-                                let ack_cmd = array_to_resp_array(vec![
-                                    "REPLCONF".to_string(),
-                                    "ACK".to_string(),
-                                    format!("{}", ack_offset),
-                                ]);
-                                writer.write(ack_cmd.as_bytes()).await?;
-                                writer.flush().await?;
-                            }
-                        }
-                        _ => {}
-                    }
-
-                    // add offset to the slave
-                    let cmd_offset = count_resp_command_type_offset(cmd) as u64;
-                    self.slave_info.write().unwrap().slave_repl_offset += cmd_offset;
-                }
-            }
-        }
-
-        Ok(())
-    }
+// unix time in milliseconds
+pub fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
 }
 
 impl Default for StoreEngine {
@@ -1,22 +1,21 @@
 use super::engine::StoreEngine;
 use super::{HandshakeState, ReplicaType, SlaveInfo};
-use crate::engine::{array_to_resp_array, PING_LEN, REPL_GETACK_LEN};
+use crate::engine::array_to_resp_array;
 // use std::io::prelude::*;
-use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio::sync::Mutex;
-use tokio::time::{interval, sleep, Duration};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::{timeout_at, Duration, Instant};
 
 pub trait MasterEngine {
     fn get_master_id(&self) -> String {
-        return String::new();
+        String::new()
     }
     fn is_master(&self) -> bool {
-        return false;
+        false
     }
 
-    fn add_master_offset(&self, offset: u64);
+    // replid, replid2, offset and second_repl_offset as INFO shows them
+    fn get_repl_ids(&self) -> (String, String, u64, i64);
+
     fn get_master_offset(&self) -> u64;
 
     fn set_last_send_offset(&self, offset: u64);
@@ -28,32 +27,61 @@ pub trait MasterEngine {
 
     fn should_sync_command(&self) -> bool;
 
-    fn set_replicas(
+    fn add_replica(&self, host: String, sender: UnboundedSender<Vec<u8>>);
+    // forget a replica whose connection is gone or which stopped acking
+    fn remove_replica(&self, host: &str);
+    // our history changed, every replica has to resync with us
+    fn disconnect_replicas(&self);
+    // replicas silent for longer than repl-timeout are dropped
+    fn drop_timed_out_replicas(&self);
+    fn send_to_replica(&self, host: &str, payload: &[u8]) -> bool;
+    // the payload of its resync is out, INFO reports it online from then on
+    fn set_replica_online(&self, host: &str);
+
+    // everything sent to the replicas goes through here and into the backlog
+    // a replica feeds the stream of its master as it is to its own replicas
+    // the master offset right after the payload is returned
+    fn feed_replication_stream(&self, payload: &[u8]) -> u64;
+    fn sync_command(&self, cmds: Vec<Vec<String>>) -> u64;
+
+    // register the replica at the current offset of the replication stream
+    // the bytes after its offset for a partial resync, None if it needs a full one
+    fn full_resync(&self, host: String, sender: UnboundedSender<Vec<u8>>) -> u64;
+    fn partial_resync(
         &self,
         host: String,
-        stream: Arc<Mutex<OwnedWriteHalf>>,
-    ) -> impl std::future::Future<Output = ()> + Send;
-    fn sync_command(
-        &self,
-        cmd: String,
-    ) -> impl std::future::Future<Output = anyhow::Result<()>> + Send;
+        sender: UnboundedSender<Vec<u8>>,
+        replid: &str,
+        psync_offset: u64,
+    ) -> Result<Vec<u8>, UnboundedSender<Vec<u8>>>;
 
     fn healthcheck_to_slave(&self) -> impl std::future::Future<Output = anyhow::Result<()>> + Send;
 
     fn get_connected_replica_count(&self) -> u32;
-
-    // to collect wait time and count
-    fn set_last_set_offset(&self, offset: u64);
-    fn get_last_set_offset(&self) -> u64;
-
-    fn send_ack_to_slave(&self) -> impl std::future::Future<Output = ()> + Send;
+    // ip, listening port, state, acked offset and seconds since the last ack
+    // of each synced replica
+    fn get_replica_list(&self) -> Vec<(String, String, &'static str, u64, i64)>;
+    // size, first byte offset and histlen of the backlog
+    fn get_backlog_info(&self) -> (u64, u64, u64);
+    // replicas which acked within min-replicas-max-lag seconds
+    fn get_good_replica_count(&self) -> u32;
+    // min-replicas-to-write refuses writes without enough good replicas
+    fn has_enough_good_replicas(&self) -> bool;
+
+    // WAIT counts the replicas which acked up to offset
+    fn send_ack_to_slave(&self);
     fn get_ack_to_slave(&self) -> Vec<u64>;
 
-    fn check_replica_follow(&self) -> impl std::future::Future<Output = u32> + Send;
+    // a single GETACK broadcast serves every WAIT up to the offset it was sent at
+    fn request_ack(&self, offset: u64);
+    fn count_acked(&self, offset: u64) -> u32;
+    // wakes every WAIT, called for each REPLCONF ACK
+    fn notify_ack(&self);
     fn wait_replica(
         &self,
         wait_count: u64,
         wait_time: u64,
+        offset: u64,
     ) -> impl std::future::Future<Output = u32> + Send;
 }
 
@@ -66,36 +94,32 @@ impl MasterEngine for StoreEngine {
         self.get_replica() == ReplicaType::Master
     }
 
-    // offset operations for set only
-    fn add_master_offset(&self, offset: u64) {
-        if !self.is_master() {
-            return;
-        }
-        self.master_info.write().unwrap().master_repl_offset += offset;
-        let master_offset = self.get_master_offset();
-
-        // we update the last set offset of the master offset
-        self.set_last_set_offset(master_offset);
-    }
-    fn get_master_offset(&self) -> u64 {
-        if !self.is_master() {
-            return 0;
-        }
-        self.master_info.read().unwrap().master_repl_offset
+    fn get_repl_ids(&self) -> (String, String, u64, i64) {
+        let master_info = self.master_info.read().unwrap();
+        let (replid, offset) = match self.get_replica() {
+            ReplicaType::Master => (
+                master_info.master_replid.clone(),
+                master_info.backlog.end_offset(),
+            ),
+            ReplicaType::Slave(_) => {
+                let slave_info = self.slave_info.read().unwrap();
+                (
+                    slave_info.master_replid.clone(),
+                    slave_info.slave_repl_offset,
+                )
+            }
+        };
+        (
+            replid,
+            master_info.master_replid2.clone(),
+            offset,
+            master_info.second_repl_offset,
+        )
     }
 
-    // set last set offset
-    fn set_last_set_offset(&self, offset: u64) {
-        if !self.is_master() {
-            return;
-        }
-        self.master_info.write().unwrap().last_set_offset = offset;
-    }
-    fn get_last_set_offset(&self) -> u64 {
-        if !self.is_master() {
-            return 0;
-        }
-        self.master_info.read().unwrap().last_set_offset
+    // the exact number of bytes written to the replication stream so far
+    fn get_master_offset(&self) -> u64 {
+        self.master_info.read().unwrap().backlog.end_offset()
     }
 
     fn set_last_send_offset(&self, offset: u64) {
@@ -117,25 +141,25 @@ impl MasterEngine for StoreEngine {
             port: stream_port,
             master_replid: self.get_master_id(),
             slave_repl_offset: 0,
-            slave_ping_count: 0,
-            slave_ack_count: 0,
+            last_ack: None,
             handshake_state,
+            online: false,
         };
 
-        match self
+        if let Some(old_slave) = self
             .master_info
             .read()
             .unwrap()
             .slave_list
             .get(&host.clone())
         {
-            Some(old_slave) => {
-                slave.port = old_slave.port.clone();
-                slave.slave_repl_offset = old_slave.slave_repl_offset;
-                slave.slave_ping_count = old_slave.slave_ping_count;
-                slave.slave_ack_count = old_slave.slave_ack_count;
-            }
-            None => {}
+            slave.port = old_slave.port.clone();
+            slave.slave_repl_offset = old_slave.slave_repl_offset;
+            slave.last_ack = old_slave.last_ack;
+        }
+        // the timeout runs from the sync until the first ack
+        if slave.handshake_state == HandshakeState::Psync {
+            slave.last_ack = Some(std::time::Instant::now());
         }
 
         // to avoid deadlock
@@ -165,6 +189,7 @@ impl MasterEngine for StoreEngine {
         {
             let mut slave = slave.clone();
             slave.slave_repl_offset = offset;
+            slave.last_ack = Some(std::time::Instant::now());
             new_slave = slave;
         } else {
             return;
@@ -180,51 +205,124 @@ impl MasterEngine for StoreEngine {
     }
 
     fn should_sync_command(&self) -> bool {
-        self.is_master() && self.master_info.read().unwrap().slave_list.len() > 0
+        self.is_master() && !self.master_info.read().unwrap().slave_list.is_empty()
     }
 
-    async fn set_replicas(&self, host: String, stream: Arc<Mutex<OwnedWriteHalf>>) {
-        self.replicas
-            .write()
-            .await
-            .insert(host.clone(), stream.clone());
+    fn add_replica(&self, host: String, sender: UnboundedSender<Vec<u8>>) {
+        self.replicas.write().unwrap().insert(host, sender);
     }
 
-    async fn sync_command(&self, cmd: String) -> anyhow::Result<()> {
-        if !self.should_sync_command() {
-            return Err(anyhow::anyhow!("err: should not sync command"));
+    fn remove_replica(&self, host: &str) {
+        let removed = self.master_info.write().unwrap().slave_list.remove(host);
+        self.replicas.write().unwrap().remove(host);
+        if removed.is_some() {
+            println!("replica {} removed", host);
         }
+    }
 
-        let _master_replid = self.get_master_id();
-        let mut slave_list = self.master_info.read().unwrap().slave_list.clone();
+    fn disconnect_replicas(&self) {
+        let hosts: Vec<String> = self.replicas.read().unwrap().keys().cloned().collect();
+        for host in hosts {
+            self.remove_replica(&host);
+        }
+        self.master_info.write().unwrap().slave_list.clear();
+    }
 
-        let cmd_vec: Vec<String> = cmd.split_whitespace().map(|s| s.to_string()).collect();
+    fn drop_timed_out_replicas(&self) {
+        let timeout = self.config.read().unwrap().repl_timeout;
+        let timed_out: Vec<String> = self
+            .master_info
+            .read()
+            .unwrap()
+            .slave_list
+            .values()
+            .filter(|slave| slave.handshake_state == HandshakeState::Psync)
+            .filter(|slave| {
+                slave
+                    .last_ack
+                    .is_some_and(|at| at.elapsed().as_secs() > timeout)
+            })
+            .map(|slave| slave.host.clone())
+            .collect();
 
-        for (host, slave) in slave_list.iter_mut() {
-            let cmd_vec1 = cmd_vec.clone();
+        for host in timed_out {
+            println!("err: replica {} timed out", host);
+            self.remove_replica(&host);
+        }
+    }
 
-            if slave.handshake_state == HandshakeState::Psync {
-                // send command to slave
-                let cmd = array_to_resp_array(cmd_vec1);
-                if let Some(stream) = self.replicas.read().await.get(&host.clone()) {
-                    let mut stream = stream.lock().await;
-                    match stream.write_all(&cmd.as_bytes()).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            println!("err: {}", e);
-                        }
-                    }
-                }
+    // false once the connection of the replica is gone
+    fn send_to_replica(&self, host: &str, payload: &[u8]) -> bool {
+        match self.replicas.read().unwrap().get(host) {
+            Some(sender) => sender.send(payload.to_vec()).is_ok(),
+            None => false,
+        }
+    }
+
+    fn set_replica_online(&self, host: &str) {
+        if let Some(slave) = self.master_info.write().unwrap().slave_list.get_mut(host) {
+            slave.online = true;
+        }
+    }
+
+    // the backlog and the replica channels are updated under the same lock
+    // so a replica registered meanwhile misses nothing and gets nothing twice
+    fn feed_replication_stream(&self, payload: &[u8]) -> u64 {
+        let mut master_info = self.master_info.write().unwrap();
+        master_info.backlog.feed(payload);
+
+        for (host, sender) in self.replicas.read().unwrap().iter() {
+            if sender.send(payload.to_vec()).is_err() {
+                println!("err: replica {} is gone", host);
             }
         }
-        Ok(())
+        master_info.backlog.end_offset()
     }
 
-    #[allow(unreachable_code)]
-    async fn healthcheck_to_slave(&self) -> anyhow::Result<()> {
+    fn sync_command(&self, cmds: Vec<Vec<String>>) -> u64 {
+        // the writes to a writable replica stay local
         if !self.is_master() {
-            return Err(anyhow::anyhow!("err: not master"));
+            return self.get_master_offset();
         }
+        // all the commands of a write are sent in one go
+        let payload: String = cmds.into_iter().map(array_to_resp_array).collect();
+        self.feed_replication_stream(payload.as_bytes())
+    }
+
+    fn full_resync(&self, host: String, sender: UnboundedSender<Vec<u8>>) -> u64 {
+        let master_info = self.master_info.read().unwrap();
+        self.add_replica(host, sender);
+        master_info.backlog.end_offset()
+    }
+
+    fn partial_resync(
+        &self,
+        host: String,
+        sender: UnboundedSender<Vec<u8>>,
+        replid: &str,
+        psync_offset: u64,
+    ) -> Result<Vec<u8>, UnboundedSender<Vec<u8>>> {
+        // the previous history is valid up to the point where it was left
+        let master_info = self.master_info.read().unwrap();
+        let same_history = replid == master_info.master_replid
+            || (replid == master_info.master_replid2
+                && psync_offset as i64 <= master_info.second_repl_offset);
+        if !same_history || psync_offset == 0 {
+            return Err(sender);
+        }
+
+        // the replica asks for the first byte it doesn't have yet
+        match master_info.backlog.range_from(psync_offset - 1) {
+            Some(payload) => {
+                self.add_replica(host, sender);
+                Ok(payload)
+            }
+            None => Err(sender),
+        }
+    }
+
+    #[allow(unreachable_code)]
+    async fn healthcheck_to_slave(&self) -> anyhow::Result<()> {
         // let get_ack_cmd = array_to_resp_array(vec![
         //     "REPLCONF".to_string(),
         //     "GETACK".to_string(),
@@ -233,25 +331,10 @@ impl MasterEngine for StoreEngine {
         let ping_cmd = array_to_resp_array(vec!["PING".to_string()]);
 
         loop {
-            let mut slave_list = self.master_info.read().unwrap().slave_list.clone();
-
-            for (host, slave) in slave_list.iter_mut() {
-                if slave.handshake_state == HandshakeState::Psync {
-                    // send command to slave
-                    if let Some(stream) = self.replicas.read().await.get(&host.clone()) {
-                        let mut stream = stream.lock().await;
-                        match stream.write_all(&ping_cmd.as_bytes()).await {
-                            Ok(_) => {
-                                // println!("sent healthcheck to slave: {}", host);
-                                slave.slave_ping_count += 1;
-                            }
-                            Err(e) => {
-                                println!("err: {}", e);
-                            }
-                        }
-                    }
-                }
+            if self.should_sync_command() {
+                self.feed_replication_stream(ping_cmd.as_bytes());
             }
+            self.drop_timed_out_replicas();
             // [TBD] perhaps we shall update the ping count back
 
             tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
@@ -265,8 +348,8 @@ impl MasterEngine for StoreEngine {
             .read()
             .unwrap()
             .slave_list
-            .iter()
-            .map(|(_, v)| {
+            .values()
+            .map(|v| {
                 if v.handshake_state == HandshakeState::Psync {
                     1
                 } else {
@@ -276,119 +359,234 @@ impl MasterEngine for StoreEngine {
             .sum()
     }
 
-    async fn send_ack_to_slave(&self) {
-        // println!("send_ack_to_slave");
-
-        let mut slave_list = self.master_info.read().unwrap().slave_list.clone();
-
-        for (host, slave) in slave_list.iter_mut() {
-            if slave.handshake_state == HandshakeState::Psync {
-                let get_ack_cmd = array_to_resp_array(vec![
-                    "REPLCONF".to_string(),
-                    "GETACK".to_string(),
-                    "*".to_string(),
-                ]);
-                // send command to slave
-                if let Some(stream) = self.replicas.read().await.get(&host.clone()) {
-                    let mut stream = stream.lock().await;
-                    match stream.write_all(&get_ack_cmd.as_bytes()).await {
-                        Ok(_) => {
-                            // here we need to wait for the ack from the slave
-                            // println!(
-                            //     "sent getack to slave: {} {}, {}",
-                            //     host, offset, slave.slave_ack_count
-                            // );
-
-                            // we send one more ack to slave
-                            slave.slave_ack_count += 1;
-                        }
-                        Err(e) => {
-                            println!("err: {}", e);
-                        }
-                    }
-                }
-            }
+    fn get_replica_list(&self) -> Vec<(String, String, &'static str, u64, i64)> {
+        let mut replicas: Vec<_> = self
+            .master_info
+            .read()
+            .unwrap()
+            .slave_list
+            .values()
+            .filter(|slave| slave.handshake_state == HandshakeState::Psync)
+            .map(|slave| {
+                let ip = slave
+                    .host
+                    .rsplit_once(':')
+                    .map_or(slave.host.as_str(), |(ip, _)| ip)
+                    .to_string();
+                let lag = slave
+                    .last_ack
+                    .map_or(-1, |at| at.elapsed().as_secs() as i64);
+                // the snapshot is taken right at PSYNC, there is no wait_bgsave
+                let state = if slave.online { "online" } else { "send_bulk" };
+                (ip, slave.port.clone(), state, slave.slave_repl_offset, lag)
+            })
+            .collect();
+        // slaveN keeps the same numbering between two INFO
+        replicas.sort();
+        replicas
+    }
+
+    fn get_backlog_info(&self) -> (u64, u64, u64) {
+        let backlog = &self.master_info.read().unwrap().backlog;
+        (
+            backlog.size(),
+            backlog.first_byte_offset(),
+            backlog.histlen(),
+        )
+    }
+
+    fn get_good_replica_count(&self) -> u32 {
+        let max_lag = self.config.read().unwrap().min_replicas_max_lag;
+        self.master_info
+            .read()
+            .unwrap()
+            .slave_list
+            .values()
+            .filter(|slave| slave.handshake_state == HandshakeState::Psync)
+            .filter(|slave| {
+                slave
+                    .last_ack
+                    .is_some_and(|at| at.elapsed().as_secs() <= max_lag)
+            })
+            .count() as u32
+    }
+
+    fn has_enough_good_replicas(&self) -> bool {
+        let min_replicas = self.config.read().unwrap().min_replicas_to_write;
+        min_replicas == 0 || !self.is_master() || self.get_good_replica_count() >= min_replicas
+    }
+
+    fn send_ack_to_slave(&self) {
+        // a replica forwards the GETACKs of its master
+        if !self.is_master() {
+            return;
         }
-        let master_offset = self.get_master_offset();
-        self.set_last_set_offset(master_offset);
+        let get_ack_cmd = array_to_resp_array(vec![
+            "REPLCONF".to_string(),
+            "GETACK".to_string(),
+            "*".to_string(),
+        ]);
+        let offset = self.feed_replication_stream(get_ack_cmd.as_bytes());
+        self.set_last_send_offset(offset);
     }
 
+    // the offsets the replicas acked, exactly as they counted them
     fn get_ack_to_slave(&self) -> Vec<u64> {
-        let mut ack_count = Vec::new();
-        let mut slave_list = self.master_info.read().unwrap().slave_list.clone();
+        self.master_info
+            .read()
+            .unwrap()
+            .slave_list
+            .values()
+            .filter(|slave| slave.handshake_state == HandshakeState::Psync)
+            .map(|slave| slave.slave_repl_offset)
+            .collect()
+    }
+
+    fn request_ack(&self, offset: u64) {
+        // the acks to a GETACK sent after offset tell whether the replicas got that far
+        if self.get_last_send_offset() < offset && self.get_connected_replica_count() > 0 {
+            self.send_ack_to_slave();
+        }
+    }
+
+    fn count_acked(&self, offset: u64) -> u32 {
+        self.get_ack_to_slave()
+            .iter()
+            .filter(|ack| **ack >= offset)
+            .count() as u32
+    }
+
+    fn notify_ack(&self) {
+        self.ack_notify.notify_waiters();
+    }
+
+    async fn wait_replica(&self, wait_count: u64, wait_time: u64, offset: u64) -> u32 {
+        // a zero timeout waits for as long as it takes
+        let deadline = (wait_time > 0).then(|| Instant::now() + Duration::from_millis(wait_time));
 
-        for (_host, slave) in slave_list.iter_mut() {
-            if slave.handshake_state == HandshakeState::Psync {
-                let mut offset = slave.slave_repl_offset;
+        loop {
+            // listen before counting so an ack arriving in between isn't missed
+            let notified = self.ack_notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let count = self.count_acked(offset);
+            if u64::from(count) >= wait_count {
+                return count;
+            }
+            self.request_ack(offset);
 
-                offset -= (slave.slave_ack_count - 1) * PING_LEN as u64
-                    + slave.slave_ping_count * REPL_GETACK_LEN as u64;
-                ack_count.push(offset);
+            match deadline {
+                None => notified.await,
+                Some(deadline) => {
+                    if timeout_at(deadline, notified).await.is_err() {
+                        return self.count_acked(offset);
+                    }
+                }
             }
         }
+    }
+}
 
-        ack_count
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    // a replica past its PSYNC, what the master sends it ends up in the receiver
+    fn add_synced_replica(db: &StoreEngine, host: &str) -> UnboundedReceiver<Vec<u8>> {
+        let (sender, receiver) = unbounded_channel();
+        db.set_slave_node(host.to_string(), "6380".to_string(), HandshakeState::Psync);
+        db.full_resync(host.to_string(), sender);
+        receiver
     }
 
-    async fn check_replica_follow(&self) -> u32 {
+    fn getacks_sent(receiver: &mut UnboundedReceiver<Vec<u8>>) -> usize {
         let mut count = 0;
-        let master_offset = self.get_master_offset();
-        let last_send_offset = self.get_last_send_offset();
-        // println!(
-        //     "master_offset: {}, last_send_offset: {}",
-        //     master_offset, last_send_offset
-        // );
-
-        // the master offset is updated. then, we need to send another ack to the slaves
-        if master_offset != last_send_offset {
-            self.send_ack_to_slave().await;
-            self.set_last_send_offset(master_offset);
-        }
-
-        // iterate slave list to run replconf getack * to verify the replica's offset has reached the last set offset
-        let slave_offsets = self.get_ack_to_slave();
-        let last_set_offset = self.get_last_set_offset();
-        // println!(
-        //     "ack_list: {:?}, last_set_offset: {}",
-        //     slave_offsets, last_set_offset
-        // );
-
-        for offset in slave_offsets.iter() {
-            if offset >= &last_set_offset {
-                count += 1;
-            }
+        while let Ok(payload) = receiver.try_recv() {
+            count += String::from_utf8_lossy(&payload).matches("GETACK").count();
         }
         count
     }
 
-    async fn wait_replica(&self, wait_count: u64, wait_time: u64) -> u32 {
-        let mut count = 0;
-        let mut ticker = interval(Duration::from_millis(20));
-        // let timeout = sleep(Duration::from_millis(wait_time));
-        // [TBD] offset counting
+    #[tokio::test]
+    async fn test_wait_replica() {
+        let db = Arc::new(StoreEngine::new());
+        let mut first = add_synced_replica(&db, "127.0.0.1:50001");
+        let mut second = add_synced_replica(&db, "127.0.0.1:50002");
+
+        let set = vec!["SET".to_string(), "k".to_string(), "v".to_string()];
+        let before = db.sync_command(vec![set.clone()]);
+        let after = db.sync_command(vec![set.clone()]);
+
+        // two clients waiting on different offsets share the GETACK of the first one
+        let waits: Vec<_> = [before, after]
+            .into_iter()
+            .map(|offset| {
+                let db = db.clone();
+                tokio::spawn(async move { db.wait_replica(2, 0, offset).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(getacks_sent(&mut first), 1);
+        assert_eq!(getacks_sent(&mut second), 1);
+
+        // the replicas ack the offset right after the GETACK
+        let acked = db.get_master_offset();
+        db.set_slave_offset("127.0.0.1:50001".to_string(), acked);
+        db.set_slave_offset("127.0.0.1:50002".to_string(), acked);
+        db.notify_ack();
+        for wait in waits {
+            assert_eq!(wait.await.unwrap(), 2);
+        }
 
-        loop {
-            tokio::select! {
-                _ = sleep(Duration::from_millis(wait_time)) => {
-                    count = self.check_replica_follow().await;
-                    break;
-                },
-                _ = async {
-                    loop {
-                        ticker.tick().await;
-                        // execute check replica follow
-                        count = self.check_replica_follow().await;
-                        if u64::from(count) >= wait_count {
-                            break;
-                        }
-                    }
-                } => {
-                    break;
-                },
-                else => {
-                },
-            };
+        // only one of them gets the next write before the timeout
+        let offset = db.sync_command(vec![set]);
+        let wait = {
+            let db = db.clone();
+            tokio::spawn(async move { db.wait_replica(2, 200, offset).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        db.set_slave_offset("127.0.0.1:50001".to_string(), db.get_master_offset());
+        db.notify_ack();
+
+        let start = Instant::now();
+        assert_eq!(wait.await.unwrap(), 1);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(getacks_sent(&mut second), 1);
+    }
+
+    #[test]
+    fn test_drop_timed_out_replicas() {
+        let db = StoreEngine::new();
+        let mut silent = add_synced_replica(&db, "127.0.0.1:50001");
+        let _acking = add_synced_replica(&db, "127.0.0.1:50002");
+        db.drop_timed_out_replicas();
+        assert_eq!(db.get_connected_replica_count(), 2);
+
+        // no ack for longer than repl-timeout
+        let timeout = db.config.read().unwrap().repl_timeout;
+        if let Some(slave) = db
+            .master_info
+            .write()
+            .unwrap()
+            .slave_list
+            .get_mut("127.0.0.1:50001")
+        {
+            let aged = std::time::Instant::now() - Duration::from_secs(timeout + 1);
+            slave.last_ack = Some(aged);
         }
-        count
+        db.drop_timed_out_replicas();
+
+        assert_eq!(db.get_connected_replica_count(), 1);
+        assert_eq!(db.get_good_replica_count(), 1);
+        assert!(db.get_slave_node("127.0.0.1:50001".to_string()).is_none());
+        // nothing is sent to it anymore, its connection gets closed
+        assert!(!db.send_to_replica("127.0.0.1:50001", b"+PING\r\n"));
+        assert!(matches!(
+            silent.try_recv(),
+            Err(tokio::sync::mpsc::error::TryRecvError::Disconnected)
+        ));
     }
 }
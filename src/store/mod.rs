@@ -1,11 +1,40 @@
+pub mod backlog;
+pub mod config;
 pub mod engine;
 pub mod master_engine;
-pub mod replicator;
+pub mod slave_engine;
+pub mod stats;
 pub mod stream_engine;
 
+use backlog::{ReplBacklog, DEFAULT_BACKLOG_SIZE};
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-const MYID: &str = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
+// replid2 of a node which never changed its history
+pub const NULL_REPLID: &str = "0000000000000000000000000000000000000000";
+
+// every instance starts its own history with a random 40 hex chars id
+pub fn random_replid() -> String {
+    let mut bytes = [0u8; 20];
+    let urandom = File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes));
+    if urandom.is_err() {
+        // randomly seeded hashers of the clock as a fallback
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        for chunk in bytes.chunks_mut(8) {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(nanos);
+            chunk.copy_from_slice(&hasher.finish().to_le_bytes()[..chunk.len()]);
+        }
+    }
+    hex::encode(bytes)
+}
 
 #[derive(Clone, PartialEq)]
 pub enum HandshakeState {
@@ -21,17 +50,48 @@ pub enum ReplicaType {
     Slave(String),
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum LinkStatus {
+    Down,
+    Connecting,
+    Sync,
+    Up,
+}
+
+// state of the link of a replica to its master
+pub struct MasterLink {
+    pub status: LinkStatus,
+    pub last_io: Option<Instant>,
+    pub down_since: Option<Instant>,
+    // read from the stream but not applied yet, the start of a split frame
+    pub read_ahead: u64,
+}
+
+impl Default for MasterLink {
+    fn default() -> Self {
+        MasterLink {
+            status: LinkStatus::Down,
+            last_io: None,
+            down_since: None,
+            read_ahead: 0,
+        }
+    }
+}
+
 pub struct NodeInfo {
     port: String,
 }
 #[allow(dead_code)]
 pub struct MasterInfo {
     master_replid: String,
-    master_repl_offset: u64,
+    // the previous history, a replica of it can still resync partially up to second_repl_offset
+    master_replid2: String,
+    second_repl_offset: i64,
+    // offset of the stream when the last GETACK went out
     last_send_repl_offset: u64,
-    last_set_offset: u64,
     pub handshake_state: HandshakeState,
     slave_list: HashMap<String, SlaveInfo>,
+    pub backlog: ReplBacklog,
 }
 #[allow(dead_code)]
 #[derive(Clone)]
@@ -39,10 +99,13 @@ pub struct SlaveInfo {
     host: String,
     pub port: String,
     master_replid: String,
+    // acked offset of a replica, processed offset on the replica itself
     slave_repl_offset: u64,
-    slave_ping_count: u64,
-    slave_ack_count: u64,
+    // when the replica acked for the last time, only known on the master
+    last_ack: Option<Instant>,
     pub handshake_state: HandshakeState,
+    // the rdb or the backlog of its resync was sent, only the stream follows
+    pub online: bool,
 }
 
 impl Default for NodeInfo {
@@ -56,12 +119,13 @@ impl Default for NodeInfo {
 impl Default for MasterInfo {
     fn default() -> Self {
         MasterInfo {
-            master_replid: MYID.to_string(),
-            master_repl_offset: 0,
+            master_replid: random_replid(),
+            master_replid2: NULL_REPLID.to_string(),
+            second_repl_offset: -1,
             last_send_repl_offset: 0,
-            last_set_offset: 0,
             handshake_state: HandshakeState::Ping,
             slave_list: HashMap::new(),
+            backlog: ReplBacklog::new(DEFAULT_BACKLOG_SIZE),
         }
     }
 }
@@ -73,9 +137,22 @@ impl Default for SlaveInfo {
             port: String::new(),
             master_replid: "?".to_string(),
             slave_repl_offset: 0,
-            slave_ping_count: 0,
-            slave_ack_count: 0,
+            last_ack: None,
             handshake_state: HandshakeState::Ping,
+            online: false,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_random_replid() {
+        let replid = random_replid();
+        assert_eq!(replid.len(), 40);
+        assert!(replid.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(replid, random_replid());
+    }
+}
@@ -0,0 +1,457 @@
+use super::engine::StoreEngine;
+use super::master_engine::MasterEngine;
+use super::{LinkStatus, ReplicaType, NULL_REPLID};
+use crate::aof::writer::AOFWriter;
+use crate::engine::array_to_resp_array;
+use crate::engine::commands::{apply_command, apply_transaction};
+use crate::engine::parser::{parse_repl_frame, ReplFrame};
+use crate::engine::RespMessage;
+use crate::rdb::loader::RDBLoader;
+use std::io::Cursor;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::TcpStream;
+use tokio::time::{interval, sleep, Duration};
+
+// delay before reconnecting to the master, doubled after every failure
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5);
+// the master hears from us at least this often, it tells how far we got and that we are alive
+const REPLICA_ACK_PERIOD: Duration = Duration::from_secs(1);
+
+pub trait SlaveEngine {
+    fn get_slave_offset(&self) -> u64;
+    fn add_slave_offset(&self, offset: u64);
+
+    // FULLRESYNC <replid> <offset> starts the replica over from the master's offset
+    fn set_master_replid(&self, replid: String, offset: u64);
+    // +CONTINUE <replid> goes on with the same offset, possibly under a new id
+    fn continue_master_replid(&self, replid: String);
+    // the bytes of a command of the master go on to our own replicas
+    fn feed_from_master(&self, payload: &[u8]);
+
+    fn set_link_status(&self, status: LinkStatus);
+    // status, seconds since the last io and since the link is down, -1 if unknown
+    fn get_master_link_info(&self) -> (LinkStatus, i64, i64);
+    // the offset of what was read from the master, applied or not
+    fn get_read_repl_offset(&self) -> u64;
+    fn touch_master_link(&self);
+
+    // keeps the link to the master up for as long as we are a replica
+    fn run_master_link(self: &Arc<Self>) -> impl std::future::Future<Output = ()> + Send;
+    // (re)start the link task, a running one is torn down first
+    fn start_master_link(self: &Arc<Self>);
+    fn stop_master_link(&self);
+
+    fn handshake_to_master(
+        self: &Arc<Self>,
+    ) -> impl std::future::Future<Output = anyhow::Result<()>> + Send;
+}
+
+impl SlaveEngine for StoreEngine {
+    fn get_slave_offset(&self) -> u64 {
+        self.slave_info.read().unwrap().slave_repl_offset
+    }
+
+    fn add_slave_offset(&self, offset: u64) {
+        self.slave_info.write().unwrap().slave_repl_offset += offset;
+    }
+
+    fn set_master_replid(&self, replid: String, offset: u64) {
+        {
+            let mut slave_info = self.slave_info.write().unwrap();
+            slave_info.master_replid = replid.clone();
+            slave_info.slave_repl_offset = offset;
+        }
+        // our history is now the one of the master, from its offset on
+        {
+            let mut master_info = self.master_info.write().unwrap();
+            master_info.master_replid = replid;
+            master_info.master_replid2 = NULL_REPLID.to_string();
+            master_info.second_repl_offset = -1;
+            master_info.backlog.reset(offset);
+        }
+        // the dataset is replaced, our replicas need it as well
+        self.disconnect_replicas();
+    }
+
+    fn continue_master_replid(&self, replid: String) {
+        let offset = self.get_slave_offset();
+        self.slave_info.write().unwrap().master_replid = replid.clone();
+
+        let changed = {
+            let mut master_info = self.master_info.write().unwrap();
+            if master_info.master_replid == replid {
+                false
+            } else {
+                // our replicas may still continue with the previous id
+                master_info.master_replid2 =
+                    std::mem::replace(&mut master_info.master_replid, replid);
+                master_info.second_repl_offset = offset as i64 + 1;
+                true
+            }
+        };
+        if changed {
+            self.disconnect_replicas();
+        }
+    }
+
+    fn feed_from_master(&self, payload: &[u8]) {
+        self.feed_replication_stream(payload);
+        self.add_slave_offset(payload.len() as u64);
+    }
+
+    fn set_link_status(&self, status: LinkStatus) {
+        let mut link = self.master_link.write().unwrap();
+        match status {
+            LinkStatus::Up => link.down_since = None,
+            // the link stays down from the first failure on
+            LinkStatus::Down | LinkStatus::Connecting | LinkStatus::Sync => {
+                if link.status == LinkStatus::Up || link.down_since.is_none() {
+                    link.down_since = Some(Instant::now());
+                }
+            }
+        }
+        // a new link starts reading from the offset it was applied up to
+        if status != LinkStatus::Up {
+            link.read_ahead = 0;
+        }
+        link.status = status;
+    }
+
+    fn get_master_link_info(&self) -> (LinkStatus, i64, i64) {
+        let link = self.master_link.read().unwrap();
+        let seconds = |at: Option<Instant>| at.map_or(-1, |at| at.elapsed().as_secs() as i64);
+        // no io to speak of without a master
+        let last_io = match link.status {
+            LinkStatus::Up => seconds(link.last_io),
+            _ => -1,
+        };
+        (link.status, last_io, seconds(link.down_since))
+    }
+
+    fn get_read_repl_offset(&self) -> u64 {
+        self.get_slave_offset() + self.master_link.read().unwrap().read_ahead
+    }
+
+    fn touch_master_link(&self) {
+        self.master_link.write().unwrap().last_io = Some(Instant::now());
+    }
+
+    async fn run_master_link(self: &Arc<Self>) {
+        let mut delay = RECONNECT_MIN_DELAY;
+        while let ReplicaType::Slave(master) = self.get_replica() {
+            self.set_link_status(LinkStatus::Connecting);
+            match self.handshake_to_master().await {
+                Ok(()) => println!("connection with master {} lost", master),
+                Err(e) => println!("err: master {}: {}", master, e),
+            }
+
+            // a link which made it to the sync starts over with a short delay
+            let was_up = self.master_link.read().unwrap().status == LinkStatus::Up;
+            self.set_link_status(LinkStatus::Down);
+            if was_up {
+                delay = RECONNECT_MIN_DELAY;
+            }
+
+            sleep(delay).await;
+            delay = next_reconnect_delay(delay);
+        }
+    }
+
+    fn start_master_link(self: &Arc<Self>) {
+        self.stop_master_link();
+
+        let db = self.clone();
+        let task = tokio::spawn(async move {
+            db.run_master_link().await;
+        });
+        *self.master_link_task.lock().unwrap() = Some(task);
+    }
+
+    fn stop_master_link(&self) {
+        if let Some(task) = self.master_link_task.lock().unwrap().take() {
+            task.abort();
+        }
+        self.set_link_status(LinkStatus::Down);
+    }
+
+    async fn handshake_to_master(self: &Arc<Self>) -> anyhow::Result<()> {
+        if let ReplicaType::Slave(master) = self.get_replica() {
+            let mut stream = TcpStream::connect(master.clone()).await?;
+            // acks are small writes, they shouldn't wait for nagle
+            stream.set_nodelay(true)?;
+
+            let (rx, tx) = stream.split();
+            let mut reader = BufReader::new(rx);
+            let mut writer = BufWriter::new(tx);
+
+            let redis_port = self.node_info.read().unwrap().port.clone();
+
+            // phase 1: send PING to master
+            let ping_cmd = array_to_resp_array(vec!["PING".to_string()]);
+
+            // phase 2-1: send REPLCONF listening-port
+            let replconf_cmd = array_to_resp_array(vec![
+                "REPLCONF".to_string(),
+                "listening-port".to_string(),
+                redis_port.clone(),
+            ]);
+
+            // pase 2-2: send REPLCONF capa psync2
+            let replconf_capa_cmd = array_to_resp_array(vec![
+                "REPLCONF".to_string(),
+                "capa".to_string(),
+                "psync2".to_string(),
+            ]);
+
+            // phase 3: send PSYNC
+            // a replica which already has a history asks for the byte after its offset
+            let (replid, offset) = {
+                let slave_info = self.slave_info.read().unwrap();
+                match slave_info.master_replid.as_str() {
+                    "?" => ("?".to_string(), "-1".to_string()),
+                    replid => (
+                        replid.to_string(),
+                        (slave_info.slave_repl_offset + 1).to_string(),
+                    ),
+                }
+            };
+            let psync_cmd = array_to_resp_array(vec!["PSYNC".to_string(), replid, offset]);
+            let mut buf = [0; 1024];
+            writer.write_all(ping_cmd.as_bytes()).await?;
+            writer.flush().await?;
+
+            match reader.read(&mut buf).await {
+                Ok(buf_len) => {
+                    let resp = String::from_utf8_lossy(buf[..buf_len].as_ref());
+                    if !resp.contains("+PONG") {
+                        return Err(anyhow::anyhow!("Handshake PING failed"));
+                    }
+                }
+                Err(e) => {
+                    return Err(anyhow::Error::new(e));
+                }
+            }
+
+            writer.write_all(replconf_cmd.as_bytes()).await?;
+            writer.flush().await?;
+            match reader.read(&mut buf).await {
+                Ok(buf_len) => {
+                    let resp = String::from_utf8_lossy(buf[..buf_len].as_ref());
+                    if !resp.contains("+OK") {
+                        return Err(anyhow::anyhow!("Handshake REPLCONF listening-port failed"));
+                    }
+                }
+                Err(e) => {
+                    return Err(anyhow::Error::new(e));
+                }
+            }
+
+            writer.write_all(replconf_capa_cmd.as_bytes()).await?;
+            writer.flush().await?;
+            match reader.read(&mut buf).await {
+                Ok(buf_len) => {
+                    let resp = String::from_utf8_lossy(buf[..buf_len].as_ref());
+                    if !resp.contains("+OK") {
+                        return Err(anyhow::anyhow!("Handshake REPLCONF capa psync2 failed"));
+                    }
+                }
+                Err(e) => {
+                    return Err(anyhow::Error::new(e));
+                }
+            }
+
+            writer.write_all(psync_cmd.as_bytes()).await?;
+            writer.flush().await?;
+            self.set_link_status(LinkStatus::Sync);
+
+            // the replication stream: reply of PSYNC, the rdb and then the commands
+            // a frame may be split over several reads
+            let mut pending: Vec<u8> = Vec::new();
+            let mut multi: Option<Vec<Arc<RwLock<RespMessage>>>> = None;
+            let mut ack_ticker = interval(REPLICA_ACK_PERIOD);
+            loop {
+                let buf_len = tokio::select! {
+                    read = reader.read(&mut buf) => read?,
+                    _ = ack_ticker.tick() => {
+                        if self.master_link.read().unwrap().status == LinkStatus::Up {
+                            writer.write_all(replconf_ack(self.get_slave_offset()).as_bytes()).await?;
+                            writer.flush().await?;
+                        }
+                        continue;
+                    }
+                };
+                if buf_len == 0 {
+                    break;
+                }
+                self.touch_master_link();
+                pending.extend_from_slice(&buf[..buf_len]);
+
+                let mut consumed = 0;
+                while let Some((frame, frame_len)) = parse_repl_frame(&pending[consumed..])? {
+                    let raw = &pending[consumed..consumed + frame_len];
+                    consumed += frame_len;
+                    match frame {
+                        ReplFrame::Simple(line) => {
+                            let parts: Vec<&str> = line.split(' ').collect();
+                            match parts[..] {
+                                ["FULLRESYNC", replid, offset] => {
+                                    self.set_master_replid(
+                                        replid.to_string(),
+                                        offset.parse::<u64>().unwrap_or(0),
+                                    );
+                                }
+                                // the stream goes on from our offset, possibly under a new id
+                                ["CONTINUE", replid] => {
+                                    self.continue_master_replid(replid.to_string());
+                                    self.set_link_status(LinkStatus::Up);
+                                }
+                                ["CONTINUE"] => self.set_link_status(LinkStatus::Up),
+                                _ => {}
+                            }
+                        }
+                        // the dataset of the master replaces ours before any command is applied
+                        ReplFrame::Rdb(rdb) => {
+                            // clients get LOADING until the new dataset is in place
+                            self.stats.loading.store(true, Ordering::Relaxed);
+                            self.flush_all();
+                            let loaded = self.parse(&mut Cursor::new(rdb));
+                            self.stats.loading.store(false, Ordering::Relaxed);
+                            loaded?;
+                            // the old log doesn't lead to the new dataset
+                            if self.config.read().unwrap().appendonly {
+                                if let Err(e) = self.start_aof() {
+                                    println!("err: append only file after the sync: {}", e);
+                                }
+                            }
+                            self.set_link_status(LinkStatus::Up);
+                        }
+                        ReplFrame::Command(args) => {
+                            // reply ack with offset to the master
+                            // the offset doesn't count the GETACK itself yet
+                            if is_getack(&args) {
+                                let ack_cmd = replconf_ack(self.get_slave_offset());
+                                writer.write_all(ack_cmd.as_bytes()).await?;
+                                writer.flush().await?;
+                                // every byte of a command moves the offset
+                                self.feed_from_master(raw);
+                            } else {
+                                // a sub-replica resyncing meanwhile gets the write either
+                                // in its snapshot or in its stream, never both
+                                let _write = self.write_lock.lock().unwrap();
+                                apply_master_command(self, &master, &mut multi, args);
+                                self.feed_from_master(raw);
+                            }
+                        }
+                    }
+                }
+                pending.drain(..consumed);
+                // before the rdb is in the bytes aren't part of the stream yet
+                if self.master_link.read().unwrap().status == LinkStatus::Up {
+                    self.master_link.write().unwrap().read_ahead = pending.len() as u64;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// every failed attempt doubles the wait before the next one, up to RECONNECT_MAX_DELAY
+fn next_reconnect_delay(delay: Duration) -> Duration {
+    (delay * 2).min(RECONNECT_MAX_DELAY)
+}
+
+fn replconf_ack(offset: u64) -> String {
+    array_to_resp_array(vec![
+        "REPLCONF".to_string(),
+        "ACK".to_string(),
+        offset.to_string(),
+    ])
+}
+
+fn is_getack(args: &[String]) -> bool {
+    args.len() > 1
+        && args[0].eq_ignore_ascii_case("replconf")
+        && args[1].eq_ignore_ascii_case("getack")
+}
+
+// commands of the master go through the same dispatcher as the clients
+// and nothing is replied, MULTI blocks are applied at EXEC
+fn apply_master_command(
+    db: &Arc<StoreEngine>,
+    master: &str,
+    multi: &mut Option<Vec<Arc<RwLock<RespMessage>>>>,
+    args: Vec<String>,
+) {
+    let name = args.first().map(|a| a.to_lowercase()).unwrap_or_default();
+    let cmd = Arc::new(RwLock::new(RespMessage::from_args(
+        master.to_string(),
+        args,
+    )));
+
+    match (name.as_str(), multi.as_mut()) {
+        ("multi", _) => *multi = Some(Vec::new()),
+        ("exec", Some(_)) => {
+            let queued = multi.take().unwrap_or_default();
+            let _ = apply_transaction(db, queued);
+        }
+        ("discard", _) => *multi = None,
+        (_, Some(queued)) => queued.push(cmd),
+        _ => {
+            let _ = apply_command(db, cmd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let mut delay = RECONNECT_MIN_DELAY;
+        let mut schedule = Vec::new();
+        for _ in 0..8 {
+            schedule.push(delay.as_millis());
+            delay = next_reconnect_delay(delay);
+        }
+        assert_eq!(schedule, [100, 200, 400, 800, 1600, 3200, 5000, 5000]);
+    }
+
+    #[tokio::test]
+    async fn test_master_link_down() {
+        // a master which hangs up right away, the handshake never gets anywhere
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let master = listener.local_addr().unwrap().to_string();
+
+        let db = Arc::new(StoreEngine::new());
+        assert!(db.get_master_link_info() == (LinkStatus::Down, -1, -1));
+        db.set_replica(master).unwrap();
+        db.start_master_link();
+
+        let mut attempts = Vec::new();
+        for _ in 0..4 {
+            let (socket, _) = listener.accept().await.unwrap();
+            drop(socket);
+            attempts.push(Instant::now());
+        }
+        db.stop_master_link();
+
+        // 100ms, 200ms and 400ms between the attempts
+        for (i, pair) in attempts.windows(2).enumerate() {
+            let expected = RECONNECT_MIN_DELAY * 2u32.pow(i as u32);
+            assert!(pair[1] - pair[0] >= expected, "attempt {}", i + 1);
+        }
+
+        // down since the first attempt failed, no io without a link
+        assert!(db.get_master_link_info() == (LinkStatus::Down, -1, 0));
+
+        db.set_link_status(LinkStatus::Up);
+        assert_eq!(db.get_master_link_info().2, -1);
+    }
+}
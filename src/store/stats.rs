@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::random_replid;
+
+// calls of a command as INFO commandstats shows them
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct CommandStat {
+    pub calls: u64,
+    pub usec: u64,
+    // refused before running, e.g. wrong arity or a write on a read only replica
+    pub rejected_calls: u64,
+    // ran and replied an error
+    pub failed_calls: u64,
+}
+
+// counters behind INFO, kept by the connections and the store
+pub struct ServerStats {
+    // identifies this run of the server, a restart gets a new one
+    pub run_id: String,
+    pub start_time: Instant,
+    // unix time in seconds of the last successful save, the start until then
+    pub last_save_time: AtomicU64,
+    // when the last save started, a failed one is retried after a delay
+    pub last_save_try: AtomicU64,
+    pub save_in_progress: AtomicBool,
+    pub last_save_ok: AtomicBool,
+    // set while the rdb or the aof is loaded at startup
+    pub loading: AtomicBool,
+    pub aof_last_write_ok: AtomicBool,
+    pub aof_rewrite_in_progress: AtomicBool,
+    // a start of the aof waiting for the running rewrite to end
+    pub aof_rewrite_scheduled: AtomicBool,
+    pub aof_last_rewrite_ok: AtomicBool,
+    pub aof_rewrites: AtomicU64,
+    // changes since the last successful save
+    pub dirty: AtomicU64,
+    // dirty when the running save took its snapshot
+    dirty_at_save: AtomicU64,
+    pub connected_clients: AtomicU64,
+    pub blocked_clients: AtomicU64,
+    pub total_connections_received: AtomicU64,
+    pub total_commands_processed: AtomicU64,
+    pub total_net_input_bytes: AtomicU64,
+    pub total_net_output_bytes: AtomicU64,
+    pub total_error_replies: AtomicU64,
+    pub expired_keys: AtomicU64,
+    pub keyspace_hits: AtomicU64,
+    pub keyspace_misses: AtomicU64,
+    pub used_memory_peak: AtomicU64,
+    commands: Mutex<HashMap<String, CommandStat>>,
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        ServerStats {
+            run_id: random_replid(),
+            start_time: Instant::now(),
+            last_save_time: AtomicU64::new(unix_time()),
+            last_save_try: AtomicU64::new(0),
+            save_in_progress: AtomicBool::new(false),
+            last_save_ok: AtomicBool::new(true),
+            loading: AtomicBool::new(false),
+            aof_last_write_ok: AtomicBool::new(true),
+            aof_rewrite_in_progress: AtomicBool::new(false),
+            aof_rewrite_scheduled: AtomicBool::new(false),
+            aof_last_rewrite_ok: AtomicBool::new(true),
+            aof_rewrites: AtomicU64::new(0),
+            dirty: AtomicU64::new(0),
+            dirty_at_save: AtomicU64::new(0),
+            connected_clients: AtomicU64::new(0),
+            blocked_clients: AtomicU64::new(0),
+            total_connections_received: AtomicU64::new(0),
+            total_commands_processed: AtomicU64::new(0),
+            total_net_input_bytes: AtomicU64::new(0),
+            total_net_output_bytes: AtomicU64::new(0),
+            total_error_replies: AtomicU64::new(0),
+            expired_keys: AtomicU64::new(0),
+            keyspace_hits: AtomicU64::new(0),
+            keyspace_misses: AtomicU64::new(0),
+            used_memory_peak: AtomicU64::new(0),
+            commands: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl ServerStats {
+    pub fn incr(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn decr(counter: &AtomicU64) {
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.start_time.elapsed()
+    }
+
+    pub fn keyspace_lookup(&self, hit: bool) {
+        match hit {
+            true => Self::incr(&self.keyspace_hits, 1),
+            false => Self::incr(&self.keyspace_misses, 1),
+        }
+    }
+
+    pub fn record_call(&self, name: &str, duration: Duration, failed: bool) {
+        Self::incr(&self.total_commands_processed, 1);
+        let mut commands = self.commands.lock().unwrap();
+        let stat = commands.entry(name.to_string()).or_default();
+        stat.calls += 1;
+        stat.usec += duration.as_micros() as u64;
+        if failed {
+            stat.failed_calls += 1;
+        }
+    }
+
+    pub fn record_rejected(&self, name: &str) {
+        let mut commands = self.commands.lock().unwrap();
+        commands.entry(name.to_string()).or_default().rejected_calls += 1;
+    }
+
+    // every command called at least once, sorted by name
+    pub fn command_stats(&self) -> Vec<(String, CommandStat)> {
+        let mut stats: Vec<_> = self
+            .commands
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, stat)| (name.clone(), stat.clone()))
+            .collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
+
+    // false while another save runs, only one rdb is written at a time
+    // the changes counted so far are the ones the snapshot will hold
+    pub fn begin_save(&self) -> bool {
+        if self
+            .save_in_progress
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return false;
+        }
+        self.last_save_try.store(unix_time(), Ordering::Relaxed);
+        self.dirty_at_save
+            .store(Self::get(&self.dirty), Ordering::Relaxed);
+        true
+    }
+
+    // the changes made while saving still count for the next save
+    pub fn end_save(&self, ok: bool) {
+        if ok {
+            self.last_save_time.store(unix_time(), Ordering::Relaxed);
+            let saved = self.dirty_at_save.load(Ordering::Relaxed);
+            self.dirty.fetch_sub(saved, Ordering::Relaxed);
+        }
+        self.last_save_ok.store(ok, Ordering::Relaxed);
+        self.save_in_progress.store(false, Ordering::Release);
+    }
+
+    // false while another rewrite runs
+    pub fn begin_aof_rewrite(&self) -> bool {
+        self.aof_rewrite_in_progress
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    pub fn end_aof_rewrite(&self, ok: bool) {
+        self.aof_last_rewrite_ok.store(ok, Ordering::Relaxed);
+        self.aof_rewrite_in_progress.store(false, Ordering::Release);
+    }
+
+    // the peak is only sampled when INFO asks for the memory
+    pub fn update_memory_peak(&self, used: u64) -> u64 {
+        self.used_memory_peak
+            .fetch_max(used, Ordering::Relaxed)
+            .max(used)
+    }
+}
+
+// unix time in seconds
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// resident set size from /proc, 0 where it isn't available
+pub fn process_rss() -> u64 {
+    // VmRSS is in kB whatever the page size is
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+            line.split_whitespace().nth(1)?.parse::<u64>().ok()
+        })
+        .map_or(0, |kb| kb * 1024)
+}
+
+// user and system cpu seconds from /proc, 0 where it isn't available
+pub fn process_cpu() -> (f64, f64) {
+    let (Ok(stat), Some(hz)) = (std::fs::read_to_string("/proc/self/stat"), clock_ticks()) else {
+        return (0.0, 0.0);
+    };
+    // the command name may hold spaces, the fields after it don't
+    let Some((_, fields)) = stat.rsplit_once(')') else {
+        return (0.0, 0.0);
+    };
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    // utime and stime are fields 14 and 15, in clock ticks
+    let ticks = |i: usize| fields.get(i).and_then(|v| v.parse::<f64>().ok());
+    match (ticks(11), ticks(12)) {
+        (Some(user), Some(sys)) => (user / hz, sys / hz),
+        _ => (0.0, 0.0),
+    }
+}
+
+// clock ticks per second, AT_CLKTCK of the aux vector the kernel started us with
+fn clock_ticks() -> Option<f64> {
+    const AT_CLKTCK: usize = 17;
+    const WORD: usize = std::mem::size_of::<usize>();
+    let auxv = std::fs::read("/proc/self/auxv").ok()?;
+    let word = |bytes: &[u8]| usize::from_ne_bytes(bytes.try_into().unwrap());
+    auxv.chunks_exact(2 * WORD)
+        .find(|entry| word(&entry[..WORD]) == AT_CLKTCK)
+        .map(|entry| word(&entry[WORD..]) as f64)
+        .filter(|hz| *hz > 0.0)
+}
+
+// 1.50K, 2.00M as redis prints the *_human fields
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [(&str, f64); 3] = [
+        ("G", 1024.0 * 1024.0 * 1024.0),
+        ("M", 1024.0 * 1024.0),
+        ("K", 1024.0),
+    ];
+    for (unit, size) in UNITS {
+        if bytes as f64 >= size {
+            return format!("{:.2}{}", bytes as f64 / size, unit);
+        }
+    }
+    format!("{}B", bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_command_stats() {
+        let stats = ServerStats::default();
+        stats.record_call("set", Duration::from_micros(10), false);
+        stats.record_call("set", Duration::from_micros(20), true);
+        stats.record_rejected("get");
+
+        let all = stats.command_stats();
+        assert_eq!(all[0].0, "get");
+        assert_eq!(all[0].1.rejected_calls, 1);
+        assert_eq!(
+            all[1].1,
+            CommandStat {
+                calls: 2,
+                usec: 30,
+                rejected_calls: 0,
+                failed_calls: 1,
+            }
+        );
+        assert_eq!(ServerStats::get(&stats.total_commands_processed), 2);
+
+        assert_eq!(human_bytes(512), "512B");
+        assert_eq!(human_bytes(1536), "1.50K");
+    }
+}
@@ -1,5 +1,4 @@
-use super::engine::{StoreEngine, StreamID};
-use crate::engine::{array_to_resp_array_for_xrange, xrange_to_read_wrap};
+use super::engine::{StoreEngine, StreamEntries, StreamID};
 use anyhow::*;
 use core::ops::Bound::{Excluded, Included};
 use std::collections::{BTreeMap, HashMap};
@@ -40,7 +39,12 @@ pub trait StreamEngine {
         &self,
         keys: Vec<String>,
         stream_ids: Vec<StreamID>,
-    ) -> Result<Vec<String>>;
+        count: Option<usize>,
+    ) -> Result<Vec<(String, Vec<StreamRange>)>>;
+
+    // whole streams, used by the rdb
+    fn set_stream(&self, k: impl AsRef<str>, entries: StreamEntries, last_id: StreamID);
+    fn get_streams(&self) -> Vec<(String, StreamEntries, StreamID)>;
 }
 
 impl StreamEngine for StoreEngine {
@@ -74,7 +78,7 @@ impl StreamEngine for StoreEngine {
         k: impl AsRef<str>,
     ) -> Option<BTreeMap<StreamID, HashMap<String, String>>> {
         let d = self.stream_dict.read().unwrap();
-        d.get(k.as_ref()).clone().cloned()
+        d.get(k.as_ref()).cloned()
     }
 
     fn valid_stream_id(&self, k: impl AsRef<str>, id: StreamID) -> bool {
@@ -179,24 +183,46 @@ impl StreamEngine for StoreEngine {
         &self,
         keys: Vec<String>,
         stream_ids: Vec<StreamID>,
-    ) -> Result<Vec<String>> {
+        count: Option<usize>,
+    ) -> Result<Vec<(String, Vec<StreamRange>)>> {
         let mut xread_arr = Vec::with_capacity(keys.len());
 
         for idx in 0..keys.len() {
             let key = keys[idx].clone();
             let from_stream_key = stream_ids[idx].clone();
-            let stream_range = self.get_xread(key.clone(), &from_stream_key);
+            let mut stream_range = self.get_xread(key.clone(), &from_stream_key);
+            if let Some(c) = count {
+                stream_range.truncate(c);
+            }
             if stream_range.is_empty() {
                 continue;
             }
 
-            let key_stream_wrap = xrange_to_read_wrap(
-                key.as_str(),
-                array_to_resp_array_for_xrange(&stream_range).as_str(),
-            );
-            xread_arr.push(key_stream_wrap);
+            xread_arr.push((key, stream_range));
         }
 
         Ok(xread_arr)
     }
+
+    fn set_stream(&self, k: impl AsRef<str>, entries: StreamEntries, last_id: StreamID) {
+        let key = k.as_ref().to_string();
+        self.stream_dict
+            .write()
+            .unwrap()
+            .insert(key.clone(), entries);
+        self.stream_last_key.write().unwrap().insert(key, last_id);
+    }
+
+    fn get_streams(&self) -> Vec<(String, StreamEntries, StreamID)> {
+        let last_ids = self.stream_last_key.read().unwrap();
+        self.stream_dict
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| {
+                let last_id = last_ids.get(k).cloned().unwrap_or_default();
+                (k.clone(), v.clone(), last_id)
+            })
+            .collect()
+    }
 }